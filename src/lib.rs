@@ -19,354 +19,9735 @@
 //! # 🪃
 //! ```
 //!
+//! **Virtual filesystems are never faked:**
+//! files in `/proc` and `/sys` (and the standard streams in `/dev`) always come from the real
+//! filesystem, even if the fake root has them, since programs rely on them describing the running
+//! system. Version 0.4.1 and earlier faked them like any other file: if you relied on that, set
+//! `FAKEROOT_PROC=1` to fake `/proc` again, but files in `/sys` can no longer be faked.
+//!
 //! Options are configured via environment variables:
 //! * `FAKEROOT`: absolute path to the fake root
-//! * `FAKEROOT_DIRS`: whether or not to intercept directory listing calls too
-//! * `FAKEROOT_ALL`: whether or not to fake non-existent files and directories
-//! * `FAKEROOT_DEBUG`: if set, will debug log to STDERR
+//! * `FAKEROOT_ROOT_PER_UID`: if set, used as the fake root in place of `FAKEROOT`, with `%u` replaced
+//!   by the user ID the process runs as, `%U` by its user name, and `%%` by `%` (such as `/fakes/%u`),
+//!   so each user on a shared host gets their own fake root from the same configuration
+//! * `FAKEROOT_DIRS`: whether or not to intercept directory listing calls too (directories are
+//!   excluded just like files, so `/proc`, `/sys` and paths not matching `FAKEROOT_MATCH_REGEX` are
+//!   still never redirected)
+//! * `FAKEROOT_DIRS_OPS`: comma separated list of the hooks (such as `opendir,chdir`) which list or
+//!   enter directories to intercept, as though `FAKEROOT_DIRS` were enabled for just those
+//! * `FAKEROOT_EXACT`: whether or not to only ever fake files, so that directories in the fake root
+//!   (and calls which list, enter or open directories) are never redirected, whatever else is enabled
+//! * `FAKEROOT_MOUNT_AT`: absolute path where the fake root appears, like a bind mount (default: `/`);
+//!   for example with `/etc`, `/etc/hosts` is looked for at `$FAKEROOT/hosts` and paths outside of
+//!   `/etc` aren't faked
+//! * `FAKEROOT_PROPAGATE`: whether or not to resolve `FAKEROOT` once when the hooks are first used
+//!   (it may then be relative to the current directory, or go through symlinks) and give the
+//!   resulting canonical path to executed programs, so child processes use exactly the same root
+//!   wherever they run
+//! * `FAKEROOT_ALL`: whether or not to fake non-existent files and directories (these only exist
+//!   once they're created in the fake root, so `access` or `stat` won't find them before then)
+//! * `FAKEROOT_CREATE_PARENTS`: whether or not `FAKEROOT_ALL` creates missing parent directories in
+//!   the fake root when files are created (defaults to true)
+//! * `FAKEROOT_ON_MISSING_DIR`: either `fail` (the default) for `opendir` of a directory which doesn't
+//!   exist to fail with `ENOENT` when `FAKEROOT_ALL` and `FAKEROOT_DIRS` are enabled, or `create` to
+//!   create it (empty) in the fake root first, just as files are created there
+//! * `FAKEROOT_UMASK`: octal umask for the directories created in the fake root (defaults to the
+//!   process' umask)
+//! * `FAKEROOT_FORCE_MODE`: octal mode given to every file created in the fake root, regardless of
+//!   the mode the program asked for (and of the umask)
+//! * `FAKEROOT_STRIP_SUID`: whether or not to drop the setuid and setgid bits from the mode of every
+//!   file created in the fake root (after applying `FAKEROOT_FORCE_MODE`)
+//! * `FAKEROOT_RELATIVE`: whether or not to resolve relative paths against the current directory
+//! * `FAKEROOT_INHERIT_CWD`: whether or not to resolve relative paths against the directory the
+//!   process was in when the hooks were first used, rather than following it as it changes (this takes
+//!   precedence over `FAKEROOT_RELATIVE`)
+//! * `FAKEROOT_STRICT_ABSOLUTE`: whether or not to log relative paths which can't be faked (because
+//!   `FAKEROOT_RELATIVE` isn't enabled) as errors, to find out why a program's files aren't faked
+//! * `FAKEROOT_FOLLOW`: whether or not to follow symlinks in the fake root which lead out of it
+//!   (defaults to true, when disabled these fail with `EACCES`)
+//! * `FAKEROOT_ORDER`: either `fake-first` (the default) to prefer files in the fake root, or
+//!   `real-first` to only use the fake root for files which don't exist on the real filesystem
+//! * `FAKEROOT_PROC`: whether or not to fake files in `/proc`, which are otherwise never faked
+//!   (nor are those in `/sys`, or the standard streams `/dev/stdin`, `/dev/stdout`, `/dev/stderr` and
+//!   `/dev/fd`)
+//! * `FAKEROOT_COW`: whether or not to copy real files into the fake root when they're opened for
+//!   writing, so that the real files are never modified (with `O_TRUNC` only the file's metadata is
+//!   copied, since its contents are being replaced)
+//! * `FAKEROOT_COPY_ON_OPEN`: whether or not to copy real files into the fake root (like
+//!   `FAKEROOT_COW`) whenever they're opened, even just for reading, so everything the program does
+//!   with them from then on uses the copy
+//! * `FAKEROOT_COW_DIR`: if set, the fake root is a read-only template and this absolute directory is
+//!   the writable layer on top of it: files are looked for here first, and files opened for writing
+//!   (or created with `FAKEROOT_ALL`) are copied here from the template or the real filesystem
+//! * `FAKEROOT_QUOTA`: if set, the most bytes the fake root (and `FAKEROOT_COW_DIR`) may hold: once
+//!   it's over this, opening files in it for writing fails with `ENOSPC`. Its size is measured when a
+//!   process first writes to it, and then grows by what the process has written each time it closes
+//!   a file, so this is best-effort (such as with files still open, or removed)
+//! * `FAKEROOT_SEED`: colon separated list of real files to copy into the fake root (preserving
+//!   their metadata) when the library is first used, so they're isolated from the start
+//! * `FAKEROOT_MATCH_REGEX`: if set, only paths matching this regular expression are looked for in
+//!   the fake root, and all others use the real filesystem
+//! * `FAKEROOT_MATCH_DEVICE`: if set to a device ID (or a path, whose device is used), only paths on
+//!   that device (or, for paths which don't exist yet, whose nearest existing parent is) are looked for
+//!   in the fake root, and all others use the real filesystem
+//! * `FAKEROOT_ALIAS`: colon separated list of `path<-alias,...` entries, where each (exact) alias is
+//!   looked for in the fake root as `path`, so several names can share one fake file (such as
+//!   `/etc/hostname<-/etc/HOSTNAME`)
+//! * `FAKEROOT_BIND`: colon separated list of `virtual=real` pairs, where the exact path `virtual` is
+//!   redirected to `real` (without using the fake root, and leaving the rest of its directory alone)
+//! * `FAKEROOT_INLINE`: colon separated list of `path=content` pairs, where opening the exact path
+//!   `path` (on Linux) returns a new in-memory file containing `content` rather than touching the disk
+//! * `FAKEROOT_GEN`: colon separated list of `path=command` pairs, where opening the exact path `path`
+//!   for reading (on Linux) runs `command` with `/bin/sh -c` and returns a new in-memory file
+//!   containing its output. The command isn't faked itself, and if it fails the path is opened as
+//!   normal
+//! * `FAKEROOT_APPLY_DIFF`: if set, the unified diff in this file is applied to the files it patches
+//!   when they're opened for reading (on Linux), which returns a new in-memory file with the patched
+//!   content and leaves the files on disk alone. Relative paths in the diff have their first component
+//!   (such as `a/` or `b/`) stripped, as `patch -p1` would from `/`
+//! * `FAKEROOT_DEFAULT_CONTENT`: if set along with `FAKEROOT_ALL`, opening a file which doesn't exist
+//!   in the fake root for reading (on Linux) returns a new in-memory file containing this, rather than
+//!   failing with `ENOENT`
+//! * `FAKEROOT_GZIP`: whether or not opening a file which doesn't exist in the fake root for reading
+//!   (on Linux) should look for it compressed with gzip (as `<path>.gz`), and return a new in-memory
+//!   file with its decompressed content
+//! * `FAKEROOT_SYNTH_STAT`: whether or not `stat` (and the rest of its family) should report files
+//!   which don't exist in the fake root as empty regular files (sized as `FAKEROOT_DEFAULT_CONTENT` if
+//!   that's set) when `FAKEROOT_ALL` is enabled, rather than failing with `ENOENT`, so that everything
+//!   appears to exist
+//! * `FAKEROOT_NULLDEV`: colon separated list of paths which behave like `/dev/null` when opened
+//!   (reads are empty and writes are discarded), without any file on disk
+//! * `FAKEROOT_SEP`: the separator for lists of paths, such as `FAKEROOT_SEED`, for when the paths
+//!   contain colons (defaults to `:`)
+//! * `FAKEROOT_DETERMINISTIC_DIR_ORDER`: whether or not directories listed with `readdir` return
+//!   their entries sorted by name, rather than in whatever order the filesystem keeps them, so
+//!   listings are reproducible
+//! * `FAKEROOT_WHITEOUT`: whether or not to honour overlayfs-style whiteouts in the fake root: a
+//!   `.wh.<name>` file (or a `0/0` character device named `<name>`) hides the real `<name>`, which
+//!   then fails with `ENOENT` and is left out of directory listings
+//! * `FAKEROOT_SPOOF_INODE`: whether or not to report a synthetic device and inode number for faked
+//!   files, which stay the same for each path no matter which `stat` call is used
+//! * `FAKEROOT_UID` and `FAKEROOT_GID`: if set, faked files report this user and group as their
+//!   owner, and `chown` of a faked file only changes the owner it reports (so an unprivileged user can
+//!   make files appear to be owned by root)
+//! * `FAKEROOT_STATE`: if set, the synthetic inode numbers from `FAKEROOT_SPOOF_INODE` and the owners
+//!   given by `chown` are loaded from this file when the library is first used, and merged back into it
+//!   when each process exits, so separate processes see the same fake metadata
+//! * `FAKEROOT_FAKE_TIME`: if set, faked files report these seconds since the epoch as their access,
+//!   modification and change times, for reproducible builds
+//! * `FAKEROOT_ON_ERROR`: either `passthrough` (the default) to use the real filesystem when a path
+//!   can't be resolved (such as when the fake root is misconfigured), or `fail` to fail the call with
+//!   `EINVAL` instead
+//! * `FAKEROOT_QUIET`: whether or not to hide the real filesystem's permission errors when a file
+//!   which isn't faked is opened for writing: calls failing with `EACCES` or `EPERM` fail with `EROFS`
+//!   instead, as though the real filesystem were read-only
+//! * `FAKEROOT_STRICT_SYS`: whether or not to deny opening files in `/dev`, `/proc` and `/sys` for
+//!   writing, failing with `EROFS` so the host's devices and kernel state can't be changed; they can
+//!   still be read
+//! * `FAKEROOT_DENY_READ`: colon separated list of real files (or directories, to include everything
+//!   in them) which can't be opened for reading when they aren't faked, failing with `EACCES` rather
+//!   than leaking them (such as `/etc/shadow` or `~/.ssh`); symlinks to them are denied too
+//! * `FAKEROOT_CONFINE`: whether or not to open files in the fake root (on Linux 5.6 or later) with
+//!   `openat2`'s `RESOLVE_IN_ROOT`, so the kernel resolves symlinks and `..` in it as though it were
+//!   `/`, as `chroot` would, rather than letting them lead out of it; older kernels open them as normal
+//! * `FAKEROOT_REPORT`: if set, a JSON list of every path passed to the hooks, with the hook and
+//!   whether it was redirected, passed through or denied, is merged into this file when each process
+//!   exits (including via `_exit`)
+//! * `FAKEROOT_TOUCH_LOG`: if set, the logical path of each file or directory created in the fake
+//!   root (such as by `FAKEROOT_ALL` or `FAKEROOT_COW`) is appended to this file, once per process
+//! * `FAKEROOT_CONFIG`: path to a config file of `key = value` lines to read the options above from,
+//!   named like the variables without their `FAKEROOT_` prefix (and `root` for `FAKEROOT`); the
+//!   variables take precedence over the file. The debugging options below are only read from the
+//!   environment, since they're needed to report problems reading the file
+//! * `FAKEROOT_ENV_FILE`: path to a dotenv-style file of `FAKEROOT_*=value` lines to read the options
+//!   above from, which the variables take precedence over (and which takes precedence over
+//!   `FAKEROOT_CONFIG`)
+//! * `FAKEROOT_DEBUG`: if set, will debug log to STDERR; a numeric level of `2` logs only errors,
+//!   `3` also logs redirects and `4` (or `1`) also logs passthroughs
+//! * `FAKEROOT_DEBUG_HOOKS`: if set, only these hooks (a comma separated list of function names,
+//!   such as `open,stat`) will debug log
+//! * `FAKEROOT_DEBUG_BACKTRACE`: if set, a backtrace of where each hooked call was made is debug
+//!   logged along with it, which is slow so is best combined with `FAKEROOT_MATCH_REGEX` (only paths
+//!   matching it are logged) or `FAKEROOT_DEBUG_HOOKS`
+//! * `FAKEROOT_DEBUG_FD`: if set to the number of an open file descriptor (such as one inherited from
+//!   a launcher), the debug log is written to it rather than to `FAKEROOT_LOG` or STDERR
+//! * `FAKEROOT_LOG`: if set, the debug log is appended to this file rather than written to STDERR
+//! * `FAKEROOT_LOG_PREFIX`: if set, debug logs are prefixed with this rather than `@HOOK@`
+//! * `FAKEROOT_LOG_MAXSIZE`: if set, the debug log file is moved to `<file>.1` once it would grow
+//!   past this many bytes, and a new one is started
+//! * `FAKEROOT_PROFILE`: if set, each hook is timed and a summary of how many times it was called and
+//!   how long it took (and how many calls were denied) is logged (even without `FAKEROOT_DEBUG`) when
+//!   the process exits
+//! * `FAKEROOT_AUDIT_DENIED`: if set, each call denied by the hooks (such as by `FAKEROOT_STRICT_SYS`)
+//!   is logged (even without `FAKEROOT_DEBUG`), along with how many were denied when the process exits
+//! * `FAKEROOT_TRACE_PID`: if set, only the process with this PID will debug log
+//!
+//! Calls which only receive a file descriptor (such as `fstat` or `getdents64`) can't be
+//! redirected themselves, but descriptors opened from faked paths already refer to the fake files.
+//!
+//! libc reads `/etc/passwd`, `/etc/group` and `/etc/shadow` internally, where the `open` hooks can't
+//! see it, so on Linux `getpwnam`, `getpwuid`, `getgrnam`, `getgrgid` and `getspnam` (and the
+//! reentrant `_r` variants of the first four) are hooked to search the fake copies instead, falling
+//! back to the real lookup if there isn't one. To fake a user, copy the real files into the fake root
+//! and add a line to each:
+//!
+//! ```sh
+//! cp /etc/passwd /etc/group $FAKEROOT/etc/
+//! echo 'me:x:4242:4242::/home/me:/bin/sh' >> $FAKEROOT/etc/passwd
+//! echo 'me:x:4242:' >> $FAKEROOT/etc/group
+//! id me
+//! ```
+//!
+//! Programs executed with `execve` or `execveat` are given this process's `LD_PRELOAD` and
+//! `FAKEROOT*` variables if their environment doesn't set them, so they're faked too.
+//!
+//! Programs which link against the library instead of using `LD_PRELOAD` can call
+//! `fakeroot_init` to configure it, and `fakeroot_resolve` to see where a path would be redirected.
+//! `fakeroot_hook_name` lists the functions it hooks on this platform, and
+//! `fakeroot_version` returns the version of the library that's loaded, which is also debug logged (at
+//! the redirect level) when the hooks are first used.
 
-use std::cell::OnceCell;
+use std::backtrace::Backtrace;
+use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet};
 use std::error::Error;
-use std::ffi::{CStr, CString};
+use std::ffi::{CStr, CString, OsStr, OsString};
+use std::fmt::{self, Display};
+use std::fs::{File, FileTimes};
+use std::io::{self, Read, Seek, Write};
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::{FromRawFd, IntoRawFd};
 use std::os::unix::prelude::OsStrExt;
-use std::path::PathBuf;
-use std::{env, str};
+use std::path::{Component, Path, PathBuf};
+#[cfg(target_os = "linux")]
+use std::process;
+#[cfg(target_os = "linux")]
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex, Once, OnceLock, PoisonError};
+use std::time::{Duration, Instant};
+use std::{env, fs, mem, ptr, str};
 
-use libc::{c_char, c_int};
+#[cfg(target_os = "linux")]
+use flate2::read::GzDecoder;
+#[cfg(target_os = "linux")]
+use libc::c_void;
+use libc::{c_char, c_int, pid_t, size_t, ssize_t, PATH_MAX};
 use libc::{DIR, FILE};
+use regex::Regex;
 
 /// Required: absolute path to the directory to use as the fake root
 pub const ENV_FAKEROOT: &str = "FAKEROOT";
+/// Optional: fake root to use in place of `ENV_FAKEROOT`, with `%u` replaced by the user ID
+pub const ENV_FAKEROOT_ROOT_PER_UID: &str = "FAKEROOT_ROOT_PER_UID";
 /// Optional: should this also hook directories?
 pub const ENV_FAKEROOT_DIRS: &str = "FAKEROOT_DIRS";
+/// Optional: comma separated list of the hooks which should also hook directories
+pub const ENV_FAKEROOT_DIRS_OPS: &str = "FAKEROOT_DIRS_OPS";
+/// Optional: should only files be faked, and never directories?
+pub const ENV_FAKEROOT_EXACT: &str = "FAKEROOT_EXACT";
+/// Optional: absolute path the fake root is mounted at (default: `/`)
+pub const ENV_FAKEROOT_MOUNT_AT: &str = "FAKEROOT_MOUNT_AT";
+/// Optional: should the fake root be resolved to a canonical path once, and set for child
+/// processes to inherit?
+pub const ENV_FAKEROOT_PROPAGATE: &str = "FAKEROOT_PROPAGATE";
 /// Optional: should non existent files be faked?
 pub const ENV_FAKEROOT_ALL: &str = "FAKEROOT_ALL";
+/// Optional: should missing parent directories be created in the fake root when creating files?
+/// Only used with `ENV_FAKEROOT_ALL` (default: true)
+pub const ENV_FAKEROOT_CREATE_PARENTS: &str = "FAKEROOT_CREATE_PARENTS";
+/// Optional: should directories which don't exist fail to open (`fail`, the default), or be created
+/// in the fake root (`create`)? Only used with `ENV_FAKEROOT_ALL` and `ENV_FAKEROOT_DIRS`
+pub const ENV_FAKEROOT_ON_MISSING_DIR: &str = "FAKEROOT_ON_MISSING_DIR";
+/// Optional: octal umask for directories created in the fake root (default: the process' umask)
+pub const ENV_FAKEROOT_UMASK: &str = "FAKEROOT_UMASK";
+/// Optional: octal mode for files created in the fake root, overriding the mode they're created with
+pub const ENV_FAKEROOT_FORCE_MODE: &str = "FAKEROOT_FORCE_MODE";
+/// Optional: should the setuid and setgid bits be dropped from files created in the fake root?
+pub const ENV_FAKEROOT_STRIP_SUID: &str = "FAKEROOT_STRIP_SUID";
+/// Optional: should relative paths be resolved against the current directory?
+pub const ENV_FAKEROOT_RELATIVE: &str = "FAKEROOT_RELATIVE";
+/// Optional: should relative paths be resolved against the directory the process started in?
+pub const ENV_FAKEROOT_INHERIT_CWD: &str = "FAKEROOT_INHERIT_CWD";
+/// Optional: should relative paths which can't be faked be logged as errors?
+pub const ENV_FAKEROOT_STRICT_ABSOLUTE: &str = "FAKEROOT_STRICT_ABSOLUTE";
+/// Optional: should symlinks in the fake root be followed if they lead out of it? (default: true)
+pub const ENV_FAKEROOT_FOLLOW: &str = "FAKEROOT_FOLLOW";
+/// Optional: should files be looked for in the fake root first (`fake-first`, the default), or in
+/// the real filesystem first (`real-first`)?
+pub const ENV_FAKEROOT_ORDER: &str = "FAKEROOT_ORDER";
+/// Optional: should files in `/proc` be faked?
+pub const ENV_FAKEROOT_PROC: &str = "FAKEROOT_PROC";
+/// Optional: should real files be copied into the fake root before they're written to?
+pub const ENV_FAKEROOT_COW: &str = "FAKEROOT_COW";
+/// Optional: should real files be copied into the fake root whenever they're opened?
+pub const ENV_FAKEROOT_COPY_ON_OPEN: &str = "FAKEROOT_COPY_ON_OPEN";
+/// Optional: directory which files are written to, leaving the fake root as a read-only template
+pub const ENV_FAKEROOT_COW_DIR: &str = "FAKEROOT_COW_DIR";
+/// Optional: bytes the fake root can grow to, after which opening files in it for writing fails
+pub const ENV_FAKEROOT_QUOTA: &str = "FAKEROOT_QUOTA";
+/// Optional: colon separated list of real files to copy into the fake root up front
+pub const ENV_FAKEROOT_SEED: &str = "FAKEROOT_SEED";
+/// Optional: regular expression which paths must match to be faked
+pub const ENV_FAKEROOT_MATCH_REGEX: &str = "FAKEROOT_MATCH_REGEX";
+/// Optional: device ID (or a path on the device) which paths must be on to be faked
+pub const ENV_FAKEROOT_MATCH_DEVICE: &str = "FAKEROOT_MATCH_DEVICE";
+/// Optional: `path<-alias,...` entries of paths which are faked as another one
+pub const ENV_FAKEROOT_ALIAS: &str = "FAKEROOT_ALIAS";
+/// Optional: `virtual=real` pairs of single paths to redirect, outside of the fake root
+pub const ENV_FAKEROOT_BIND: &str = "FAKEROOT_BIND";
+/// Optional: `path=content` pairs of files to create in memory when they're opened
+pub const ENV_FAKEROOT_INLINE: &str = "FAKEROOT_INLINE";
+/// Optional: `path=command` pairs of files to create in memory from a command's output
+pub const ENV_FAKEROOT_GEN: &str = "FAKEROOT_GEN";
+/// Optional: unified diff to patch files with (in memory) when they're opened
+pub const ENV_FAKEROOT_APPLY_DIFF: &str = "FAKEROOT_APPLY_DIFF";
+/// Optional: content of files which are read from the fake root with `ENV_FAKEROOT_ALL` enabled,
+/// but don't exist
+pub const ENV_FAKEROOT_DEFAULT_CONTENT: &str = "FAKEROOT_DEFAULT_CONTENT";
+/// Optional: should files which don't exist be read from gzip compressed ones next to them?
+pub const ENV_FAKEROOT_GZIP: &str = "FAKEROOT_GZIP";
+/// Optional: list of paths to open as `/dev/null`
+pub const ENV_FAKEROOT_NULLDEV: &str = "FAKEROOT_NULLDEV";
+/// Optional: should files which don't exist be reported by `stat` as empty ones with
+/// `ENV_FAKEROOT_ALL` enabled?
+pub const ENV_FAKEROOT_SYNTH_STAT: &str = "FAKEROOT_SYNTH_STAT";
+/// Optional: separator for the lists of paths in other options (default: `:`)
+pub const ENV_FAKEROOT_SEP: &str = "FAKEROOT_SEP";
+/// Optional: should whiteouts in the fake root hide real files?
+pub const ENV_FAKEROOT_WHITEOUT: &str = "FAKEROOT_WHITEOUT";
+/// Optional: should faked files report a synthetic device and inode number?
+pub const ENV_FAKEROOT_SPOOF_INODE: &str = "FAKEROOT_SPOOF_INODE";
+/// Optional: user ID for faked files to report as their owner
+pub const ENV_FAKEROOT_UID: &str = "FAKEROOT_UID";
+/// Optional: group ID for faked files to report as their owner
+pub const ENV_FAKEROOT_GID: &str = "FAKEROOT_GID";
+/// Optional: file to keep the spoofed inode numbers and owners of faked files in, between processes
+pub const ENV_FAKEROOT_STATE: &str = "FAKEROOT_STATE";
+/// Optional: seconds since the epoch for faked files to report as their times
+pub const ENV_FAKEROOT_FAKE_TIME: &str = "FAKEROOT_FAKE_TIME";
+/// Optional: path to a config file to read the other options from, which they take precedence over
+pub const ENV_FAKEROOT_CONFIG: &str = "FAKEROOT_CONFIG";
+/// Optional: path to a dotenv-style file to read the other options from, which they take
+/// precedence over
+pub const ENV_FAKEROOT_ENV_FILE: &str = "FAKEROOT_ENV_FILE";
+/// Optional: should calls whose paths can't be resolved pass through to the real filesystem
+/// (`passthrough`, the default), or fail (`fail`)?
+pub const ENV_FAKEROOT_ON_ERROR: &str = "FAKEROOT_ON_ERROR";
+/// Optional: should permission errors writing to the real filesystem be reported as `EROFS`?
+pub const ENV_FAKEROOT_QUIET: &str = "FAKEROOT_QUIET";
+/// Optional: should directory entries be listed sorted by name?
+pub const ENV_FAKEROOT_DETERMINISTIC_DIR_ORDER: &str = "FAKEROOT_DETERMINISTIC_DIR_ORDER";
+/// Optional: should opening files in `/dev`, `/proc` and `/sys` for writing fail with `EROFS`?
+pub const ENV_FAKEROOT_STRICT_SYS: &str = "FAKEROOT_STRICT_SYS";
+/// Optional: list of real files and directories which can't be opened for reading unless faked
+pub const ENV_FAKEROOT_DENY_READ: &str = "FAKEROOT_DENY_READ";
+/// Optional: should files be opened in the fake root as though it were `/`, via `openat2`?
+pub const ENV_FAKEROOT_CONFINE: &str = "FAKEROOT_CONFINE";
 /// Optional: should this hook log debug information to STDERR?
 pub const ENV_FAKEROOT_DEBUG: &str = "FAKEROOT_DEBUG";
+/// Optional: number of an open file descriptor to debug log to, rather than STDERR
+pub const ENV_FAKEROOT_DEBUG_FD: &str = "FAKEROOT_DEBUG_FD";
+/// Optional: path to a file to debug log to, rather than STDERR
+pub const ENV_FAKEROOT_LOG: &str = "FAKEROOT_LOG";
+/// Optional: prefix for debug logs, rather than `@HOOK@`
+pub const ENV_FAKEROOT_LOG_PREFIX: &str = "FAKEROOT_LOG_PREFIX";
+/// Optional: size in bytes after which the debug log file is rotated
+pub const ENV_FAKEROOT_LOG_MAXSIZE: &str = "FAKEROOT_LOG_MAXSIZE";
+/// Optional: comma separated list of the hooks to debug log from
+pub const ENV_FAKEROOT_DEBUG_HOOKS: &str = "FAKEROOT_DEBUG_HOOKS";
+/// Optional: should a backtrace of where each hooked call was made be debug logged?
+pub const ENV_FAKEROOT_DEBUG_BACKTRACE: &str = "FAKEROOT_DEBUG_BACKTRACE";
+/// Optional: should the time spent in each hook be logged when the process exits?
+pub const ENV_FAKEROOT_PROFILE: &str = "FAKEROOT_PROFILE";
+/// Optional: should each denied call be logged, and how many were denied when the process exits?
+pub const ENV_FAKEROOT_AUDIT_DENIED: &str = "FAKEROOT_AUDIT_DENIED";
+/// Optional: file to write a JSON report of the paths passed to the hooks to
+pub const ENV_FAKEROOT_REPORT: &str = "FAKEROOT_REPORT";
+/// Optional: file to append the paths created in the fake root to
+pub const ENV_FAKEROOT_TOUCH_LOG: &str = "FAKEROOT_TOUCH_LOG";
+/// Optional: only debug log from the process with this PID
+pub const ENV_FAKEROOT_TRACE_PID: &str = "FAKEROOT_TRACE_PID";
+
+/// Flag for `fakeroot_init`: should this also hook directories?
+pub const FAKEROOT_FLAG_DIRS: u32 = 1 << 0;
+/// Flag for `fakeroot_init`: should non existent files be faked?
+pub const FAKEROOT_FLAG_ALL: u32 = 1 << 1;
+/// Flag for `fakeroot_init`: should relative paths be resolved against the current directory?
+pub const FAKEROOT_FLAG_RELATIVE: u32 = 1 << 2;
+/// Flag for `fakeroot_init`: should symlinks in the fake root which lead out of it be refused?
+pub const FAKEROOT_FLAG_NO_FOLLOW: u32 = 1 << 3;
+/// Flag for `fakeroot_init`: should real files be copied into the fake root before writes?
+pub const FAKEROOT_FLAG_COW: u32 = 1 << 4;
+/// Flag for `fakeroot_init`: should the real filesystem be preferred to the fake root?
+pub const FAKEROOT_FLAG_REAL_FIRST: u32 = 1 << 5;
+/// Flag for `fakeroot_init`: should files in `/proc` be faked?
+pub const FAKEROOT_FLAG_PROC: u32 = 1 << 6;
+/// Flag for `fakeroot_init`: should whiteouts in the fake root hide real files?
+pub const FAKEROOT_FLAG_WHITEOUT: u32 = 1 << 7;
+/// Flag for `fakeroot_init`: should faked files report a synthetic device and inode number?
+pub const FAKEROOT_FLAG_SPOOF_INODE: u32 = 1 << 8;
+/// Flag for `fakeroot_init`: should missing parent directories be left uncreated when creating files?
+pub const FAKEROOT_FLAG_NO_CREATE_PARENTS: u32 = 1 << 9;
+/// Flag for `fakeroot_init`: should calls whose paths can't be resolved fail?
+pub const FAKEROOT_FLAG_FAIL_ON_ERROR: u32 = 1 << 10;
+/// Flag for `fakeroot_init`: should permission errors writing to the real filesystem be hidden?
+pub const FAKEROOT_FLAG_QUIET: u32 = 1 << 11;
+/// Flag for `fakeroot_init`: deny opening files in `/dev`, `/proc` and `/sys` for writing
+pub const FAKEROOT_FLAG_STRICT_SYS: u32 = 1 << 12;
+/// Flag for `fakeroot_init`: open files in the fake root as though it were `/`, via `openat2`
+pub const FAKEROOT_FLAG_CONFINE: u32 = 1 << 13;
+/// Flag for `fakeroot_init`: only fake files, never directories
+pub const FAKEROOT_FLAG_EXACT: u32 = 1 << 14;
+/// Flag for `fakeroot_init`: report files which don't exist as empty ones in `stat` (with
+/// `FAKEROOT_FLAG_ALL`)
+pub const FAKEROOT_FLAG_SYNTH_STAT: u32 = 1 << 15;
+/// Flag for `fakeroot_init`: read files which don't exist from gzip compressed ones next to them
+pub const FAKEROOT_FLAG_GZIP: u32 = 1 << 16;
+/// Flag for `fakeroot_init`: should real files be copied into the fake root whenever they're opened?
+pub const FAKEROOT_FLAG_COPY_ON_OPEN: u32 = 1 << 17;
+/// Flag for `fakeroot_init`: should the setuid and setgid bits be dropped from created files?
+pub const FAKEROOT_FLAG_STRIP_SUID: u32 = 1 << 18;
 
-/// Used as a prefix for all debug logs
+/// Used as a prefix for all debug logs, unless `ENV_FAKEROOT_LOG_PREFIX` is set
 const HOOK_TAG: &str = "@HOOK@";
-/// Runtime cache of the fake root directory
-const FAKEROOT_ROOT: OnceCell<Result<PathBuf, Box<dyn Error>>> = OnceCell::new();
-/// Runtime cache of debug state
-const FAKEROOT_DEBUG: OnceCell<bool> = OnceCell::new();
+/// Virtual filesystems (and the standard streams) which are never faked, since programs rely on
+/// them reflecting the system
+const NEVER_FAKE: &[&str] = &[
+    "/proc",
+    "/sys",
+    "/dev/stdin",
+    "/dev/stdout",
+    "/dev/stderr",
+    "/dev/fd",
+];
+/// Names of the functions this library hooks on this platform
+const HOOKS: &[&CStr] = &[
+    c"open",
+    #[cfg(target_env = "gnu")]
+    c"open64",
+    c"openat",
+    #[cfg(target_env = "gnu")]
+    c"openat64",
+    c"creat",
+    #[cfg(target_env = "gnu")]
+    c"creat64",
+    c"fopen",
+    #[cfg(target_env = "gnu")]
+    c"fopen64",
+    c"stat",
+    c"lstat",
+    #[cfg(target_env = "gnu")]
+    c"stat64",
+    #[cfg(target_env = "gnu")]
+    c"lstat64",
+    #[cfg(target_os = "linux")]
+    c"statx",
+    c"chown",
+    c"lchown",
+    c"fchownat",
+    c"access",
+    c"faccessat",
+    c"readlink",
+    c"readlinkat",
+    c"rename",
+    c"renameat",
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    c"renameat2",
+    c"utimes",
+    c"lutimes",
+    #[cfg(any(target_os = "freebsd", target_os = "macos"))]
+    c"chflags",
+    #[cfg(any(target_os = "freebsd", target_os = "macos"))]
+    c"lchflags",
+    c"opendir",
+    c"readdir",
+    #[cfg(target_env = "gnu")]
+    c"readdir64",
+    c"closedir",
+    #[cfg(target_os = "linux")]
+    c"getdents64",
+    c"fstat",
+    #[cfg(target_env = "gnu")]
+    c"fstat64",
+    c"fclose",
+    c"close",
+    c"execve",
+    #[cfg(target_os = "linux")]
+    c"execveat",
+    c"chdir",
+    c"fchdir",
+    c"getcwd",
+    c"realpath",
+    #[cfg(target_os = "linux")]
+    c"getpwnam",
+    #[cfg(target_os = "linux")]
+    c"getpwnam_r",
+    #[cfg(target_os = "linux")]
+    c"getpwuid",
+    #[cfg(target_os = "linux")]
+    c"getpwuid_r",
+    #[cfg(target_os = "linux")]
+    c"getgrnam",
+    #[cfg(target_os = "linux")]
+    c"getgrnam_r",
+    #[cfg(target_os = "linux")]
+    c"getgrgid",
+    #[cfg(target_os = "linux")]
+    c"getgrgid_r",
+    #[cfg(target_os = "linux")]
+    c"getspnam",
+    c"_exit",
+];
+/// Directories which can't be written to with `ENV_FAKEROOT_STRICT_SYS`
+const STRICT_SYS: &[&str] = &["/dev", "/proc", "/sys"];
+/// Prefix of the files which mark a path as deleted, like overlayfs
+const WHITEOUT_PREFIX: &str = ".wh.";
+/// Device number reported for faked files when their identity is spoofed
+const SPOOFED_DEV: u64 = 0xfa4e;
+/// Runtime cache of options
+static FAKEROOT_OPTIONS: OnceLock<Options> = OnceLock::new();
+/// Set once the files in `ENV_FAKEROOT_SEED` have been copied into the fake root
+static FAKEROOT_SEEDED: Once = Once::new();
+/// Set once the `ENV_FAKEROOT_STATE` file has been loaded
+static FAKEROOT_STATE_LOADED: Once = Once::new();
+/// Set once the `ENV_FAKEROOT_STATE` file has been saved, since a process may exit via both `exit`
+/// and `_exit`
+static FAKEROOT_STATE_WRITTEN: Once = Once::new();
+/// Canonical fake root given to executed programs, if `ENV_FAKEROOT_PROPAGATE` is enabled
+static FAKEROOT_PROPAGATED: OnceLock<PathBuf> = OnceLock::new();
+/// Descriptor of the fake root for `ENV_FAKEROOT_CONFINE` to open files relative to, with the device
+/// and inode it was opened on
+#[cfg(target_os = "linux")]
+static FAKEROOT_ROOT_FD: Mutex<Option<(c_int, libc::dev_t, libc::ino_t)>> = Mutex::new(None);
+/// Set if the kernel doesn't have `openat2`, so `ENV_FAKEROOT_CONFINE` can't be used
+#[cfg(target_os = "linux")]
+static FAKEROOT_NO_OPENAT2: AtomicBool = AtomicBool::new(false);
+/// Set once the version and fake root have been logged
+static FAKEROOT_BANNER: Once = Once::new();
+/// Version of this library, as a nul-terminated string for `fakeroot_version`
+const VERSION: &str = concat!(env!("CARGO_PKG_VERSION"), "\0");
+/// Set once a misconfigured fake root has been reported
+static FAKEROOT_ROOT_ERROR: Once = Once::new();
+/// Runtime cache of the debug log level
+static FAKEROOT_DEBUG: OnceLock<u8> = OnceLock::new();
+/// Runtime cache of the prefix for debug logs
+static FAKEROOT_LOG_PREFIX: OnceLock<String> = OnceLock::new();
+/// Runtime cache of the file to debug log to
+static FAKEROOT_LOG: OnceLock<Option<Mutex<LogFile>>> = OnceLock::new();
+/// Runtime cache of the file descriptor to debug log to
+static FAKEROOT_DEBUG_FD: OnceLock<Option<c_int>> = OnceLock::new();
+/// Runtime cache of whether hooked calls should log a backtrace
+static FAKEROOT_DEBUG_BACKTRACE: OnceLock<bool> = OnceLock::new();
+/// Runtime cache of whether hooks should be timed
+static FAKEROOT_PROFILE: OnceLock<bool> = OnceLock::new();
+/// How many times each hook was called, and how long they took in total
+static FAKEROOT_PROFILE_DATA: Mutex<BTreeMap<&str, (u64, Duration)>> = Mutex::new(BTreeMap::new());
+/// Runtime cache of whether denied calls should be logged
+static FAKEROOT_AUDIT_DENIED: OnceLock<bool> = OnceLock::new();
+/// How many calls the hooks have denied
+static FAKEROOT_DENIED: AtomicU64 = AtomicU64::new(0);
+/// Set once writing the `ENV_FAKEROOT_REPORT` file on exit has been registered
+static FAKEROOT_REPORT: Once = Once::new();
+/// Each path passed to the hooks, with what was done with it and by which hook
+static FAKEROOT_REPORT_DATA: Mutex<BTreeSet<(String, &str, &str)>> = Mutex::new(BTreeSet::new());
+/// Set once the report has been written, since a process may exit via both `exit` and `_exit`
+static FAKEROOT_REPORT_WRITTEN: Once = Once::new();
+/// The logical paths created in the fake root which have been written to the touch log
+static FAKEROOT_TOUCHED: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+/// Runtime cache of the hooks to debug log from
+static FAKEROOT_DEBUG_HOOKS: OnceLock<Option<Vec<String>>> = OnceLock::new();
+/// Runtime cache of the PID to debug log from
+static FAKEROOT_TRACE_PID: OnceLock<Option<pid_t>> = OnceLock::new();
+/// The logical current directory, if `chdir` was redirected into the fake root
+static FAKEROOT_CWD: Mutex<Option<PathBuf>> = Mutex::new(None);
+/// File descriptors which were opened in the fake root, and the paths they were opened with
+static FAKE_FDS: Mutex<BTreeMap<c_int, String>> = Mutex::new(BTreeMap::new());
+/// Streams which were opened in the fake root, and the paths they were opened with
+static FAKE_STREAMS: Mutex<BTreeMap<usize, String>> = Mutex::new(BTreeMap::new());
+/// Bytes the fake root holds, with `ENV_FAKEROOT_QUOTA`
+static FAKEROOT_USAGE: OnceLock<AtomicU64> = OnceLock::new();
+/// File descriptors open for writing in the fake root with `ENV_FAKEROOT_QUOTA`, and the sizes of
+/// their files when they were opened
+static WRITTEN_FDS: Mutex<BTreeMap<c_int, u64>> = Mutex::new(BTreeMap::new());
+/// Directory streams which may contain whited out entries, and where their whiteouts would be
+static WHITEOUT_DIRS: Mutex<BTreeMap<usize, PathBuf>> = Mutex::new(BTreeMap::new());
+/// Directory streams listed in name order, and their entries once they've been read
+static SORTED_DIRS: Mutex<BTreeMap<usize, SortedDir<libc::dirent>>> = Mutex::new(BTreeMap::new());
+/// Like `SORTED_DIRS`, for streams listed with `readdir64`
+#[cfg(target_env = "gnu")]
+static SORTED_DIRS64: Mutex<BTreeMap<usize, SortedDir<libc::dirent64>>> =
+    Mutex::new(BTreeMap::new());
+/// Synthetic inode numbers handed out to faked files, by their logical path
+static SPOOFED_INODES: Mutex<BTreeMap<PathBuf, u64>> = Mutex::new(BTreeMap::new());
+/// The user and group IDs a faked file is owned by, if they've been changed
+type Ownership = (Option<u32>, Option<u32>);
+/// Owners given to faked files by `chown` with `ENV_FAKEROOT_UID` or `ENV_FAKEROOT_GID` set, by
+/// their logical path
+static SPOOFED_OWNERS: Mutex<BTreeMap<PathBuf, Ownership>> = Mutex::new(BTreeMap::new());
+/// Fake files this process is copying into the fake root, so other threads wait for the copy rather
+/// than making their own from the (possibly slow) source
+static COPYING: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+/// Notified whenever a file has been removed from `COPYING`
+static COPIED: Condvar = Condvar::new();
 
-macro_rules! log {
-    ($($arg:tt)+) => {
-        if *FAKEROOT_DEBUG.get_or_init(|| is_enabled(ENV_FAKEROOT_DEBUG)) {
-            eprintln!($($arg)*);
-        }
-    };
+thread_local! {
+    /// Set while the hooks access the filesystem themselves, so those calls aren't hooked too
+    static FAKEROOT_BYPASS: Cell<bool> = const { Cell::new(false) };
+    /// The name of the hook running on this thread, if any
+    static FAKEROOT_HOOK: Cell<&'static str> = const { Cell::new("") };
+    /// Buffer reused to build fake paths in, so resolving a path doesn't allocate one each time
+    static FAKEROOT_PATH_BUF: Cell<Vec<u8>> = const { Cell::new(Vec::new()) };
 }
 
-/// Read the environment variable to know where the fake root directory is.
-/// This is used to initialise the `FAKEROOT_ROOT` `OnceCell` constant.
-fn get_fake_root() -> Result<PathBuf, Box<dyn Error>> {
-    match env::var(ENV_FAKEROOT) {
-        Ok(path) => {
-            let path = PathBuf::from(path);
-            if path.is_absolute() {
-                if path.exists() {
-                    Ok(path)
-                } else {
-                    Err(format!("{} does not exist on disk", ENV_FAKEROOT).into())
-                }
-            } else {
-                Err(format!("{} is not absolute", ENV_FAKEROOT).into())
-            }
-        }
-        Err(e) => Err(e.into()),
+/// A buffer taken from `FAKEROOT_PATH_BUF`, which is cleared and put back for reuse when dropped.
+/// A fresh one is used if it's already taken, such as by a signal handler interrupting a hook.
+struct PathBuffer(Vec<u8>);
+
+impl PathBuffer {
+    fn take() -> PathBuffer {
+        PathBuffer(FAKEROOT_PATH_BUF.try_with(Cell::take).unwrap_or_default())
+    }
+
+    fn as_path(&self) -> &Path {
+        Path::new(OsStr::from_bytes(&self.0))
     }
 }
 
-/// Return a `CString` if a file exists in the fake root for the given string.
-fn get_fake_path(c_str: &CStr) -> Result<CString, Box<dyn Error>> {
-    // parse c string
-    let path_str = match str::from_utf8(c_str.to_bytes()) {
-        Ok(actual_path) => actual_path,
-        Err(e) => {
-            return Err(format!("failed to read string: {}", e).into());
-        }
-    };
+impl Drop for PathBuffer {
+    fn drop(&mut self) {
+        let mut buf = std::mem::take(&mut self.0);
+        buf.clear();
+        let _ = FAKEROOT_PATH_BUF.try_with(|cell| cell.set(buf));
+    }
+}
 
-    // get fake root
-    let fake_root = match FAKEROOT_ROOT.get_or_init(get_fake_root) {
-        Ok(path) => path.to_path_buf(),
-        Err(e) => {
-            return Err(format!("{}", e).into());
-        }
-    };
+/// Run `f` with hooks passing straight through to the real functions on this thread.
+fn bypass<T>(f: impl FnOnce() -> T) -> T {
+    let previous = FAKEROOT_BYPASS.replace(true);
+    let result = f();
+    FAKEROOT_BYPASS.set(previous);
+    result
+}
+
+/// Whether the current call was made by the hooks themselves.
+fn bypassed() -> bool {
+    FAKEROOT_BYPASS.get()
+}
 
-    // make path relative to our fake root
-    // trim off leading `/` since `.join` will replace if it finds an absolute path
-    let fake_path = fake_root.join(&path_str[1..]);
+/// Restores the name of the previously running hook when dropped.
+struct HookGuard(&'static str);
 
-    // bail out if the file doesn't exist and `ENV_FAKEROOT_ALL` isn't enabled
-    if !is_enabled(ENV_FAKEROOT_ALL) && !fake_path.exists() {
-        return Err(format!("not in fake root: {}", path_str).into());
+impl Drop for HookGuard {
+    fn drop(&mut self) {
+        FAKEROOT_HOOK.set(self.0);
     }
+}
 
-    // we found a fake file, return a string representing its path
-    log!("{}: {} => {}", HOOK_TAG, path_str, fake_path.display());
-    Ok(CString::new(fake_path.as_os_str().as_bytes()).unwrap())
+/// Note that the hook `name` is running on this thread, until the returned guard is dropped.
+fn enter_hook(name: &'static str) -> HookGuard {
+    HookGuard(FAKEROOT_HOOK.replace(name))
 }
 
-fn is_enabled(env_key: &str) -> bool {
-    match env::var(env_key) {
-        Ok(val) => val != "false" && val != "0",
-        Err(_) => false,
+/// Records how long a hook took when dropped, if `ENV_FAKEROOT_PROFILE` is enabled.
+struct ProfileGuard {
+    name: &'static str,
+    start: Instant,
+}
+
+impl Drop for ProfileGuard {
+    fn drop(&mut self) {
+        let elapsed = self.start.elapsed();
+        if let Ok(mut profile) = FAKEROOT_PROFILE_DATA.lock() {
+            let (calls, total) = profile.entry(self.name).or_default();
+            *calls += 1;
+            *total += elapsed;
+        }
     }
 }
 
-// macros ----------------------------------------------------------------------
+/// Start timing the hook `name`, if `ENV_FAKEROOT_PROFILE` is enabled.
+fn start_profile(name: &'static str) -> Option<ProfileGuard> {
+    let enabled = *FAKEROOT_PROFILE.get_or_init(|| {
+        let enabled = is_enabled(ENV_FAKEROOT_PROFILE);
+        if enabled {
+            unsafe { libc::atexit(write_profile) };
+        }
+        enabled
+    });
 
-macro_rules! do_hook {
-    ($name:ident => $($before_arg:ident, )* [$path:ident] $(, $after_arg:ident)* $(,)?) => {
-        do_hook!($name if true => $($before_arg, )* [$path] $(, $after_arg)*)
-    };
+    enabled.then(|| ProfileGuard {
+        name,
+        start: Instant::now(),
+    })
+}
 
-    ($name:ident if $cond:expr => $($before_arg:ident, )* [$path:ident] $(, $after_arg:ident)* $(,)?) => {{
-        let real = redhook::real!($name);
-        match get_fake_path(CStr::from_ptr($path)) {
-            Ok(c_str) if $cond => real($($before_arg, )* c_str.as_ptr() $(, $after_arg)*),
-            Ok(_) => real($($before_arg, )* $path $(, $after_arg)*),
-            Err(e) => {
-                log!("{}: {}", HOOK_TAG, e);
-                real($($before_arg, )* $path $(, $after_arg)*)
-            },
+/// Write how many times each hook was called and how long they took, when the process exits.
+extern "C" fn write_profile() {
+    if let Ok(profile) = FAKEROOT_PROFILE_DATA.lock() {
+        for (name, (calls, total)) in profile.iter() {
+            write_log(format_args!(
+                "{}: profile: {}: {} calls, {}ns total, {}ns average",
+                hook_tag(),
+                name,
+                calls,
+                total.as_nanos(),
+                total.as_nanos() / u128::from(*calls)
+            ));
         }
-    }};
+    }
+
+    let denied = FAKEROOT_DENIED.load(Ordering::Relaxed);
+    if denied > 0 {
+        write_log(format_args!(
+            "{}: profile: denied: {} calls",
+            hook_tag(),
+            denied
+        ));
+    }
 }
 
-// hooks -----------------------------------------------------------------------
+/// Log level for errors encountered while resolving paths
+const LOG_ERROR: u8 = 2;
+/// Log level for paths redirected into the fake root
+const LOG_REDIRECT: u8 = 3;
+/// Log level for paths passed through to the real filesystem
+const LOG_PASSTHROUGH: u8 = 4;
 
-// open
-redhook::hook! {
-    unsafe fn open(path: *const c_char, flags: c_int, mode: c_int) -> c_int => my_open {
-        do_hook!(open => [path], flags, mode)
-    }
+macro_rules! log {
+    ($level:expr, $($arg:tt)+) => {
+        if $level <= *FAKEROOT_DEBUG.get_or_init(get_debug_level) && is_traced() && is_hook_traced() {
+            write_log(format_args!($($arg)*));
+        }
+    };
 }
 
-// open64
-redhook::hook! {
-    unsafe fn open64(path: *const c_char, flags: c_int, mode: c_int) -> c_int => my_open64 {
-        do_hook!(open64 => [path], flags, mode)
+/// The prefix for debug logs.
+fn hook_tag() -> &'static str {
+    FAKEROOT_LOG_PREFIX
+        .get_or_init(|| env::var(ENV_FAKEROOT_LOG_PREFIX).unwrap_or_else(|_| HOOK_TAG.to_string()))
+}
+
+/// Write a line to the debug log, which is STDERR unless `ENV_FAKEROOT_DEBUG_FD` or
+/// `ENV_FAKEROOT_LOG` is set.
+fn write_log(args: fmt::Arguments) {
+    if let Some(fd) = *FAKEROOT_DEBUG_FD.get_or_init(get_debug_fd) {
+        // written at once, so lines from other threads (or processes sharing it) aren't mixed in
+        let line = format!("{}\n", args);
+        unsafe { libc::write(fd, line.as_ptr().cast(), line.len()) };
+        return;
+    }
+
+    match FAKEROOT_LOG.get_or_init(LogFile::open) {
+        Some(log) => {
+            if let Ok(mut log) = log.lock() {
+                let _ = log.write(&format!("{}\n", args));
+            }
+        }
+        None => eprintln!("{}", args),
     }
 }
 
-// fopen
-redhook::hook! {
-    unsafe fn fopen(path: *const c_char, mode: *const c_char) -> *mut FILE => my_fopen {
-        do_hook!(fopen => [path], mode)
+/// Read the file descriptor to debug log to, if it's open for writing.
+/// This is used to initialise the `FAKEROOT_DEBUG_FD` `OnceLock` static.
+fn get_debug_fd() -> Option<c_int> {
+    let fd = env::var(ENV_FAKEROOT_DEBUG_FD)
+        .ok()?
+        .parse::<c_int>()
+        .ok()?;
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags == -1 || flags & libc::O_ACCMODE == libc::O_RDONLY {
+        eprintln!(
+            "{}: {} is not open for writing: {}",
+            hook_tag(),
+            ENV_FAKEROOT_DEBUG_FD,
+            fd
+        );
+        return None;
     }
+
+    Some(fd)
 }
 
-// opendir
-redhook::hook! {
-    unsafe fn opendir(path: *const c_char) -> *mut DIR => my_opendir {
-        do_hook!(opendir if is_enabled(ENV_FAKEROOT_DIRS) => [path])
+/// The file the debug log is written to, if `ENV_FAKEROOT_LOG` is set.
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    /// Bytes written to the file so far
+    size: u64,
+    /// Size after which the file is rotated, from `ENV_FAKEROOT_LOG_MAXSIZE`
+    max_size: Option<u64>,
+}
+
+impl LogFile {
+    fn open() -> Option<Mutex<LogFile>> {
+        let path = PathBuf::from(env::var_os(ENV_FAKEROOT_LOG)?);
+        let file = bypass(|| File::options().create(true).append(true).open(&path)).ok()?;
+        Some(Mutex::new(LogFile {
+            size: file.metadata().map_or(0, |metadata| metadata.len()),
+            max_size: env::var(ENV_FAKEROOT_LOG_MAXSIZE)
+                .ok()
+                .and_then(|size| size.parse().ok()),
+            path,
+            file,
+        }))
+    }
+
+    fn write(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64;
+        if self
+            .max_size
+            .is_some_and(|max| self.size > 0 && self.size + len > max)
+        {
+            self.rotate()?;
+        }
+
+        self.file.write_all(line.as_bytes())?;
+        self.size += len;
+        Ok(())
+    }
+
+    /// Move the log file to `<path>.1` (replacing any previous one), and start a new one.
+    fn rotate(&mut self) -> io::Result<()> {
+        let mut backup = self.path.clone().into_os_string();
+        backup.push(".1");
+        bypass(|| {
+            fs::rename(&self.path, backup)?;
+            self.file = File::options().create(true).append(true).open(&self.path)?;
+            self.size = 0;
+            Ok(())
+        })
     }
 }
 
-// tests -----------------------------------------------------------------------
+/// Read the environment variable to know how much debug information to log.
+/// This is used to initialise the `FAKEROOT_DEBUG` `OnceLock` static.
+fn get_debug_level() -> u8 {
+    if !is_enabled(ENV_FAKEROOT_DEBUG) {
+        return 0;
+    }
 
-#[cfg(test)]
-mod tests {
-    use std::{
-        env, fs,
-        path::{Path, PathBuf},
-        process::{self, Command},
-    };
+    // `1` (or any other truthy value) logs everything, as it did before levels existed
+    match env::var(ENV_FAKEROOT_DEBUG).map(|val| val.parse::<u8>()) {
+        Ok(Ok(level)) if level > 1 => level,
+        _ => LOG_PASSTHROUGH,
+    }
+}
 
-    use super::*;
+/// Whether the running hook should debug log, according to `ENV_FAKEROOT_DEBUG_HOOKS`.
+fn is_hook_traced() -> bool {
+    let hooks = FAKEROOT_DEBUG_HOOKS.get_or_init(|| {
+        env::var(ENV_FAKEROOT_DEBUG_HOOKS).ok().map(|hooks| {
+            let hooks = hooks
+                .split(',')
+                .map(|hook| hook.trim().to_string())
+                .filter(|hook| !hook.is_empty())
+                .collect::<Vec<_>>();
 
-    #[test]
-    fn test_is_enabled() {
-        let test_var = "test_var";
+            // this is only reached while debug logging, but `log!` would check these very hooks
+            for hook in &hooks {
+                if !available_hooks().contains(&hook.as_str()) {
+                    write_log(format_args!(
+                        "{}: unknown hook in {}: {}",
+                        hook_tag(),
+                        ENV_FAKEROOT_DEBUG_HOOKS,
+                        hook
+                    ));
+                }
+            }
 
-        env::remove_var(test_var);
-        assert_eq!(is_enabled(test_var), false);
+            hooks
+        })
+    });
 
-        env::set_var(test_var, "false");
-        assert_eq!(is_enabled(test_var), false);
+    hooks
+        .as_ref()
+        .is_none_or(|hooks| hooks.iter().any(|hook| hook == FAKEROOT_HOOK.get()))
+}
 
-        env::set_var(test_var, "0");
-        assert_eq!(is_enabled(test_var), false);
+/// Whether this process should debug log, according to `ENV_FAKEROOT_TRACE_PID`.
+fn is_traced() -> bool {
+    let trace_pid = FAKEROOT_TRACE_PID.get_or_init(|| {
+        env::var(ENV_FAKEROOT_TRACE_PID)
+            .ok()
+            .and_then(|pid| pid.parse().ok())
+    });
 
-        env::set_var(test_var, "true");
-        assert_eq!(is_enabled(test_var), true);
+    // not cached, since forked children inherit the cache but have a new PID
+    trace_pid.is_none_or(|pid| pid == unsafe { libc::getpid() })
+}
 
-        env::set_var(test_var, "1");
-        assert_eq!(is_enabled(test_var), true);
+/// Runtime configuration of the hooks.
+// the in-memory files and `ENV_FAKEROOT_CONFINE` are only supported on Linux
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+struct Options {
+    /// Absolute path to the directory to use as the fake root
+    root: Result<PathBuf, String>,
+    /// Should this also hook directories?
+    dirs: bool,
+    /// Hooks which should also hook directories, even if `dirs` isn't set
+    dirs_ops: Vec<String>,
+    /// Should only files be faked, and never directories?
+    exact: bool,
+    /// Should non existent files be faked?
+    all: bool,
+    /// Should relative paths be resolved against the current directory?
+    relative: bool,
+    /// Directory to resolve all relative paths against, from when the options were read
+    inherit_cwd: Option<PathBuf>,
+    /// Should relative paths which can't be faked be logged as errors?
+    strict_absolute: bool,
+    /// Should symlinks in the fake root be followed if they lead out of it?
+    follow: bool,
+    /// Should real files be copied into the fake root before they're written to?
+    cow: bool,
+    /// Should real files be copied into the fake root when they're opened, even for reading?
+    copy_on_open: bool,
+    /// File to write a JSON report of the paths passed to the hooks to
+    report: Option<PathBuf>,
+    /// File to append the paths created in the fake root to
+    touch_log: Option<PathBuf>,
+    /// Absolute path the fake root appears at, if it isn't `/`
+    mount_at: Option<PathBuf>,
+    /// Writable layer over the fake root, which is left untouched if set
+    cow_dir: Option<PathBuf>,
+    /// Bytes the fake root can hold before writing to it is denied
+    quota: Option<u64>,
+    /// Where should files be looked for first?
+    order: Order,
+    /// Should files in `/proc` be faked?
+    proc: bool,
+    /// Should whiteouts in the fake root hide real files?
+    whiteout: bool,
+    /// Should faked files report a synthetic device and inode number?
+    spoof_inode: bool,
+    /// User ID for faked files to report as their owner
+    uid: Option<u32>,
+    /// Group ID for faked files to report as their owner
+    gid: Option<u32>,
+    /// File to load and save the spoofed inode numbers and owners of faked files with
+    state: Option<PathBuf>,
+    /// Time for faked files to report, in seconds since the epoch
+    fake_time: Option<i64>,
+    /// Real files to copy into the fake root when the options are first used
+    seed: Vec<PathBuf>,
+    /// Paths must match this to be faked
+    match_regex: Option<Regex>,
+    /// Paths must be on this device to be faked
+    match_device: Option<u64>,
+    /// Exact paths to fake as another path
+    aliases: Vec<(PathBuf, PathBuf)>,
+    /// Exact paths to redirect somewhere other than the fake root
+    binds: Vec<(PathBuf, PathBuf)>,
+    /// Exact paths to open as in-memory files with the given content
+    inline: Vec<(PathBuf, String)>,
+    /// Exact paths to open as in-memory files with the output of the given command
+    gen: Vec<(PathBuf, String)>,
+    /// Exact paths to open as in-memory files, patched with the given hunks
+    apply_diff: Vec<(PathBuf, Vec<Hunk>)>,
+    /// Content of missing files which are opened for reading with `all`
+    default_content: Option<String>,
+    /// Should missing files be read from `<path>.gz` in the fake root?
+    gzip: bool,
+    /// Should `stat` report files which don't exist as empty ones with `all`?
+    synth_stat: bool,
+    /// Exact paths to open as `/dev/null`
+    nulldev: Vec<PathBuf>,
+    /// Should missing parent directories be created in the fake root when creating files?
+    create_parents: bool,
+    /// Should missing directories be created in the fake root when they're opened?
+    create_dirs: bool,
+    /// Permissions to leave off of directories created in the fake root
+    umask: Option<u32>,
+    /// Mode to give files created in the fake root, whatever mode they were created with
+    force_mode: Option<libc::mode_t>,
+    /// Should the setuid and setgid bits be dropped from files created in the fake root?
+    strip_suid: bool,
+    /// What should happen when a path can't be resolved?
+    on_error: OnError,
+    /// Should permission errors writing to the real filesystem be reported as `EROFS`?
+    quiet: bool,
+    /// Should opening files in `/dev`, `/proc` and `/sys` for writing be denied?
+    strict_sys: bool,
+    /// Real files and directories which can't be opened for reading
+    deny_read: Vec<PathBuf>,
+    /// Should files be opened in the fake root as though it were `/`?
+    confine: bool,
+    /// Should directory entries be listed sorted by name?
+    sorted_dirs: bool,
+}
 
-        env::set_var(test_var, "anything");
-        assert_eq!(is_enabled(test_var), true);
+impl Options {
+    /// Read the options from the environment, and the `ENV_FAKEROOT_CONFIG` file if there is one.
+    fn from_env() -> Options {
+        Options::from_settings(&Settings::load())
     }
 
-    // NOTE: this requires that `cargo build` be run before the tests are run
-    // - is there a way to use one that's built when the tests are built?
-    fn get_so() -> PathBuf {
-        env::current_exe() // target/debug/deps/<file>
-            .unwrap()
-            .parent() // target/debug/deps
-            .unwrap()
-            .parent() // target/debug
-            .unwrap()
-            .join("libfakeroot.so")
+    /// Read the options from `settings`.
+    fn from_settings(settings: &Settings) -> Options {
+        Options {
+            root: get_fake_root(settings),
+            dirs: settings.is_enabled(ENV_FAKEROOT_DIRS),
+            dirs_ops: get_dirs_ops(settings),
+            exact: settings.is_enabled(ENV_FAKEROOT_EXACT),
+            all: settings.is_enabled(ENV_FAKEROOT_ALL),
+            relative: settings.is_enabled(ENV_FAKEROOT_RELATIVE),
+            inherit_cwd: get_inherit_cwd(settings),
+            strict_absolute: settings.is_enabled(ENV_FAKEROOT_STRICT_ABSOLUTE),
+            follow: settings.get(ENV_FAKEROOT_FOLLOW).is_none()
+                || settings.is_enabled(ENV_FAKEROOT_FOLLOW),
+            cow: settings.is_enabled(ENV_FAKEROOT_COW),
+            copy_on_open: settings.is_enabled(ENV_FAKEROOT_COPY_ON_OPEN),
+            report: settings.get(ENV_FAKEROOT_REPORT).map(PathBuf::from),
+            touch_log: settings.get(ENV_FAKEROOT_TOUCH_LOG).map(PathBuf::from),
+            mount_at: get_mount_at(settings),
+            cow_dir: get_cow_dir(settings),
+            quota: get_quota(settings),
+            order: match settings.get(ENV_FAKEROOT_ORDER).as_deref() {
+                Some("real-first") => Order::RealFirst,
+                _ => Order::FakeFirst,
+            },
+            proc: settings.is_enabled(ENV_FAKEROOT_PROC),
+            whiteout: settings.is_enabled(ENV_FAKEROOT_WHITEOUT),
+            spoof_inode: settings.is_enabled(ENV_FAKEROOT_SPOOF_INODE),
+            uid: get_id(settings, ENV_FAKEROOT_UID),
+            gid: get_id(settings, ENV_FAKEROOT_GID),
+            state: settings.get(ENV_FAKEROOT_STATE).map(PathBuf::from),
+            fake_time: get_fake_time(settings),
+            seed: get_seed(settings),
+            match_regex: get_match_regex(settings),
+            match_device: get_match_device(settings),
+            aliases: get_aliases(settings),
+            binds: get_binds(settings),
+            inline: get_inline(settings),
+            gen: get_pairs(settings, ENV_FAKEROOT_GEN),
+            apply_diff: get_apply_diff(settings),
+            nulldev: settings.get_list(ENV_FAKEROOT_NULLDEV),
+            default_content: settings.get(ENV_FAKEROOT_DEFAULT_CONTENT),
+            gzip: settings.is_enabled(ENV_FAKEROOT_GZIP),
+            synth_stat: settings.is_enabled(ENV_FAKEROOT_SYNTH_STAT),
+            create_parents: settings.get(ENV_FAKEROOT_CREATE_PARENTS).is_none()
+                || settings.is_enabled(ENV_FAKEROOT_CREATE_PARENTS),
+            create_dirs: settings.get(ENV_FAKEROOT_ON_MISSING_DIR).as_deref() == Some("create"),
+            umask: get_umask(settings),
+            force_mode: get_octal(settings, ENV_FAKEROOT_FORCE_MODE)
+                .map(|mode| (mode & 0o7777) as libc::mode_t),
+            strip_suid: settings.is_enabled(ENV_FAKEROOT_STRIP_SUID),
+            on_error: match settings.get(ENV_FAKEROOT_ON_ERROR).as_deref() {
+                Some("fail") => OnError::Fail,
+                _ => OnError::Passthrough,
+            },
+            quiet: settings.is_enabled(ENV_FAKEROOT_QUIET),
+            strict_sys: settings.is_enabled(ENV_FAKEROOT_STRICT_SYS),
+            deny_read: settings.get_list(ENV_FAKEROOT_DENY_READ),
+            confine: settings.is_enabled(ENV_FAKEROOT_CONFINE),
+            sorted_dirs: settings.is_enabled(ENV_FAKEROOT_DETERMINISTIC_DIR_ORDER),
+        }
+    }
+}
+
+/// Option values from the environment, falling back to those in the `ENV_FAKEROOT_ENV_FILE` and
+/// then the `ENV_FAKEROOT_CONFIG` files.
+struct Settings {
+    env_file: Result<BTreeMap<String, String>, String>,
+    config: Result<BTreeMap<String, String>, String>,
+}
+
+impl Settings {
+    fn load() -> Settings {
+        Settings {
+            env_file: match env::var_os(ENV_FAKEROOT_ENV_FILE) {
+                Some(path) => bypass(|| fs::read_to_string(path))
+                    .map_err(|e| format!("failed to read {}: {}", ENV_FAKEROOT_ENV_FILE, e))
+                    .and_then(|contents| parse_env_file(&contents)),
+                None => Ok(BTreeMap::new()),
+            },
+            config: match env::var_os(ENV_FAKEROOT_CONFIG) {
+                // the hooks would otherwise try to read the options while they're being read
+                Some(path) => bypass(|| fs::read_to_string(path))
+                    .map_err(|e| format!("failed to read {}: {}", ENV_FAKEROOT_CONFIG, e))
+                    .and_then(|contents| parse_config(&contents)),
+                None => Ok(BTreeMap::new()),
+            },
+        }
     }
 
-    macro_rules! cat {
-        ($p:expr) => {
-            fs::read_to_string($p).unwrap()
-        };
+    /// Get the value of the environment variable `env_key`, or otherwise the same variable in the
+    /// env file, or otherwise the matching key in the config file: `root` for `ENV_FAKEROOT`, and
+    /// the rest of the name in lowercase for the others (so `all` for `ENV_FAKEROOT_ALL`).
+    fn get(&self, env_key: &str) -> Option<String> {
+        let env_file = || self.env_file.as_ref().ok()?.get(env_key).cloned();
+        env::var(env_key).ok().or_else(env_file).or_else(|| {
+            let key = match env_key.strip_prefix("FAKEROOT_") {
+                Some(key) => key.to_lowercase(),
+                None => "root".into(),
+            };
+            self.config.as_ref().ok()?.get(&key).cloned()
+        })
     }
 
-    macro_rules! cmd {
-        (
-            $fake_root:expr,
-            $cmd:expr
-            $(, all = $all:literal)?
-            $(, dirs = $dirs:literal)?
-            $(, debug = $debug:literal)?
-            $(,)?
-        ) => {{
-            let mut cmd = Command::new("sh");
-            cmd.arg("-c")
-                .arg($cmd)
-                .env("LD_PRELOAD", get_so().display().to_string())
-                .env(ENV_FAKEROOT, $fake_root);
+    fn is_enabled(&self, env_key: &str) -> bool {
+        self.get(env_key).is_some_and(|val| is_truthy(&val))
+    }
 
-            $(
-                if $all {
-                    cmd.env(ENV_FAKEROOT_ALL, "1");
-                }
-            )?
+    /// Get a list of paths, separated by `:` unless `ENV_FAKEROOT_SEP` gives another separator (so
+    /// that paths containing colons can be listed).
+    fn get_list(&self, env_key: &str) -> Vec<PathBuf> {
+        let list = match self.get(env_key) {
+            Some(list) => list,
+            None => return Vec::new(),
+        };
 
-            $(
-                if $dirs {
-                    cmd.env(ENV_FAKEROOT_DIRS, "1");
-                }
-            )?
+        let sep = self.get(ENV_FAKEROOT_SEP).unwrap_or_else(|| ":".into());
+        if sep.is_empty() {
+            return vec![PathBuf::from(list)];
+        }
 
-            $(
-                if $debug {
-                    cmd.env(ENV_FAKEROOT_DEBUG, "1");
-                }
-            )?
+        list.split(sep.as_str())
+            .filter(|path| !path.is_empty())
+            .map(PathBuf::from)
+            .collect()
+    }
+}
 
-            let output = cmd.output()
-                .unwrap();
+/// Parse the `KEY=VALUE` lines of a dotenv-style file: blank lines and comments are skipped, an
+/// `export ` prefix is allowed, and values may be quoted.
+fn parse_env_file(contents: &str) -> Result<BTreeMap<String, String>, String> {
+    let mut vars = BTreeMap::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
 
-            let success = output.status.success();
-            if !success {
-                assert!(
-                    false,
-                    "\"{}\" -> {}\n{}",
-                    $cmd,
-                    output.status,
-                    String::from_utf8_lossy(&output.stderr).trim()
-                );
-            }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (key, value) = line.split_once('=').ok_or_else(|| {
+            format!(
+                "{} line {}: expected `KEY=VALUE`",
+                ENV_FAKEROOT_ENV_FILE,
+                i + 1
+            )
+        })?;
+        let value = value.trim();
+        let value = ['"', '\'']
+            .iter()
+            .find_map(|quote| value.strip_prefix(*quote)?.strip_suffix(*quote))
+            .unwrap_or(value);
 
-            output
-        }};
+        vars.insert(key.trim().to_string(), value.to_string());
     }
 
-    // TODO: include doc comments so can add #[should_panic] on top
-    macro_rules! test {
-        ($(#[$($attr:tt)+] )? $name:ident, $f:expr) => {
-            #[test]
-            $(#[$($attr)+])?
-            fn $name() {
-                let tmp_dir = env::temp_dir().join(format!(
-                    "fakehook-{}-{}",
-                    stringify!($name),
-                    process::id()
-                ));
-                std::fs::create_dir_all(&tmp_dir).unwrap();
-                $f(&tmp_dir);
-                std::fs::remove_dir_all(&tmp_dir).unwrap();
-            }
+    Ok(vars)
+}
+
+/// Parse the `key = value` lines of a config file. This is a small subset of TOML: blank lines,
+/// comments, and values which are either quoted strings or bare words (such as `true` or `1`).
+fn parse_config(contents: &str) -> Result<BTreeMap<String, String>, String> {
+    let mut config = BTreeMap::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (key, value) = line.split_once('=').ok_or_else(|| {
+            format!(
+                "{} line {}: expected `key = value`",
+                ENV_FAKEROOT_CONFIG,
+                i + 1
+            )
+        })?;
+        let value = value.trim();
+        let value = match value.strip_prefix('"') {
+            Some(quoted) => quoted
+                .split_once('"')
+                .map(|(value, _)| value)
+                .ok_or_else(|| {
+                    format!(
+                        "{} line {}: unterminated string",
+                        ENV_FAKEROOT_CONFIG,
+                        i + 1
+                    )
+                })?,
+            None => value.split('#').next().unwrap_or_default().trim(),
         };
+
+        config.insert(key.trim().to_string(), value.to_string());
     }
 
-    test!(simple, |dir: &Path| {
-        let fake_etc = dir.join("etc");
-        fs::create_dir_all(&fake_etc).unwrap();
-        fs::write(fake_etc.join("hosts"), "🎉").unwrap();
+    Ok(config)
+}
 
-        // check hook worked
-        let output = cmd!(&dir, "cat /etc/hosts");
-        assert_eq!(String::from_utf8_lossy(&output.stdout), "🎉");
+/// The order in which the fake root and real filesystem are checked for files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Order {
+    /// Use the fake file whenever it exists
+    FakeFirst,
+    /// Only use the fake file when the real one doesn't exist
+    RealFirst,
+}
 
-        // check other files aren't hooked
-        let output = cmd!(&dir, "cat /etc/passwd", debug = true);
-        assert_eq!(output.stdout, fs::read("/etc/passwd").unwrap());
-    });
+/// What happens to a hooked call when its path can't be resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OnError {
+    /// Call the real function with the original path
+    Passthrough,
+    /// Fail the call with `EINVAL`
+    Fail,
+}
 
-    test!(debug, |dir: &Path| {
-        let fake_etc = dir.join("etc");
-        fs::create_dir_all(&fake_etc).unwrap();
-        fs::write(fake_etc.join("hosts"), "🎉").unwrap();
+/// An error which should fail the hooked call with `errno`, rather than pass through to the real
+/// filesystem.
+#[derive(Debug)]
+struct Denied {
+    errno: c_int,
+    reason: String,
+}
 
-        // this checks ENV_DEBUG behaviour, so ensure it's not set
-        assert!(
-            env::var(ENV_FAKEROOT_DEBUG).is_err(),
-            "DEBUG must not be defined during tests"
-        );
+impl Display for Denied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "denied: {}", self.reason)
+    }
+}
 
-        // should be no logs
-        let output = cmd!(&dir, "cat /etc/hosts");
-        assert_eq!(String::from_utf8_lossy(&output.stderr), "");
+impl Error for Denied {}
 
-        // should be logs
-        let output = cmd!(&dir, "cat /etc/passwd", debug = true);
-        assert!(String::from_utf8_lossy(&output.stderr)
-            .contains("@HOOK@: not in fake root: /etc/passwd"));
-    });
+/// Values which hooked functions return to signal failure.
+trait Failure {
+    const FAILURE: Self;
+}
 
-    test!(dir, |dir: &PathBuf| {
-        let fake_etc = dir.join("etc");
-        fs::create_dir_all(&fake_etc).unwrap();
-        fs::write(fake_etc.join("FAKED"), "💥").unwrap();
+impl Failure for c_int {
+    const FAILURE: Self = -1;
+}
 
-        // check dir not hooked
-        let output = cmd!(&dir, "ls /etc");
-        assert_ne!(String::from_utf8_lossy(&output.stdout).trim(), "FAKED");
+impl Failure for ssize_t {
+    const FAILURE: Self = -1;
+}
 
-        // check dir hooked
-        let output = cmd!(&dir, "ls /etc", dirs = true);
-        assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "FAKED");
-    });
+impl<T> Failure for *mut T {
+    const FAILURE: Self = ptr::null_mut();
+}
+
+/// Report a real write which failed because of the real filesystem's permissions as `EROFS`, if
+/// `ENV_FAKEROOT_QUIET` is enabled.
+unsafe fn quiet<T: Failure + PartialEq>(ret: T, flags: Option<c_int>) -> T {
+    if ret != T::FAILURE || bypassed() {
+        return ret;
+    }
+
+    let errno = *errno_location();
+    if is_writing(flags) && matches!(errno, libc::EACCES | libc::EPERM) && options().quiet {
+        *errno_location() = libc::EROFS;
+    }
+
+    ret
+}
+
+/// Whether a call redirected to the fake copy of `path` failed because it was removed after it was
+/// found there, in which case (unless `ENV_FAKEROOT_ALL` is enabled) the real path should be used as
+/// though it had never been there.
+unsafe fn vanished<T: Failure + PartialEq>(ret: &T, path: &CStr) -> bool {
+    if *ret != T::FAILURE || *errno_location() != libc::ENOENT || options().all {
+        return false;
+    }
+
+    log!(
+        LOG_REDIRECT,
+        "{}: removed from fake root: {}",
+        hook_tag(),
+        path.to_string_lossy()
+    );
+    true
+}
+
+/// Are the `open` flags `flags` (if the file is being opened) for writing to it?
+fn is_writing(flags: Option<c_int>) -> bool {
+    flags.is_some_and(|flags| {
+        flags != -1 && (flags & libc::O_ACCMODE != libc::O_RDONLY || flags & libc::O_CREAT != 0)
+    })
+}
+
+/// Fail a hooked call with `errno`.
+unsafe fn deny<T: Failure>(errno: c_int) -> T {
+    *errno_location() = errno;
+    T::FAILURE
+}
+
+/// Where this thread's `errno` is stored.
+#[cfg(target_os = "linux")]
+unsafe fn errno_location() -> *mut c_int {
+    libc::__errno_location()
+}
+
+/// Where this thread's `errno` is stored.
+#[cfg(any(target_os = "freebsd", target_os = "macos"))]
+unsafe fn errno_location() -> *mut c_int {
+    libc::__error()
+}
+
+/// The kinds of hooked calls, which aren't all redirected in the same cases.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hook {
+    /// Calls which access a file (or anything else) by its path
+    File,
+    /// Calls which list or enter directories, which are only redirected if `ENV_FAKEROOT_DIRS` is
+    /// enabled (or the hook is in `ENV_FAKEROOT_DIRS_OPS`)
+    Dir,
+}
+
+/// Whether the running hook should redirect directories, according to `ENV_FAKEROOT_DIRS` and
+/// `ENV_FAKEROOT_DIRS_OPS` (and never with `ENV_FAKEROOT_EXACT`).
+fn hooks_dirs(options: &Options) -> bool {
+    !options.exact && (options.dirs || options.dirs_ops.iter().any(|op| op == FAKEROOT_HOOK.get()))
+}
+
+/// What a hooked call should do with the path it was given.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decision {
+    /// Call the real function with this path instead
+    Redirect(CString),
+    /// Call the real function with the original path
+    Passthrough,
+    /// Fail the call with this `errno`
+    Deny(c_int),
+}
+
+/// Decide what a hooked call of the kind `hook` should do with `path`. `flags` are the `open` flags
+/// the file is being opened with, if it is.
+pub fn decide(hook: Hook, path: &CStr, flags: Option<c_int>) -> Decision {
+    if bypassed() {
+        return Decision::Passthrough;
+    }
+
+    // looking in the fake root uses the `stat` family, which mustn't be hooked while doing so
+    let decision = bypass(|| match decide_with(options(), hook, path, flags) {
+        Decision::Passthrough if is_read_denied(options(), path, flags) => {
+            log!(
+                LOG_ERROR,
+                "{}: read denied ({}): {}",
+                hook_tag(),
+                ENV_FAKEROOT_DENY_READ,
+                path.to_string_lossy()
+            );
+            Decision::Deny(libc::EACCES)
+        }
+        Decision::Redirect(_) if is_writing(flags) && over_quota(options()) => {
+            log!(
+                LOG_ERROR,
+                "{}: write denied ({}): {}",
+                hook_tag(),
+                ENV_FAKEROOT_QUOTA,
+                path.to_string_lossy()
+            );
+            Decision::Deny(libc::ENOSPC)
+        }
+        decision => decision,
+    });
+    report(path, &decision);
+    audit(path, &decision);
+    bypass(|| log_backtrace(path));
+    decision
+}
+
+/// Log where the hooked call with `path` was made from, if `ENV_FAKEROOT_DEBUG_BACKTRACE` is
+/// enabled and the path matches `ENV_FAKEROOT_MATCH_REGEX`. This must be called while the hooks are
+/// bypassed, since finding the symbols reads files.
+fn log_backtrace(path: &CStr) {
+    if !*FAKEROOT_DEBUG_BACKTRACE.get_or_init(|| is_enabled(ENV_FAKEROOT_DEBUG_BACKTRACE)) {
+        return;
+    }
+
+    let path_str = path.to_string_lossy();
+    if let Some(regex) = &options().match_regex {
+        let logical_path = get_logical_path(options(), &path_str);
+        if !logical_path.is_some_and(|path| regex.is_match(&path.to_string_lossy())) {
+            return;
+        }
+    }
+
+    log!(
+        LOG_ERROR,
+        "{}: backtrace for {}:\n{}",
+        hook_tag(),
+        path_str,
+        Backtrace::force_capture()
+    );
+}
+
+/// Count the call with `path` if it was denied, and log it if `ENV_FAKEROOT_AUDIT_DENIED` is enabled.
+fn audit(path: &CStr, decision: &Decision) {
+    let errno = match decision {
+        Decision::Deny(errno) => *errno,
+        _ => return,
+    };
+
+    FAKEROOT_DENIED.fetch_add(1, Ordering::Relaxed);
+    let enabled = *FAKEROOT_AUDIT_DENIED.get_or_init(|| {
+        let enabled = is_enabled(ENV_FAKEROOT_AUDIT_DENIED);
+        if enabled {
+            unsafe { libc::atexit(write_audit) };
+        }
+        enabled
+    });
+    if enabled {
+        write_log(format_args!(
+            "{}: denied: {}: {}: {}",
+            hook_tag(),
+            FAKEROOT_HOOK.get(),
+            path.to_string_lossy(),
+            io::Error::from_raw_os_error(errno)
+        ));
+    }
+}
+
+/// Write how many calls were denied, when the process exits.
+extern "C" fn write_audit() {
+    write_log(format_args!(
+        "{}: audit: {} calls denied",
+        hook_tag(),
+        FAKEROOT_DENIED.load(Ordering::Relaxed)
+    ));
+}
+
+/// Remember what was done with `path`, if `ENV_FAKEROOT_REPORT` is set.
+fn report(path: &CStr, decision: &Decision) {
+    if options().report.is_none() {
+        return;
+    }
+    FAKEROOT_REPORT.call_once(|| unsafe {
+        libc::atexit(write_report);
+    });
+
+    let action = match decision {
+        Decision::Redirect(_) => "redirect",
+        Decision::Passthrough => "passthrough",
+        Decision::Deny(_) => "deny",
+    };
+    if let Ok(mut report) = FAKEROOT_REPORT_DATA.lock() {
+        let path = path.to_string_lossy().into_owned();
+        report.insert((path, action, FAKEROOT_HOOK.get()));
+    }
+}
+
+/// Merge the paths touched by this process into the `ENV_FAKEROOT_REPORT` file.
+extern "C" fn write_report() {
+    FAKEROOT_REPORT_WRITTEN.call_once(|| {
+        let report = FAKEROOT_OPTIONS
+            .get()
+            .and_then(|options| options.report.as_ref());
+        if let Some(report) = report {
+            if let Err(e) = bypass(|| merge_report(report)) {
+                log!(
+                    LOG_ERROR,
+                    "{}: failed to write {}: {}",
+                    hook_tag(),
+                    ENV_FAKEROOT_REPORT,
+                    e
+                );
+            }
+        }
+    });
+}
+
+/// Write the report as one entry per line, so the entries of other processes sharing the same file
+/// can be merged without parsing the JSON.
+fn merge_report(report: &Path) -> io::Result<()> {
+    let mut entries = match FAKEROOT_REPORT_DATA.lock() {
+        Ok(data) => data
+            .iter()
+            .map(|(path, action, hook)| {
+                format!(
+                    r#"{{"path": {}, "action": "{}", "hook": "{}"}}"#,
+                    json_string(path),
+                    action,
+                    hook
+                )
+            })
+            .collect::<BTreeSet<_>>(),
+        Err(_) => return Ok(()),
+    };
+
+    let mut file = File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(report)?;
+
+    // processes exiting at the same time would otherwise lose each other's entries
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    entries.extend(
+        contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| line.starts_with('{'))
+            .map(|line| line.trim_end_matches(',').to_string()),
+    );
+
+    let entries = entries.into_iter().collect::<Vec<_>>();
+    let json = format!("[\n  {}\n]\n", entries.join(",\n  "));
+    file.set_len(0)?;
+    file.rewind()?;
+    file.write_all(json.as_bytes())
+}
+
+/// Quote `s` as a JSON string.
+fn json_string(s: &str) -> String {
+    let mut json = String::from('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if c.is_control() => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+
+    json.push('"');
+    json
+}
+
+/// Decide what a hooked call should do according to `options`. Errors resolving the path fail
+/// denied paths with their `errno`, and otherwise pass through to the real function (or fail with
+/// `EINVAL` if `ENV_FAKEROOT_ON_ERROR` is `fail`).
+fn decide_with(options: &Options, hook: Hook, path: &CStr, flags: Option<c_int>) -> Decision {
+    if options.strict_sys && is_writing(flags) && is_sys_path(options, path) {
+        log!(
+            LOG_ERROR,
+            "{}: write denied ({}): {}",
+            hook_tag(),
+            ENV_FAKEROOT_STRICT_SYS,
+            path.to_string_lossy()
+        );
+        return Decision::Deny(libc::EROFS);
+    }
+
+    // nothing which is only used as a directory is looked for in the fake root
+    let directory = flags.is_some_and(|flags| flags != -1 && flags & libc::O_DIRECTORY != 0);
+    if options.exact && (hook == Hook::Dir || directory) {
+        return Decision::Passthrough;
+    }
+
+    let nofollow = flags.is_some_and(|flags| flags != -1 && flags & libc::O_NOFOLLOW != 0);
+    match find_fake_path(options, path, nofollow) {
+        Ok(Some(c_str)) if hook == Hook::Dir && !hooks_dirs(options) => {
+            // the fake root still decides what isn't a directory, so `O_DIRECTORY` fails the same
+            // way whether the real path is a directory or not
+            let fake_file =
+                || fs::metadata(OsStr::from_bytes(c_str.to_bytes())).is_ok_and(|m| !m.is_dir());
+            if directory && fake_file() {
+                log!(
+                    LOG_REDIRECT,
+                    "{}: not a directory in fake root: {}",
+                    hook_tag(),
+                    path.to_string_lossy()
+                );
+                Decision::Deny(libc::ENOTDIR)
+            } else {
+                Decision::Passthrough
+            }
+        }
+        Ok(Some(c_str))
+            if options.exact
+                && fs::metadata(OsStr::from_bytes(c_str.to_bytes())).is_ok_and(|m| m.is_dir()) =>
+        {
+            Decision::Passthrough
+        }
+        Ok(Some(c_str)) => {
+            // exclusive creates are checked against the file that's actually opened, which in
+            // `ENV_FAKEROOT_ALL` mode is always the fake one
+            if options.all && flags.is_some_and(|flags| flags & libc::O_EXCL != 0) {
+                log!(
+                    LOG_REDIRECT,
+                    "{}: exclusive create in fake root: {}",
+                    hook_tag(),
+                    path.to_string_lossy()
+                );
+            }
+
+            Decision::Redirect(c_str)
+        }
+        Ok(None) => Decision::Passthrough,
+        Err(e) => {
+            log!(LOG_ERROR, "{}: {}", hook_tag(), e);
+            match e.downcast_ref::<Denied>() {
+                Some(denied) => Decision::Deny(denied.errno),
+                None if options.on_error == OnError::Fail => Decision::Deny(libc::EINVAL),
+                None => Decision::Passthrough,
+            }
+        }
+    }
+}
+
+/// Is `path` in one of the `STRICT_SYS` directories?
+fn is_sys_path(options: &Options, path: &CStr) -> bool {
+    resolve_any(options, path).is_some_and(|path| STRICT_SYS.iter().any(|p| path.starts_with(p)))
+}
+
+/// Is `path` (which isn't faked) being opened for reading, and one of the `ENV_FAKEROOT_DENY_READ`
+/// files or in one of its directories? Symlinks are resolved too, so they can't be used to get
+/// around it.
+fn is_read_denied(options: &Options, path: &CStr, flags: Option<c_int>) -> bool {
+    let reading = flags.is_some_and(|flags| {
+        flags != -1 && !is_path_only(flags) && flags & libc::O_ACCMODE != libc::O_WRONLY
+    });
+    if options.deny_read.is_empty() || !reading {
+        return false;
+    }
+
+    let Some(logical_path) = resolve_any(options, path) else {
+        return false;
+    };
+    let canonical_path = fs::canonicalize(&logical_path).ok();
+    [Some(logical_path), canonical_path]
+        .iter()
+        .flatten()
+        .any(|path| options.deny_read.iter().any(|p| path.starts_with(p)))
+}
+
+/// The logical path of `path`. Relative paths are resolved against the current directory even if
+/// `ENV_FAKEROOT_RELATIVE` isn't enabled, so they can't be used to get around the checks using this.
+fn resolve_any(options: &Options, path: &CStr) -> Option<PathBuf> {
+    let path_str = str::from_utf8(path.to_bytes()).ok()?;
+    get_logical_path(options, path_str).or_else(|| {
+        let cwd = env::current_dir().ok()?;
+        Some(normalize(&cwd.join(path_str)))
+    })
+}
+
+/// Return the options, reading them from the environment if `fakeroot_init` wasn't called.
+fn options() -> &'static Options {
+    // checking the root goes through the `stat` family, which would otherwise need the options
+    let options = FAKEROOT_OPTIONS.get_or_init(|| bypass(Options::from_env));
+    FAKEROOT_BANNER.call_once(|| banner(options));
+    FAKEROOT_SEEDED.call_once(|| seed(options));
+    FAKEROOT_STATE_LOADED.call_once(|| load_state(options));
+    options
+}
+
+/// Log which version of the library is loaded, and with which fake root, to help tell which library
+/// was actually preloaded.
+fn banner(options: &Options) {
+    log!(
+        LOG_REDIRECT,
+        "{}: fakeroot {} loaded, root: {}",
+        hook_tag(),
+        env!("CARGO_PKG_VERSION"),
+        match &options.root {
+            Ok(root) => root.display().to_string(),
+            Err(e) => e.clone(),
+        }
+    );
+}
+
+/// Read the environment variable (or config file) to know where the fake root directory is.
+fn get_fake_root(settings: &Settings) -> Result<PathBuf, String> {
+    if let Err(e) = &settings.config {
+        return Err(e.clone());
+    }
+
+    if let Err(e) = &settings.env_file {
+        return Err(e.clone());
+    }
+
+    let path = match settings.get(ENV_FAKEROOT_ROOT_PER_UID) {
+        Some(template) => expand_per_uid(&template)?,
+        None => settings
+            .get(ENV_FAKEROOT)
+            .ok_or_else(|| format!("{} is not set", ENV_FAKEROOT))?,
+    };
+
+    if settings.is_enabled(ENV_FAKEROOT_PROPAGATE) {
+        // resolved against the current directory now, rather than wherever children start
+        let root = fs::canonicalize(&path).unwrap_or_else(|_| PathBuf::from(path));
+        let root = check_fake_root(root)?;
+        propagate(&root);
+        Ok(root)
+    } else {
+        check_fake_root(PathBuf::from(path))
+    }
+}
+
+/// Expand the placeholders in the `ENV_FAKEROOT_ROOT_PER_UID` template for the user running this
+/// process: `%u` is its user ID, `%U` its user name and `%%` a literal `%`.
+fn expand_per_uid(template: &str) -> Result<String, String> {
+    let uid = unsafe { libc::getuid() };
+    let mut expanded = String::new();
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            expanded.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('u') => expanded.push_str(&uid.to_string()),
+            Some('U') => match user_name(uid) {
+                Some(name) => expanded.push_str(&name),
+                None => {
+                    return Err(format!(
+                        "{}: no user name for {}",
+                        ENV_FAKEROOT_ROOT_PER_UID, uid
+                    ))
+                }
+            },
+            Some('%') => expanded.push('%'),
+            c => {
+                return Err(format!(
+                    "{}: unknown placeholder: %{}",
+                    ENV_FAKEROOT_ROOT_PER_UID,
+                    c.map(String::from).unwrap_or_default()
+                ))
+            }
+        }
+    }
+
+    Ok(expanded)
+}
+
+/// The name of the user with the ID `uid`, if it has one.
+fn user_name(uid: libc::uid_t) -> Option<String> {
+    let mut passwd = unsafe { mem::zeroed::<libc::passwd>() };
+    let mut buf = vec![0 as c_char; 4096];
+    let mut result = ptr::null_mut();
+    let ret =
+        unsafe { libc::getpwuid_r(uid, &mut passwd, buf.as_mut_ptr(), buf.len(), &mut result) };
+    if ret != 0 || result.is_null() {
+        return None;
+    }
+
+    let name = unsafe { CStr::from_ptr(passwd.pw_name) };
+    Some(name.to_string_lossy().into_owned())
+}
+
+/// Give the fake root to the programs this process executes, even if they're given a different
+/// value. This process's own environment is left alone, since the hooks may first be used while
+/// another thread is reading it.
+fn propagate(root: &Path) {
+    let _ = FAKEROOT_PROPAGATED.set(root.to_path_buf());
+}
+
+/// Read the list of files to seed the fake root with.
+fn get_seed(settings: &Settings) -> Vec<PathBuf> {
+    settings.get_list(ENV_FAKEROOT_SEED)
+}
+
+/// Compile the regular expression which paths must match to be faked.
+fn get_match_regex(settings: &Settings) -> Option<Regex> {
+    let regex = settings.get(ENV_FAKEROOT_MATCH_REGEX)?;
+    match Regex::new(&regex) {
+        Ok(regex) => Some(regex),
+        Err(e) => {
+            log!(
+                LOG_ERROR,
+                "{}: invalid {}: {}",
+                hook_tag(),
+                ENV_FAKEROOT_MATCH_REGEX,
+                e
+            );
+            None
+        }
+    }
+}
+
+/// Read the device ID which paths must be on to be faked, either as a number or from a path on it.
+fn get_match_device(settings: &Settings) -> Option<u64> {
+    let device = settings.get(ENV_FAKEROOT_MATCH_DEVICE)?;
+    if let Ok(device) = device.parse() {
+        return Some(device);
+    }
+
+    match bypass(|| fs::metadata(&device)) {
+        Ok(metadata) => Some(metadata.dev()),
+        Err(e) => {
+            log!(
+                LOG_ERROR,
+                "{}: invalid {}: {}: {}",
+                hook_tag(),
+                ENV_FAKEROOT_MATCH_DEVICE,
+                device,
+                e
+            );
+            None
+        }
+    }
+}
+
+/// Read the `path<-alias,...` entries of paths to fake as another one, as `(alias, path)` pairs.
+fn get_aliases(settings: &Settings) -> Vec<(PathBuf, PathBuf)> {
+    let mut aliases = Vec::new();
+    for entry in settings.get_list(ENV_FAKEROOT_ALIAS) {
+        let entry = entry.to_string_lossy();
+        let valid = entry.split_once("<-").filter(|(path, names)| {
+            Path::new(path).is_absolute()
+                && names.split(',').all(|alias| Path::new(alias).is_absolute())
+        });
+        match valid {
+            Some((path, names)) => aliases.extend(
+                names
+                    .split(',')
+                    .map(|alias| (PathBuf::from(alias), PathBuf::from(path))),
+            ),
+            None => log!(
+                LOG_ERROR,
+                "{}: invalid {}: {}",
+                hook_tag(),
+                ENV_FAKEROOT_ALIAS,
+                entry
+            ),
+        }
+    }
+
+    aliases
+}
+
+/// Read the `virtual=real` pairs of paths to redirect.
+fn get_binds(settings: &Settings) -> Vec<(PathBuf, PathBuf)> {
+    get_pairs(settings, ENV_FAKEROOT_BIND)
+        .into_iter()
+        .map(|(virt, real)| (virt, PathBuf::from(real)))
+        .collect()
+}
+
+/// Read the `path=content` pairs of files to create in memory.
+fn get_inline(settings: &Settings) -> Vec<(PathBuf, String)> {
+    get_pairs(settings, ENV_FAKEROOT_INLINE)
+}
+
+/// A hunk of a unified diff: the index of the line in the original file it starts at, and its
+/// lines (each starting with ` `, `-` or `+`, and ending with the newline it had, if any).
+#[derive(Debug, PartialEq)]
+struct Hunk {
+    start: usize,
+    lines: Vec<String>,
+}
+
+/// Read the unified diff to patch files with as they're opened.
+fn get_apply_diff(settings: &Settings) -> Vec<(PathBuf, Vec<Hunk>)> {
+    let Some(diff_path) = settings.get(ENV_FAKEROOT_APPLY_DIFF) else {
+        return Vec::new();
+    };
+
+    let patches = bypass(|| fs::read_to_string(&diff_path))
+        .map_err(|e| e.to_string())
+        .and_then(|diff| parse_diff(&diff));
+    match patches {
+        Ok(patches) => patches,
+        Err(e) => {
+            log!(
+                LOG_ERROR,
+                "{}: invalid {}: {}: {}",
+                hook_tag(),
+                ENV_FAKEROOT_APPLY_DIFF,
+                diff_path,
+                e
+            );
+            Vec::new()
+        }
+    }
+}
+
+/// Parse the hunks of each file patched by a unified diff. Anything outside of the file headers and
+/// hunks (such as `diff --git` lines) is ignored.
+fn parse_diff(diff: &str) -> Result<Vec<(PathBuf, Vec<Hunk>)>, String> {
+    let mut patches: Vec<(PathBuf, Vec<Hunk>)> = Vec::new();
+    let mut lines = diff.split_inclusive('\n').peekable();
+    while let Some(line) = lines.next() {
+        if let Some(path) = line.strip_prefix("+++ ") {
+            // the path may be followed by a timestamp
+            let path = path
+                .trim_end_matches('\n')
+                .split('\t')
+                .next()
+                .unwrap_or_default();
+            patches.push((diff_path(path), Vec::new()));
+            continue;
+        }
+
+        let Some(header) = line.strip_prefix("@@ -") else {
+            continue;
+        };
+        let (_, hunks) = patches.last_mut().ok_or("hunk without a file header")?;
+        let (start, mut old, mut new) = parse_hunk_header(header)
+            .ok_or_else(|| format!("invalid hunk header: {}", line.trim_end()))?;
+
+        // an empty range in the original file is given as the line before where the hunk goes
+        let start = if old == 0 {
+            start
+        } else {
+            start.saturating_sub(1)
+        };
+        let mut hunk = Hunk {
+            start,
+            lines: Vec::new(),
+        };
+        loop {
+            // a line which didn't end with a newline is marked by the line after it
+            if lines.peek().is_some_and(|line| line.starts_with('\\')) {
+                lines.next();
+                if let Some(last) = hunk.lines.last_mut() {
+                    if last.ends_with('\n') {
+                        last.pop();
+                    }
+                }
+                continue;
+            }
+
+            if old == 0 && new == 0 {
+                break;
+            }
+
+            // some editors strip the trailing space from empty context lines
+            let line = match lines.next().ok_or("hunk is shorter than its header")? {
+                "\n" => " \n",
+                line => line,
+            };
+            let (take_old, take_new) = match line.as_bytes()[0] {
+                b' ' => (1, 1),
+                b'-' => (1, 0),
+                b'+' => (0, 1),
+                _ => return Err(format!("invalid hunk line: {}", line.trim_end())),
+            };
+            if old < take_old || new < take_new {
+                return Err(format!(
+                    "hunk is longer than its header: {}",
+                    line.trim_end()
+                ));
+            }
+            old -= take_old;
+            new -= take_new;
+            hunk.lines.push(line.to_string());
+        }
+        hunks.push(hunk);
+    }
+
+    // removed files have nothing to patch
+    patches.retain(|(path, _)| path != Path::new("/dev/null"));
+    Ok(patches)
+}
+
+/// Parse the start and length of the original and new ranges of a hunk, from the header after its
+/// leading `@@ -`.
+fn parse_hunk_header(header: &str) -> Option<(usize, usize, usize)> {
+    let range = |range: &str| -> Option<(usize, usize)> {
+        match range.split_once(',') {
+            Some((start, len)) => Some((start.parse().ok()?, len.parse().ok()?)),
+            None => Some((range.parse().ok()?, 1)),
+        }
+    };
+
+    let (old, rest) = header.split_once(" +")?;
+    let (new, _) = rest.split_once(" @@")?;
+    let ((start, old), (_, new)) = (range(old)?, range(new)?);
+    Some((start, old, new))
+}
+
+/// The logical path a diff patches, stripping the first component of relative paths.
+fn diff_path(path: &str) -> PathBuf {
+    let path = Path::new(path);
+    if path.is_absolute() {
+        return path.to_path_buf();
+    }
+
+    Path::new("/").join(path.components().skip(1).collect::<PathBuf>())
+}
+
+/// Apply the `hunks` of a diff to `content`, failing if any of their lines don't match it.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn apply_hunks(content: &str, hunks: &[Hunk]) -> Result<String, String> {
+    let lines = content.split_inclusive('\n').collect::<Vec<_>>();
+    let mut patched = String::new();
+    let mut pos = 0;
+    for hunk in hunks {
+        if hunk.start < pos || hunk.start > lines.len() {
+            return Err(format!("hunk at line {} is out of place", hunk.start + 1));
+        }
+
+        patched.extend(lines[pos..hunk.start].iter().copied());
+        pos = hunk.start;
+        for line in &hunk.lines {
+            let (op, text) = line.split_at(1);
+            if op == "+" {
+                patched.push_str(text);
+                continue;
+            }
+
+            if lines.get(pos) != Some(&text) {
+                return Err(format!("line {} doesn't match", pos + 1));
+            }
+            if op == " " {
+                patched.push_str(text);
+            }
+            pos += 1;
+        }
+    }
+
+    patched.extend(lines[pos..].iter().copied());
+    Ok(patched)
+}
+
+/// Read a list of `path=value` pairs, where each `path` must be absolute.
+fn get_pairs(settings: &Settings, env_key: &str) -> Vec<(PathBuf, String)> {
+    settings
+        .get_list(env_key)
+        .iter()
+        .filter_map(|pair| {
+            let pair = pair.to_string_lossy();
+            match pair.split_once('=') {
+                Some((path, value)) if Path::new(path).is_absolute() => {
+                    Some((PathBuf::from(path), value.to_string()))
+                }
+                _ => {
+                    log!(LOG_ERROR, "{}: invalid {}: {}", hook_tag(), env_key, pair);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Read the writable layer over the fake root.
+fn get_cow_dir(settings: &Settings) -> Option<PathBuf> {
+    let cow_dir = PathBuf::from(settings.get(ENV_FAKEROOT_COW_DIR)?);
+    if !cow_dir.is_absolute() {
+        log!(
+            LOG_ERROR,
+            "{}: {} is not absolute",
+            hook_tag(),
+            ENV_FAKEROOT_COW_DIR
+        );
+        return None;
+    }
+
+    Some(cow_dir)
+}
+
+/// Read the hooks which should also hook directories.
+fn get_dirs_ops(settings: &Settings) -> Vec<String> {
+    let ops = match settings.get(ENV_FAKEROOT_DIRS_OPS) {
+        Some(ops) => ops,
+        None => return Vec::new(),
+    };
+
+    ops.split(',')
+        .map(str::trim)
+        .filter(|op| !op.is_empty())
+        .filter(|op| {
+            let known = available_hooks().contains(op);
+            if !known {
+                log!(
+                    LOG_ERROR,
+                    "{}: unknown hook in {}: {}",
+                    hook_tag(),
+                    ENV_FAKEROOT_DIRS_OPS,
+                    op
+                );
+            }
+            known
+        })
+        .map(str::to_string)
+        .collect()
+}
+
+/// Read where the fake root is mounted.
+fn get_mount_at(settings: &Settings) -> Option<PathBuf> {
+    let mount_at = PathBuf::from(settings.get(ENV_FAKEROOT_MOUNT_AT)?);
+    if !mount_at.is_absolute() {
+        log!(
+            LOG_ERROR,
+            "{}: {} is not absolute",
+            hook_tag(),
+            ENV_FAKEROOT_MOUNT_AT
+        );
+        return None;
+    }
+
+    Some(normalize(&mount_at))
+}
+
+/// Read the time for faked files to report.
+fn get_fake_time(settings: &Settings) -> Option<i64> {
+    let time = settings.get(ENV_FAKEROOT_FAKE_TIME)?;
+    match time.parse() {
+        Ok(time) => Some(time),
+        Err(e) => {
+            log!(
+                LOG_ERROR,
+                "{}: invalid {}: {}",
+                hook_tag(),
+                ENV_FAKEROOT_FAKE_TIME,
+                e
+            );
+            None
+        }
+    }
+}
+
+/// Take a snapshot of the current directory, to resolve relative paths against.
+fn get_inherit_cwd(settings: &Settings) -> Option<PathBuf> {
+    if !settings.is_enabled(ENV_FAKEROOT_INHERIT_CWD) {
+        return None;
+    }
+
+    match env::current_dir() {
+        Ok(cwd) => Some(cwd),
+        Err(e) => {
+            log!(
+                LOG_ERROR,
+                "{}: failed to read current directory for {}: {}",
+                hook_tag(),
+                ENV_FAKEROOT_INHERIT_CWD,
+                e
+            );
+            None
+        }
+    }
+}
+
+/// Read the number of bytes the fake root can hold.
+fn get_quota(settings: &Settings) -> Option<u64> {
+    let quota = settings.get(ENV_FAKEROOT_QUOTA)?;
+    match quota.parse() {
+        Ok(quota) => Some(quota),
+        Err(e) => {
+            log!(
+                LOG_ERROR,
+                "{}: invalid {}: {}",
+                hook_tag(),
+                ENV_FAKEROOT_QUOTA,
+                e
+            );
+            None
+        }
+    }
+}
+
+/// Read the user or group ID in the setting `name`.
+fn get_id(settings: &Settings, name: &str) -> Option<u32> {
+    let id = settings.get(name)?;
+    match id.parse() {
+        Ok(id) => Some(id),
+        Err(e) => {
+            log!(LOG_ERROR, "{}: invalid {}: {}", hook_tag(), name, e);
+            None
+        }
+    }
+}
+
+/// Read the octal umask for directories created in the fake root.
+fn get_umask(settings: &Settings) -> Option<u32> {
+    get_octal(settings, ENV_FAKEROOT_UMASK).map(|umask| umask & 0o777)
+}
+
+/// Read the octal number in the setting `name`, such as a mode.
+fn get_octal(settings: &Settings, name: &str) -> Option<u32> {
+    let value = settings.get(name)?;
+    match u32::from_str_radix(&value, 8) {
+        Ok(value) => Some(value),
+        Err(e) => {
+            log!(LOG_ERROR, "{}: invalid {}: {}", hook_tag(), name, e);
+            None
+        }
+    }
+}
+
+/// Ensure the fake root directory is usable.
+fn check_fake_root(path: PathBuf) -> Result<PathBuf, String> {
+    // this is the only time the root is looked at on disk, so it should only be logged once
+    log!(LOG_REDIRECT, "{}: checking {}", hook_tag(), path.display());
+    if path.is_absolute() {
+        if path.exists() {
+            Ok(path)
+        } else {
+            Err(format!("{} does not exist on disk", ENV_FAKEROOT))
+        }
+    } else {
+        Err(format!("{} is not absolute", ENV_FAKEROOT))
+    }
+}
+
+/// Lexically resolve `.` and `..` components of an absolute path.
+fn normalize(path: &Path) -> PathBuf {
+    // the result is never longer than the path, so this only allocates once
+    let mut normalized = PathBuf::with_capacity(path.as_os_str().len().max(1));
+    normalized.push("/");
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+
+    normalized
+}
+
+/// The path within the fake root of `logical_path`, which is relative to `ENV_FAKEROOT_MOUNT_AT`, or
+/// `None` if it isn't under it.
+fn mounted_path<'a>(options: &Options, logical_path: &'a Path) -> Option<&'a Path> {
+    let mount_at = options.mount_at.as_deref().unwrap_or(Path::new("/"));
+    logical_path.strip_prefix(mount_at).ok()
+}
+
+/// The logical path of `relative`, a path within the fake root.
+fn unmounted_path(options: &Options, relative: &Path) -> PathBuf {
+    let mount_at = options.mount_at.as_deref().unwrap_or(Path::new("/"));
+    mount_at.join(relative)
+}
+
+/// Check whether resolving `path` (which must be inside `root`) would follow a symlink out of it.
+fn escapes_root(root: &Path, path: &Path) -> bool {
+    let mut resolved = root.to_path_buf();
+    let mut remaining = path.strip_prefix(root).unwrap_or(path).to_path_buf();
+
+    // limit the number of links followed, like the kernel does
+    for _ in 0..40 {
+        let mut followed = false;
+        let components = remaining.clone();
+        for (i, component) in components.components().enumerate() {
+            resolved = normalize(&resolved.join(component));
+            if !resolved.starts_with(root) {
+                return true;
+            }
+
+            if let Ok(target) = fs::read_link(&resolved) {
+                // continue resolving from the link's target, with whatever was left of the path
+                resolved.pop();
+                let target = normalize(&resolved.join(target));
+                if !target.starts_with(root) {
+                    return true;
+                }
+
+                remaining = target
+                    .strip_prefix(root)
+                    .unwrap()
+                    .join(components.components().skip(i + 1).collect::<PathBuf>());
+                resolved = root.to_path_buf();
+                followed = true;
+                break;
+            }
+        }
+
+        if !followed {
+            return false;
+        }
+    }
+
+    true
+}
+
+/// Return the logical current directory, which is the real one unless `chdir` was redirected.
+/// A real one inside the fake root (such as one inherited from a parent process which changed into
+/// a faked directory) is mapped back out of it.
+fn get_cwd(options: &Options) -> Option<PathBuf> {
+    let cwd = FAKEROOT_CWD.lock().ok().and_then(|cwd| cwd.clone());
+    cwd.or_else(|| {
+        let cwd = env::current_dir().ok()?;
+        let root = options.root.as_ref().ok();
+        match root.and_then(|root| cwd.strip_prefix(root).ok()) {
+            Some(relative) => Some(unmounted_path(options, relative)),
+            None => Some(cwd),
+        }
+    })
+}
+
+/// Check whether relative paths would really be resolved inside the fake root.
+fn is_cwd_in_root(fake_root: &Path) -> bool {
+    FAKEROOT_CWD.lock().is_ok_and(|cwd| cwd.is_some())
+        || env::current_dir().is_ok_and(|cwd| cwd.starts_with(fake_root))
+}
+
+/// Return the absolute path a program meant by the given string, with any `..` clamped at `/` so
+/// that it can't climb out of the fake root once joined to it.
+/// Relative paths are only resolved if `ENV_FAKEROOT_INHERIT_CWD` or `ENV_FAKEROOT_RELATIVE` is
+/// enabled.
+fn get_logical_path(options: &Options, path_str: &str) -> Option<PathBuf> {
+    let path = Path::new(path_str);
+    if path.is_absolute() {
+        Some(normalize(path))
+    } else if let Some(cwd) = &options.inherit_cwd {
+        Some(normalize(&cwd.join(path)))
+    } else if options.relative {
+        get_cwd(options).map(|cwd| normalize(&cwd.join(path)))
+    } else {
+        None
+    }
+}
+
+/// Return a `CString` if a file exists in the fake root for the given string.
+/// Returns `None` if the path should pass through to the real filesystem.
+fn get_fake_path(c_str: &CStr) -> Result<Option<CString>, Box<dyn Error>> {
+    // looking in the fake root uses the `stat` family, which mustn't be hooked while doing so
+    bypass(|| find_fake_path(options(), c_str, false))
+}
+
+/// If `nofollow`, the call won't follow the path if it's a symlink (such as `lstat`), so it may lead
+/// anywhere even if `ENV_FAKEROOT_FOLLOW` is disabled.
+fn find_fake_path(
+    options: &Options,
+    c_str: &CStr,
+    nofollow: bool,
+) -> Result<Option<CString>, Box<dyn Error>> {
+    // parse c string
+    let path_str = match str::from_utf8(c_str.to_bytes()) {
+        Ok(actual_path) => actual_path,
+        Err(e) => {
+            return Err(format!("failed to read string: {}", e).into());
+        }
+    };
+
+    // relative paths can't be faked unless we know what they're relative to
+    let logical_path = match get_logical_path(options, path_str) {
+        Some(path) => path,
+        None if options.strict_absolute => {
+            log!(
+                LOG_ERROR,
+                "{}: relative path not faked (enable {} to fake it): {}",
+                hook_tag(),
+                ENV_FAKEROOT_RELATIVE,
+                path_str
+            );
+            return Ok(None);
+        }
+        None => {
+            log!(
+                LOG_PASSTHROUGH,
+                "{}: relative path: {}",
+                hook_tag(),
+                path_str
+            );
+            return Ok(None);
+        }
+    };
+
+    // aliases are faked as the path they're an alias of
+    let logical_path = match options
+        .aliases
+        .iter()
+        .find(|(alias, _)| *alias == logical_path)
+    {
+        Some((_, path)) => path.clone(),
+        None => logical_path,
+    };
+
+    // binds replace exactly one path, and don't need the fake root at all
+    if let Some((_, real)) = options.binds.iter().find(|(virt, _)| *virt == logical_path) {
+        return Ok(Some(CString::new(real.as_os_str().as_bytes())?));
+    }
+
+    // virtual filesystems and the standard streams are never faked, unless explicitly allowed
+    if let Some(prefix) = NEVER_FAKE.iter().find(|p| logical_path.starts_with(p)) {
+        if !(options.proc && *prefix == "/proc") {
+            log!(LOG_PASSTHROUGH, "{}: never faked: {}", hook_tag(), path_str);
+            return Ok(None);
+        }
+    }
+
+    // only paths matching `ENV_FAKEROOT_MATCH_REGEX` are looked for
+    if let Some(regex) = &options.match_regex {
+        if !regex.is_match(&logical_path.to_string_lossy()) {
+            log!(LOG_PASSTHROUGH, "{}: not matched: {}", hook_tag(), path_str);
+            return Ok(None);
+        }
+    }
+
+    // only paths on `ENV_FAKEROOT_MATCH_DEVICE` are looked for, and since paths which are about to
+    // be created don't have a device yet, their nearest existing parent's is used
+    if let Some(device) = options.match_device {
+        let metadata = logical_path.ancestors().find_map(|p| fs::metadata(p).ok());
+        if metadata.is_some_and(|m| m.dev() != device) {
+            log!(
+                LOG_PASSTHROUGH,
+                "{}: other device: {}",
+                hook_tag(),
+                path_str
+            );
+            return Ok(None);
+        }
+    }
+
+    // get fake root
+    // a misconfigured root won't change, so it's only reported once rather than for every call
+    let fake_root = match &options.root {
+        Ok(path) => path.as_path(),
+        Err(e) if options.on_error == OnError::Fail => {
+            return Err(e.as_str().into());
+        }
+        Err(e) => {
+            FAKEROOT_ROOT_ERROR.call_once(|| log!(LOG_ERROR, "{}: {}", hook_tag(), e));
+            return Ok(None);
+        }
+    };
+
+    // paths which are already in the fake root have been resolved, and joining them to it again
+    // would only nest them
+    let in_cow_dir = options
+        .cow_dir
+        .as_ref()
+        .is_some_and(|cow_dir| logical_path.starts_with(cow_dir));
+    if logical_path.starts_with(fake_root) || in_cow_dir {
+        log!(
+            LOG_PASSTHROUGH,
+            "{}: already in fake root: {}",
+            hook_tag(),
+            path_str
+        );
+        return Ok(None);
+    }
+
+    // only paths under `ENV_FAKEROOT_MOUNT_AT` are in the fake root
+    let relative = match mounted_path(options, &logical_path) {
+        Some(relative) => relative,
+        None => {
+            log!(
+                LOG_PASSTHROUGH,
+                "{}: outside mount point: {}",
+                hook_tag(),
+                path_str
+            );
+            return Ok(None);
+        }
+    };
+
+    // make path relative to our fake root, building it in a reused buffer since most paths aren't
+    // faked and it's only copied if it's redirected to
+    let mut buf = PathBuffer::take();
+    buf.0.extend_from_slice(fake_root.as_os_str().as_bytes());
+    if buf.0.last() == Some(&b'/') {
+        buf.0.pop();
+    }
+    buf.0.push(b'/');
+    buf.0.extend_from_slice(relative.as_os_str().as_bytes());
+    let fake_path = buf.as_path();
+
+    // whited out paths (or those in whited out directories) are deleted, even if the real one exists
+    if options.whiteout {
+        let mut ancestor = fake_root.to_path_buf();
+        for component in fake_path
+            .strip_prefix(fake_root)
+            .unwrap_or(fake_path)
+            .components()
+        {
+            ancestor.push(component);
+            if is_whiteout(&ancestor) {
+                return Err(Box::new(Denied {
+                    errno: libc::ENOENT,
+                    reason: format!("whited out: {}", path_str),
+                }));
+            }
+        }
+    }
+
+    // refuse to follow symlinks back out of the fake root
+    let followed = match fake_path.parent() {
+        Some(parent) if nofollow => parent,
+        _ => fake_path,
+    };
+    if !options.follow && escapes_root(fake_root, followed) {
+        return Err(Box::new(Denied {
+            errno: libc::EACCES,
+            reason: format!("symlink leads out of fake root: {}", path_str),
+        }));
+    }
+
+    // the current directory may be in the fake root, so relative paths need to be made absolute in
+    // order to reach the real file
+    let passthrough = || {
+        if Path::new(path_str).is_relative() && is_cwd_in_root(fake_root) {
+            return Ok(Some(
+                CString::new(logical_path.as_os_str().as_bytes()).unwrap(),
+            ));
+        }
+
+        Ok(None)
+    };
+
+    // bail out if the real file exists and `ENV_FAKEROOT_ORDER` prefers it
+    if options.order == Order::RealFirst && logical_path.exists() {
+        log!(
+            LOG_PASSTHROUGH,
+            "{}: in real filesystem: {}",
+            hook_tag(),
+            path_str
+        );
+        return passthrough();
+    }
+
+    // the writable layer shadows the fake root, and is where new files are created
+    let cow_path = options.cow_dir.as_ref().and_then(|cow_dir| {
+        let cow_path = cow_dir.join(fake_path.strip_prefix(fake_root).unwrap_or(fake_path));
+        (cow_path.symlink_metadata().is_ok() || fake_path.symlink_metadata().is_err())
+            .then_some(cow_path)
+    });
+    let fake_path = cow_path.as_deref().unwrap_or(fake_path);
+
+    // bail out if the file doesn't exist and `ENV_FAKEROOT_ALL` isn't enabled
+    // symlinks aren't followed here, since a dangling one is still a fake file (and calls such as
+    // `lstat` or `open` with `O_NOFOLLOW` look at the link itself)
+    if !options.all && fake_path.symlink_metadata().is_err() {
+        log!(
+            LOG_PASSTHROUGH,
+            "{}: not in fake root: {}",
+            hook_tag(),
+            path_str
+        );
+        return passthrough();
+    }
+
+    // we found a fake file, return a string representing its path
+    log!(
+        LOG_REDIRECT,
+        "{}: {} => {}",
+        hook_tag(),
+        path_str,
+        fake_path.display()
+    );
+    Ok(Some(
+        CString::new(fake_path.as_os_str().as_bytes()).unwrap(),
+    ))
+}
+
+/// Whether `fake_path` has been whited out, either by a `.wh.<name>` file next to it or by being a
+/// character device with device number `0/0`, as overlayfs does it.
+fn is_whiteout(fake_path: &Path) -> bool {
+    let (Some(parent), Some(name)) = (fake_path.parent(), fake_path.file_name()) else {
+        return false;
+    };
+
+    let mut marker = OsString::from(WHITEOUT_PREFIX);
+    marker.push(name);
+    parent.join(marker).symlink_metadata().is_ok()
+        || fake_path
+            .symlink_metadata()
+            .is_ok_and(|m| m.file_type().is_char_device() && m.rdev() == 0)
+}
+
+/// Remember a directory stream opened by `opendir`, so `readdir` lists it in name order if
+/// `ENV_FAKEROOT_DETERMINISTIC_DIR_ORDER` is enabled.
+fn track_sorted_dir(dir: *mut DIR) {
+    if bypassed() || dir.is_null() || !options().sorted_dirs {
+        return;
+    }
+
+    if let Ok(mut dirs) = SORTED_DIRS.lock() {
+        dirs.insert(dir as usize, SortedDir::default());
+    }
+    #[cfg(target_env = "gnu")]
+    if let Ok(mut dirs) = SORTED_DIRS64.lock() {
+        dirs.insert(dir as usize, SortedDir::default());
+    }
+}
+
+/// The entries of a directory stream, which are all read (and sorted) on the first `readdir`.
+struct SortedDir<T> {
+    entries: Option<Vec<T>>,
+    next: usize,
+}
+
+impl<T> Default for SortedDir<T> {
+    fn default() -> Self {
+        SortedDir {
+            entries: None,
+            next: 0,
+        }
+    }
+}
+
+/// The `dirent` structures returned by the `readdir` family.
+trait DirEntry: Copy {
+    fn name(&self) -> &CStr;
+}
+
+impl DirEntry for libc::dirent {
+    fn name(&self) -> &CStr {
+        unsafe { CStr::from_ptr(self.d_name.as_ptr()) }
+    }
+}
+
+#[cfg(target_env = "gnu")]
+impl DirEntry for libc::dirent64 {
+    fn name(&self) -> &CStr {
+        unsafe { CStr::from_ptr(self.d_name.as_ptr()) }
+    }
+}
+
+/// Read the next entry of the directory stream `dirp` with `real`, leaving out any which are hidden
+/// by whiteouts.
+unsafe fn read_entry<T: DirEntry>(
+    dirp: *mut DIR,
+    real: unsafe extern "C" fn(*mut DIR) -> *mut T,
+) -> *mut T {
+    let fake_dir = match WHITEOUT_DIRS
+        .lock()
+        .ok()
+        .and_then(|dirs| dirs.get(&(dirp as usize)).cloned())
+    {
+        Some(fake_dir) => fake_dir,
+        None => return real(dirp),
+    };
+
+    loop {
+        let entry = real(dirp);
+        if entry.is_null() || !is_hidden_entry(&fake_dir, (*entry).name()) {
+            return entry;
+        }
+    }
+}
+
+/// Read the next entry of the directory stream `dirp` like `read_entry`, in name order if it's
+/// tracked in `sorted`. The entries returned stay valid until the stream is closed.
+unsafe fn read_sorted<T: DirEntry>(
+    sorted: &Mutex<BTreeMap<usize, SortedDir<T>>>,
+    dirp: *mut DIR,
+    real: unsafe extern "C" fn(*mut DIR) -> *mut T,
+) -> *mut T {
+    let mut dirs = match sorted.lock() {
+        Ok(dirs) => dirs,
+        Err(_) => return read_entry(dirp, real),
+    };
+    let dir = match dirs.get_mut(&(dirp as usize)) {
+        Some(dir) => dir,
+        None => {
+            drop(dirs);
+            return read_entry(dirp, real);
+        }
+    };
+
+    let entries = dir.entries.get_or_insert_with(|| {
+        let mut entries = Vec::new();
+        loop {
+            let entry = read_entry(dirp, real);
+            if entry.is_null() {
+                break;
+            }
+            entries.push(*entry);
+        }
+
+        entries.sort_by(|a, b| a.name().cmp(b.name()));
+        entries
+    });
+
+    // the end of the directory is a null entry, which leaves `errno` alone
+    match entries.get_mut(dir.next) {
+        Some(entry) => {
+            dir.next += 1;
+            entry
+        }
+        None => ptr::null_mut(),
+    }
+}
+
+/// Whether the directory entry `name` should be left out of listings of the directory whose
+/// whiteouts are in `fake_dir`. The whiteout markers themselves are always left out.
+fn is_hidden_entry(fake_dir: &Path, name: &CStr) -> bool {
+    let name = OsStr::from_bytes(name.to_bytes());
+    name.as_bytes().starts_with(WHITEOUT_PREFIX.as_bytes())
+        || bypass(|| is_whiteout(&fake_dir.join(name)))
+}
+
+/// Remember where the whiteouts for the directory stream `dir` opened from `path` would be, so that
+/// entries can be hidden from `readdir`.
+unsafe fn track_whiteout_dir(dir: *mut DIR, path: *const c_char) {
+    if bypassed() || !options().whiteout || dir.is_null() || path.is_null() {
+        return;
+    }
+
+    let logical_path = match CStr::from_ptr(path)
+        .to_str()
+        .map(|path| get_logical_path(options(), path))
+    {
+        Ok(Some(path)) => path,
+        _ => return,
+    };
+    let relative = match mounted_path(options(), &logical_path) {
+        Some(relative) => relative,
+        None => return,
+    };
+    if let (Ok(root), Ok(mut dirs)) = (&options().root, WHITEOUT_DIRS.lock()) {
+        dirs.insert(dir as usize, root.join(relative));
+    }
+}
+
+/// Remove hidden entries from a buffer filled by `getdents64`, returning its new length.
+#[cfg(target_os = "linux")]
+unsafe fn filter_dirents(fake_dir: &Path, dirp: *mut c_void, len: usize) -> usize {
+    let buf = dirp.cast::<u8>();
+    let (mut read, mut write) = (0, 0);
+    while read < len {
+        let entry = buf.add(read).cast::<libc::dirent64>();
+        let reclen = (*entry).d_reclen as usize;
+        if !is_hidden_entry(fake_dir, CStr::from_ptr((*entry).d_name.as_ptr())) {
+            ptr::copy(buf.add(read), buf.add(write), reclen);
+            write += reclen;
+        }
+        read += reclen;
+    }
+
+    write
+}
+
+/// Copy a real file into the fake root before it's opened for writing, so that the real file isn't
+/// modified. Only done if `ENV_FAKEROOT_COW` is enabled and the file isn't already in the fake root.
+/// With `ENV_FAKEROOT_COW_DIR` the file (from the fake root if it's there) is copied there instead.
+/// With `ENV_FAKEROOT_COPY_ON_OPEN` it's copied when it's opened at all.
+unsafe fn copy_on_write(path: *const c_char, flags: c_int) {
+    if bypassed() || flags == -1 {
+        return;
+    }
+
+    let copy_on_open = options().copy_on_open;
+    if flags & libc::O_ACCMODE == libc::O_RDONLY && !copy_on_open {
+        return;
+    }
+
+    if !options().cow && options().cow_dir.is_none() && !copy_on_open {
+        return;
+    }
+
+    let logical_path = match CStr::from_ptr(path)
+        .to_str()
+        .map(|path| get_logical_path(options(), path))
+    {
+        Ok(Some(path)) => path,
+        _ => return,
+    };
+    let relative = match mounted_path(options(), &logical_path) {
+        Some(relative) => relative,
+        None => return,
+    };
+    let fake_path = match &options().root {
+        Ok(root) => root.join(relative),
+        Err(_) => return,
+    };
+
+    // the contents only need copying if they'll be kept (such as when appending), not when the
+    // file is about to be truncated
+    let contents = flags & libc::O_TRUNC == 0;
+    let options = options();
+    bypass(|| match &options.cow_dir {
+        Some(cow_dir) if fake_path.is_file() => {
+            copy_into_root(options, &fake_path, &cow_dir.join(relative), contents)
+        }
+        Some(cow_dir) => copy_into_root(options, &logical_path, &cow_dir.join(relative), contents),
+        None => copy_into_root(options, &logical_path, &fake_path, contents),
+    })
+}
+
+/// The fake file a call with `flags` is about to create at `path`, if `ENV_FAKEROOT_TOUCH_LOG`,
+/// `ENV_FAKEROOT_FORCE_MODE` or `ENV_FAKEROOT_STRIP_SUID` is set (so it can be recorded or have its
+/// mode set once it has been).
+unsafe fn creating(path: *const c_char, flags: c_int) -> Option<PathBuf> {
+    if bypassed() || flags == -1 || flags & libc::O_CREAT == 0 {
+        return None;
+    }
+
+    let options = options();
+    if options.touch_log.is_none() && options.force_mode.is_none() && !options.strip_suid {
+        return None;
+    }
+    match get_fake_path(CStr::from_ptr(path)) {
+        Ok(Some(c_str)) => {
+            let fake_path = PathBuf::from(OsStr::from_bytes(c_str.to_bytes()));
+            let exists = bypass(|| fake_path.symlink_metadata().is_ok());
+            (!exists).then_some(fake_path)
+        }
+        _ => None,
+    }
+}
+
+/// The mode a call creating `new_file` (from `creating`) should pass on in place of `mode`, which
+/// `ENV_FAKEROOT_FORCE_MODE` overrides and `ENV_FAKEROOT_STRIP_SUID` strips.
+fn forced_mode(new_file: &Option<PathBuf>, mode: libc::mode_t) -> libc::mode_t {
+    match new_file {
+        Some(_) => stripped_mode(options().force_mode.unwrap_or(mode)),
+        None => mode,
+    }
+}
+
+/// `mode` without its setuid and setgid bits, if `ENV_FAKEROOT_STRIP_SUID` is set.
+fn stripped_mode(mode: libc::mode_t) -> libc::mode_t {
+    if options().strip_suid {
+        mode & !(libc::S_ISUID | libc::S_ISGID)
+    } else {
+        mode
+    }
+}
+
+/// Record the `new_file` from `creating` in the touch log and give it the mode from
+/// `ENV_FAKEROOT_FORCE_MODE` (since the mode it was created with was still masked by the umask), if
+/// the call creating it succeeded.
+fn created<T: Failure + PartialEq>(new_file: Option<PathBuf>, ret: T) -> T {
+    if let Some(fake_path) = new_file.filter(|_| ret != T::FAILURE) {
+        bypass(|| {
+            if let Some(mode) = options().force_mode.map(stripped_mode) {
+                if let Err(e) =
+                    fs::set_permissions(&fake_path, fs::Permissions::from_mode(mode as _))
+                {
+                    log!(
+                        LOG_ERROR,
+                        "{}: failed to set the mode of {}: {}",
+                        hook_tag(),
+                        fake_path.display(),
+                        e
+                    );
+                }
+            }
+            touch(options(), &fake_path)
+        });
+    }
+
+    ret
+}
+
+/// Append the logical path of `fake_path`, which was just created in the fake root, to the
+/// `ENV_FAKEROOT_TOUCH_LOG` file. This must be called while the hooks are bypassed, and is given the
+/// `options` since it's also called while they're first being read (when seeding the fake root).
+fn touch(options: &Options, fake_path: &Path) {
+    let touch_log = match &options.touch_log {
+        Some(touch_log) => touch_log,
+        None => return,
+    };
+
+    let roots = [options.cow_dir.as_ref(), options.root.as_ref().ok()];
+    let logical_path = match roots
+        .into_iter()
+        .flatten()
+        .find_map(|root| fake_path.strip_prefix(root).ok())
+    {
+        Some(relative) => unmounted_path(options, relative),
+        None => return,
+    };
+
+    if !FAKEROOT_TOUCHED
+        .lock()
+        .is_ok_and(|mut touched| touched.insert(logical_path.clone()))
+    {
+        return;
+    }
+
+    // each line is appended with a single write, so a crash can't leave half of one behind
+    let line = [logical_path.as_os_str().as_bytes(), b"\n"].concat();
+    let written = File::options()
+        .append(true)
+        .create(true)
+        .open(touch_log)
+        .and_then(|mut file| file.write_all(&line));
+    if let Err(e) = written {
+        log!(
+            LOG_ERROR,
+            "{}: failed to write {}: {}",
+            hook_tag(),
+            ENV_FAKEROOT_TOUCH_LOG,
+            e
+        );
+    }
+}
+
+/// Create the missing parent directories in the fake root of a file that's about to be created
+/// there, so that (as on the real filesystem) creating it only fails if its parents are missing
+/// there too, and `O_EXCL` is checked against the fake file rather than failing with `ENOENT`. Only
+/// done if `ENV_FAKEROOT_ALL` and `ENV_FAKEROOT_CREATE_PARENTS` are enabled.
+unsafe fn create_parents(path: *const c_char, flags: c_int) {
+    if bypassed()
+        || !options().all
+        || !options().create_parents
+        || flags == -1
+        || flags & libc::O_CREAT == 0
+    {
+        return;
+    }
+
+    let fake_path = match get_fake_path(CStr::from_ptr(path)) {
+        Ok(Some(c_str)) => PathBuf::from(OsStr::from_bytes(c_str.to_bytes())),
+        _ => return,
+    };
+    if let Some(parent) = fake_path.parent() {
+        create_dirs(parent);
+    }
+}
+
+/// Create the directory `path` for `opendir` in the fake root if it's missing there, so it can be
+/// listed (empty) just as a missing file could be created. Only done if `ENV_FAKEROOT_ALL` and
+/// `ENV_FAKEROOT_DIRS` are enabled and `ENV_FAKEROOT_ON_MISSING_DIR` is `create`.
+unsafe fn create_missing_dir(path: *const c_char) {
+    if bypassed()
+        || path.is_null()
+        || !options().all
+        || !hooks_dirs(options())
+        || !options().create_dirs
+    {
+        return;
+    }
+
+    if let Ok(Some(c_str)) = get_fake_path(CStr::from_ptr(path)) {
+        create_dirs(Path::new(OsStr::from_bytes(c_str.to_bytes())));
+    }
+}
+
+/// Create the directory `fake_dir` and any of its missing parents, up to the fake root (or
+/// `ENV_FAKEROOT_COW_DIR`) it's in.
+fn create_dirs(fake_dir: &Path) {
+    let roots = [options().cow_dir.as_ref(), options().root.as_ref().ok()];
+    let root = match roots
+        .into_iter()
+        .flatten()
+        .find(|root| fake_dir.starts_with(root))
+    {
+        Some(root) => root,
+        None => return,
+    };
+
+    bypass(|| {
+        let missing = fake_dir
+            .ancestors()
+            .take_while(|dir| *dir != root && !dir.exists())
+            .collect::<Vec<_>>();
+
+        for dir in missing.into_iter().rev() {
+            match create_dir(dir) {
+                Ok(()) => {
+                    log!(LOG_REDIRECT, "{}: created {}", hook_tag(), dir.display());
+                    touch(options(), dir);
+                }
+                Err(e) => {
+                    log!(
+                        LOG_ERROR,
+                        "{}: failed to create {}: {}",
+                        hook_tag(),
+                        dir.display(),
+                        e
+                    );
+                    return;
+                }
+            }
+        }
+    })
+}
+
+/// Create a directory in the fake root, with its permissions set by `ENV_FAKEROOT_UMASK` if it's
+/// set rather than the process' umask.
+fn create_dir(dir: &Path) -> io::Result<()> {
+    fs::create_dir(dir)?;
+    match options().umask {
+        Some(umask) => fs::set_permissions(dir, fs::Permissions::from_mode(0o777 & !umask)),
+        None => Ok(()),
+    }
+}
+
+/// Copy the real files listed in `ENV_FAKEROOT_SEED` into the fake root, so they're isolated from
+/// the start rather than only once they're written to.
+fn seed(options: &Options) {
+    let root = match &options.root {
+        Ok(root) => root,
+        Err(_) => return,
+    };
+
+    bypass(|| {
+        for path in &options.seed {
+            if !path.is_absolute() {
+                log!(
+                    LOG_ERROR,
+                    "{}: seed path is not absolute: {}",
+                    hook_tag(),
+                    path.display()
+                );
+                continue;
+            }
+
+            if let Some(relative) = mounted_path(options, path) {
+                copy_into_root(options, path, &root.join(relative), true);
+            }
+        }
+    })
+}
+
+/// Copy the file at `src` (usually the real one) to `fake_path` (only its metadata, unless
+/// `contents`), unless it's already in the fake root. Each file is only copied once, however many
+/// threads want it at the same time. This must be called while the hooks are bypassed.
+fn copy_into_root(options: &Options, src: &Path, fake_path: &Path, contents: bool) {
+    let mut copying = COPYING.lock().unwrap_or_else(PoisonError::into_inner);
+    while copying.contains(fake_path) {
+        copying = COPIED.wait(copying).unwrap_or_else(PoisonError::into_inner);
+    }
+    if fake_path.symlink_metadata().is_ok() || !src.is_file() {
+        return;
+    }
+    copying.insert(fake_path.to_path_buf());
+    drop(copying);
+
+    copy_once(options, src, fake_path, contents);
+    COPYING
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .remove(fake_path);
+    COPIED.notify_all();
+}
+
+/// Copy `src` to `fake_path` for `copy_into_root`, once no other thread is copying it.
+fn copy_once(options: &Options, src: &Path, fake_path: &Path, contents: bool) {
+    match copy_file(src, fake_path, contents) {
+        Ok(()) => {
+            log!(
+                LOG_REDIRECT,
+                "{}: copied {} => {}",
+                hook_tag(),
+                src.display(),
+                fake_path.display()
+            );
+            touch(options, fake_path);
+        }
+        Err(e) => log!(
+            LOG_ERROR,
+            "{}: failed to copy {}: {}",
+            hook_tag(),
+            src.display(),
+            e
+        ),
+    }
+}
+
+/// Copy a file (creating its parent directories), preserving its permissions and its access and
+/// modification times so tools comparing them aren't confused. If not copying its `contents`, the
+/// copy is left empty. The copy is made next to `dst` and then moved into place, so other processes
+/// never see it half copied.
+fn copy_file(src: &Path, dst: &Path, contents: bool) -> io::Result<()> {
+    // read the times before copying, since reading the file may update them
+    let metadata = fs::metadata(src)?;
+    let times = FileTimes::new()
+        .set_accessed(metadata.accessed()?)
+        .set_modified(metadata.modified()?);
+
+    if let Some(parent) = dst.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut tmp_name = OsString::from(".");
+    tmp_name.push(dst.file_name().unwrap_or_default());
+    tmp_name.push(format!(".fakeroot-{}", unsafe { libc::getpid() }));
+    let tmp = dst.with_file_name(tmp_name);
+    let copied = (|| {
+        if contents {
+            fs::copy(src, &tmp)?;
+        } else {
+            File::create(&tmp)?;
+        }
+        fs::set_permissions(&tmp, metadata.permissions())?;
+        File::open(&tmp)?.set_times(times)?;
+        fs::rename(&tmp, dst)
+    })();
+    if copied.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+
+    copied
+}
+
+/// Open an in-memory file with the `ENV_FAKEROOT_INLINE` content for `path`, if it has some, or
+/// the output of its `ENV_FAKEROOT_GEN` command, or its content patched by `ENV_FAKEROOT_APPLY_DIFF`,
+/// or the decompressed content of `<path>.gz` with `ENV_FAKEROOT_GZIP`, or the
+/// `ENV_FAKEROOT_DEFAULT_CONTENT` if it's missing. Returns `None` if `path` should be opened as
+/// normal.
+#[cfg(target_os = "linux")]
+unsafe fn open_inline(path: *const c_char, flags: c_int) -> Option<c_int> {
+    if path.is_null() || bypassed() {
+        return None;
+    }
+
+    let options = options();
+    if options.inline.is_empty()
+        && options.gen.is_empty()
+        && options.apply_diff.is_empty()
+        && options.default_content.is_none()
+        && !options.gzip
+    {
+        return None;
+    }
+
+    let path_str = CStr::from_ptr(path).to_str().ok()?;
+    let logical_path = bypass(|| get_logical_path(options, path_str))?;
+    let content = match options.inline.iter().find(|(p, _)| *p == logical_path) {
+        Some((_, content)) => content.as_bytes().to_vec(),
+        None => generated_content(options, &logical_path, flags)
+            .or_else(|| patched_content(options, CStr::from_ptr(path), &logical_path, flags))
+            .or_else(|| gzip_content(options, CStr::from_ptr(path), flags))
+            .or_else(|| {
+                default_content(options, CStr::from_ptr(path), flags)
+                    .map(|content| content.as_bytes().to_vec())
+            })?,
+    };
+
+    let memfd_flags = if flags & libc::O_CLOEXEC != 0 {
+        libc::MFD_CLOEXEC
+    } else {
+        0
+    };
+    // the name is only for debugging, and the kernel rejects names longer than 249 bytes
+    let fd = libc::memfd_create(c"fakeroot".as_ptr(), memfd_flags);
+    if fd < 0 {
+        return Some(fd);
+    }
+
+    // a truncated file starts out empty, as it would have on disk
+    if flags & libc::O_TRUNC == 0 {
+        let mut file = File::from_raw_fd(fd);
+        let written = file.write_all(&content).and_then(|_| file.rewind());
+        let fd = file.into_raw_fd();
+        if let Err(e) = written {
+            libc::close(fd);
+            return Some(deny(e.raw_os_error().unwrap_or(libc::EIO)));
+        }
+    }
+
+    // like `O_CLOEXEC` above, the file status flags the real `open` would've set are kept
+    let status_flags = flags & (libc::O_APPEND | libc::O_NONBLOCK);
+    if status_flags != 0 {
+        libc::fcntl(fd, libc::F_SETFL, status_flags);
+    }
+
+    log!(
+        LOG_REDIRECT,
+        "{}: {} => inline content",
+        hook_tag(),
+        path_str
+    );
+    track_fd(fd, path);
+    Some(fd)
+}
+
+/// The `ENV_FAKEROOT_DEFAULT_CONTENT` to read `path` with, if it's a file which would be faked by
+/// `ENV_FAKEROOT_ALL` but hasn't been created.
+#[cfg(target_os = "linux")]
+fn default_content<'a>(options: &'a Options, path: &CStr, flags: c_int) -> Option<&'a String> {
+    let content = options.default_content.as_ref()?;
+    let special = flags & (libc::O_DIRECTORY | libc::O_PATH) != 0;
+    if !options.all || special || is_writing(Some(flags)) {
+        return None;
+    }
+
+    let fake_path = get_fake_path(path).ok()??;
+    let fake_path = Path::new(OsStr::from_bytes(fake_path.to_bytes()));
+    bypass(|| fake_path.symlink_metadata().is_err()).then_some(content)
+}
+
+/// The output of the `ENV_FAKEROOT_GEN` command for `logical_path`, if it's opened for reading and
+/// has one which succeeds. The command is run without `LD_PRELOAD`, so it sees the real filesystem
+/// and can't recurse into generating the file again.
+#[cfg(target_os = "linux")]
+fn generated_content(options: &Options, logical_path: &Path, flags: c_int) -> Option<Vec<u8>> {
+    let special = flags & (libc::O_DIRECTORY | libc::O_PATH) != 0;
+    if special || is_writing(Some(flags)) {
+        return None;
+    }
+
+    let (_, command) = options.gen.iter().find(|(p, _)| p == logical_path)?;
+    let output = bypass(|| {
+        process::Command::new("/bin/sh")
+            .arg("-c")
+            .arg(command)
+            .env_remove("LD_PRELOAD")
+            .stdin(process::Stdio::null())
+            .output()
+    });
+    match output {
+        Ok(output) if output.status.success() => Some(output.stdout),
+        Ok(output) => {
+            log!(
+                LOG_ERROR,
+                "{}: failed to generate {}: {}",
+                hook_tag(),
+                logical_path.display(),
+                output.status
+            );
+            None
+        }
+        Err(e) => {
+            log!(
+                LOG_ERROR,
+                "{}: failed to generate {}: {}",
+                hook_tag(),
+                logical_path.display(),
+                e
+            );
+            None
+        }
+    }
+}
+
+/// The content of `path` patched by `ENV_FAKEROOT_APPLY_DIFF`, if it's opened for reading and the
+/// diff patches it. It's read from the fake root if it would be faked, or the real filesystem if not.
+#[cfg(target_os = "linux")]
+fn patched_content(
+    options: &Options,
+    path: &CStr,
+    logical_path: &Path,
+    flags: c_int,
+) -> Option<Vec<u8>> {
+    let special = flags & (libc::O_DIRECTORY | libc::O_PATH) != 0;
+    if special || is_writing(Some(flags)) {
+        return None;
+    }
+
+    let (_, hunks) = options.apply_diff.iter().find(|(p, _)| p == logical_path)?;
+    let fake_path = get_fake_path(path).ok()?;
+    let base = Path::new(OsStr::from_bytes(
+        fake_path.as_deref().unwrap_or(path).to_bytes(),
+    ));
+    let patched = bypass(|| fs::read_to_string(base))
+        .map_err(|e| e.to_string())
+        .and_then(|content| apply_hunks(&content, hunks));
+    match patched {
+        Ok(patched) => Some(patched.into_bytes()),
+        Err(e) => {
+            log!(
+                LOG_ERROR,
+                "{}: failed to patch {}: {}",
+                hook_tag(),
+                base.display(),
+                e
+            );
+            None
+        }
+    }
+}
+
+/// The decompressed content of `<path>.gz` in the fake root with `ENV_FAKEROOT_GZIP`, if `path` is
+/// opened for reading and doesn't exist there itself.
+#[cfg(target_os = "linux")]
+fn gzip_content(options: &Options, path: &CStr, flags: c_int) -> Option<Vec<u8>> {
+    let special = flags & (libc::O_DIRECTORY | libc::O_PATH) != 0;
+    if !options.gzip || special || is_writing(Some(flags)) {
+        return None;
+    }
+
+    let exists = |fake_path: &CStr| {
+        let fake_path = Path::new(OsStr::from_bytes(fake_path.to_bytes()));
+        bypass(|| fake_path.symlink_metadata().is_ok())
+    };
+    if get_fake_path(path)
+        .ok()?
+        .is_some_and(|fake_path| exists(&fake_path))
+    {
+        return None;
+    }
+
+    let gz_path = CString::new([path.to_bytes(), b".gz"].concat()).ok()?;
+    let gz_path = get_fake_path(&gz_path).ok()??;
+    if !exists(&gz_path) {
+        return None;
+    }
+
+    let gz_path = Path::new(OsStr::from_bytes(gz_path.to_bytes()));
+    let mut content = Vec::new();
+    let read = bypass(|| {
+        File::open(gz_path).and_then(|file| GzDecoder::new(file).read_to_end(&mut content))
+    });
+    match read {
+        Ok(_) => Some(content),
+        Err(e) => {
+            log!(
+                LOG_ERROR,
+                "{}: failed to decompress {}: {}",
+                hook_tag(),
+                gz_path.display(),
+                e
+            );
+            None
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+unsafe fn open_inline(_path: *const c_char, _flags: c_int) -> Option<c_int> {
+    None
+}
+
+/// Open `/dev/null` in place of `path` if it's listed in `ENV_FAKEROOT_NULLDEV`, so reading it finds
+/// nothing and writes to it are thrown away. Returns `None` if `path` should be opened as normal.
+unsafe fn open_null(path: *const c_char, flags: c_int) -> Option<c_int> {
+    if path.is_null() || bypassed() {
+        return None;
+    }
+
+    let options = options();
+    if options.nulldev.is_empty() {
+        return None;
+    }
+
+    let path_str = CStr::from_ptr(path).to_str().ok()?;
+    let logical_path = bypass(|| get_logical_path(options, path_str))?;
+    if !options.nulldev.contains(&logical_path) {
+        return None;
+    }
+
+    // there's nothing to create or truncate, but the descriptor should otherwise be the same
+    let flags = flags & (libc::O_ACCMODE | libc::O_CLOEXEC | libc::O_APPEND | libc::O_NONBLOCK);
+    log!(LOG_REDIRECT, "{}: {} => /dev/null", hook_tag(), path_str);
+    Some(bypass(|| libc::open(c"/dev/null".as_ptr(), flags)))
+}
+
+/// The `open_how` argument of `openat2`.
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct OpenHow {
+    flags: u64,
+    mode: u64,
+    resolve: u64,
+}
+
+/// Open `path` if `ENV_FAKEROOT_CONFINE` is enabled, with `openat2` if it's redirected into the fake
+/// root so the kernel keeps symlinks (and `..`) in it from leading out of it, or otherwise with
+/// `real`. Returns `None` if `path` should be opened as normal.
+#[cfg(target_os = "linux")]
+unsafe fn open_confined(
+    hook: Hook,
+    path: *const c_char,
+    flags: c_int,
+    mode: c_int,
+    real: impl Fn(*const c_char) -> c_int,
+) -> Option<c_int> {
+    if path.is_null() || bypassed() || !options().confine {
+        return None;
+    }
+
+    match decide(hook, CStr::from_ptr(path), Some(flags)) {
+        Decision::Redirect(c_str) => {
+            let fd = open_in_root(options(), &c_str, flags, mode)
+                .unwrap_or_else(|| real(c_str.as_ptr()));
+            if vanished(&fd, CStr::from_ptr(path)) {
+                return Some(quiet(real(path), Some(flags)));
+            }
+            track_fd(fd, path);
+            Some(fd)
+        }
+        Decision::Passthrough => Some(quiet(real(path), Some(flags))),
+        Decision::Deny(errno) => Some(deny(errno)),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+unsafe fn open_confined(
+    _hook: Hook,
+    _path: *const c_char,
+    _flags: c_int,
+    _mode: c_int,
+    _real: impl Fn(*const c_char) -> c_int,
+) -> Option<c_int> {
+    None
+}
+
+/// Open `fake_path` relative to the fake root with `RESOLVE_IN_ROOT`. Returns `None` if it isn't in
+/// the fake root (such as when it's in `ENV_FAKEROOT_COW_DIR`), or the kernel doesn't have
+/// `openat2`, so it should be opened as normal.
+#[cfg(target_os = "linux")]
+unsafe fn open_in_root(
+    options: &Options,
+    fake_path: &CStr,
+    flags: c_int,
+    mode: c_int,
+) -> Option<c_int> {
+    if FAKEROOT_NO_OPENAT2.load(Ordering::Relaxed) {
+        return None;
+    }
+
+    let root = options.root.as_ref().ok()?;
+    let relative = Path::new(OsStr::from_bytes(fake_path.to_bytes()))
+        .strip_prefix(root)
+        .ok()?;
+    let relative = if relative.as_os_str().is_empty() {
+        c".".to_owned()
+    } else {
+        CString::new(relative.as_os_str().as_bytes()).ok()?
+    };
+    let root_fd = root_fd(root)?;
+
+    // the kernel refuses a mode for calls which can't create a file
+    let creates = flags & (libc::O_CREAT | libc::O_TMPFILE) != 0;
+    let how = OpenHow {
+        flags: flags as u64,
+        mode: if creates { mode as u64 & 0o7777 } else { 0 },
+        resolve: libc::RESOLVE_IN_ROOT,
+    };
+    let fd = libc::syscall(
+        libc::SYS_openat2,
+        root_fd,
+        relative.as_ptr(),
+        &how as *const OpenHow,
+        mem::size_of::<OpenHow>(),
+    ) as c_int;
+    if fd < 0 && *errno_location() == libc::ENOSYS {
+        log!(
+            LOG_ERROR,
+            "{}: openat2 isn't supported, so {} has no effect",
+            hook_tag(),
+            ENV_FAKEROOT_CONFINE
+        );
+        FAKEROOT_NO_OPENAT2.store(true, Ordering::Relaxed);
+        return None;
+    }
+
+    Some(fd)
+}
+
+/// The descriptor of the fake root for `open_in_root`. It's opened again if the program has since
+/// closed it or put another file in its place (as daemons closing every descriptor do), rather than
+/// opening files relative to whatever it is now.
+#[cfg(target_os = "linux")]
+unsafe fn root_fd(root: &Path) -> Option<c_int> {
+    let mut root_fd = FAKEROOT_ROOT_FD.lock().ok()?;
+    let mut buf = mem::zeroed::<libc::stat>();
+    if let Some((fd, dev, ino)) = *root_fd {
+        if bypass(|| libc::fstat(fd, &mut buf)) == 0 && buf.st_dev == dev && buf.st_ino == ino {
+            return Some(fd);
+        }
+        log!(
+            LOG_REDIRECT,
+            "{}: fake root descriptor {} was replaced, reopening",
+            hook_tag(),
+            fd
+        );
+    }
+
+    // the old descriptor isn't closed, since it's the program's now
+    *root_fd = None;
+    let root = CString::new(root.as_os_str().as_bytes()).ok()?;
+    let flags = libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC;
+    let fd = bypass(|| libc::open(root.as_ptr(), flags));
+    if fd < 0 {
+        return None;
+    }
+    if bypass(|| libc::fstat(fd, &mut buf)) != 0 {
+        libc::close(fd);
+        return None;
+    }
+
+    *root_fd = Some((fd, buf.st_dev, buf.st_ino));
+    Some(fd)
+}
+
+/// Remember a file descriptor that was opened in the fake root, so later calls which only receive
+/// the descriptor (such as `getdents64` or `fstat`) know that it's been faked.
+unsafe fn track_fd(fd: c_int, path: *const c_char) {
+    if fd < 0 {
+        return;
+    }
+
+    let path = CStr::from_ptr(path).to_string_lossy().into_owned();
+    if let Ok(mut fds) = FAKE_FDS.lock() {
+        fds.insert(fd, path);
+    }
+
+    if options().quota.is_some() {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags != -1 && flags & libc::O_ACCMODE != libc::O_RDONLY {
+            if let (Some(size), Ok(mut fds)) = (fd_size(fd), WRITTEN_FDS.lock()) {
+                fds.insert(fd, size);
+            }
+        }
+    }
+}
+
+/// The size of the file open as `fd`, as it is on disk.
+unsafe fn fd_size(fd: c_int) -> Option<u64> {
+    let mut buf = mem::zeroed::<libc::stat>();
+    (bypass(|| libc::fstat(fd, &mut buf)) == 0).then_some(buf.st_size as u64)
+}
+
+/// Is the fake root holding more than the `ENV_FAKEROOT_QUOTA`?
+fn over_quota(options: &Options) -> bool {
+    options
+        .quota
+        .is_some_and(|quota| usage(options).load(Ordering::Relaxed) > quota)
+}
+
+/// The bytes the fake root holds, measuring it the first time. This must be called while the hooks
+/// are bypassed.
+fn usage(options: &Options) -> &'static AtomicU64 {
+    FAKEROOT_USAGE.get_or_init(|| {
+        let roots = options.root.iter().chain(&options.cow_dir);
+        AtomicU64::new(roots.map(|root| dir_size(root)).sum())
+    })
+}
+
+/// The total size of the files in `dir`, and all of its subdirectories.
+fn dir_size(dir: &Path) -> u64 {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return 0;
+    };
+
+    entries
+        .flatten()
+        .map(|entry| match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => dir_size(&entry.path()),
+            _ => entry.metadata().map_or(0, |metadata| metadata.len()),
+        })
+        .sum()
+}
+
+/// Count what's been written to `fd` towards the `ENV_FAKEROOT_QUOTA`, as it's about to be closed.
+unsafe fn closing_written(fd: c_int) {
+    let Some(opened) = WRITTEN_FDS.lock().ok().and_then(|mut fds| fds.remove(&fd)) else {
+        return;
+    };
+
+    if let Some(size) = fd_size(fd) {
+        let usage = bypass(|| usage(options()));
+        if size >= opened {
+            usage.fetch_add(size - opened, Ordering::Relaxed);
+        } else {
+            let freed = opened - size;
+            let _ = usage.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |usage| {
+                Some(usage.saturating_sub(freed))
+            });
+        }
+    }
+}
+
+/// Remember a file descriptor that was opened relative to `dirfd`, if that was opened in the fake
+/// root. The kernel already resolves such paths within the faked directory, so they aren't
+/// redirected themselves.
+unsafe fn track_relative_fd(fd: c_int, dirfd: c_int, path: *const c_char) {
+    if fd < 0 || path.is_null() {
+        return;
+    }
+
+    if let Ok(mut fds) = FAKE_FDS.lock() {
+        if let Some(dir) = fds.get(&dirfd) {
+            let path = Path::new(dir).join(OsStr::from_bytes(CStr::from_ptr(path).to_bytes()));
+            fds.insert(fd, path.to_string_lossy().into_owned());
+        }
+    }
+}
+
+/// The kind of hook an `open` with `flags` is. `O_PATH` opens only hold a reference (as `find` does
+/// for directories), so they're treated like listing a directory.
+fn open_hook(flags: c_int) -> Hook {
+    if is_path_only(flags) {
+        Hook::Dir
+    } else {
+        Hook::File
+    }
+}
+
+/// Do `flags` open only a reference to a file, with `O_PATH`?
+#[cfg(target_os = "linux")]
+fn is_path_only(flags: c_int) -> bool {
+    flags & libc::O_PATH != 0
+}
+
+/// Do `flags` open only a reference to a file? Only Linux has `O_PATH`.
+#[cfg(not(target_os = "linux"))]
+fn is_path_only(_flags: c_int) -> bool {
+    false
+}
+
+/// The file flags taken by `chflags`, which differ in size between the BSDs.
+#[cfg(target_os = "freebsd")]
+type FileFlags = libc::c_ulong;
+#[cfg(target_os = "macos")]
+type FileFlags = u32;
+
+/// The `stat` structures whose device and inode numbers can be spoofed.
+trait Identity {
+    fn set_identity(&mut self, dev: u64, ino: u64);
+}
+
+impl Identity for libc::stat {
+    fn set_identity(&mut self, dev: u64, ino: u64) {
+        self.st_dev = dev as _;
+        self.st_ino = ino as _;
+    }
+}
+
+#[cfg(target_env = "gnu")]
+impl Identity for libc::stat64 {
+    fn set_identity(&mut self, dev: u64, ino: u64) {
+        self.st_dev = dev as _;
+        self.st_ino = ino as _;
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Identity for libc::statx {
+    fn set_identity(&mut self, dev: u64, ino: u64) {
+        self.stx_dev_major = unsafe { libc::major(dev as _) };
+        self.stx_dev_minor = unsafe { libc::minor(dev as _) };
+        self.stx_ino = ino;
+    }
+}
+
+/// The times in a `stat` buffer.
+trait Times {
+    fn set_times(&mut self, time: i64);
+}
+
+impl Times for libc::stat {
+    fn set_times(&mut self, time: i64) {
+        (self.st_atime, self.st_mtime, self.st_ctime) = (time as _, time as _, time as _);
+        (self.st_atime_nsec, self.st_mtime_nsec, self.st_ctime_nsec) = (0, 0, 0);
+    }
+}
+
+#[cfg(target_env = "gnu")]
+impl Times for libc::stat64 {
+    fn set_times(&mut self, time: i64) {
+        (self.st_atime, self.st_mtime, self.st_ctime) = (time as _, time as _, time as _);
+        (self.st_atime_nsec, self.st_mtime_nsec, self.st_ctime_nsec) = (0, 0, 0);
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Times for libc::statx {
+    fn set_times(&mut self, time: i64) {
+        for timestamp in [
+            &mut self.stx_atime,
+            &mut self.stx_btime,
+            &mut self.stx_ctime,
+            &mut self.stx_mtime,
+        ] {
+            timestamp.tv_sec = time;
+            timestamp.tv_nsec = 0;
+        }
+    }
+}
+
+/// The owner in a `stat` buffer.
+trait Owner {
+    fn set_owner(&mut self, uid: Option<u32>, gid: Option<u32>);
+}
+
+impl Owner for libc::stat {
+    fn set_owner(&mut self, uid: Option<u32>, gid: Option<u32>) {
+        self.st_uid = uid.unwrap_or(self.st_uid);
+        self.st_gid = gid.unwrap_or(self.st_gid);
+    }
+}
+
+#[cfg(target_env = "gnu")]
+impl Owner for libc::stat64 {
+    fn set_owner(&mut self, uid: Option<u32>, gid: Option<u32>) {
+        self.st_uid = uid.unwrap_or(self.st_uid);
+        self.st_gid = gid.unwrap_or(self.st_gid);
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Owner for libc::statx {
+    fn set_owner(&mut self, uid: Option<u32>, gid: Option<u32>) {
+        self.stx_uid = uid.unwrap_or(self.stx_uid);
+        self.stx_gid = gid.unwrap_or(self.stx_gid);
+    }
+}
+
+/// The `stat` structures which can describe a file that doesn't exist.
+trait Synthetic {
+    /// Describe a regular file with `mode` permissions and `size` bytes, owned by the caller.
+    fn synthesize(&mut self, mode: u32, size: u64);
+}
+
+impl Synthetic for libc::stat {
+    fn synthesize(&mut self, mode: u32, size: u64) {
+        *self = unsafe { mem::zeroed() };
+        self.st_mode = libc::S_IFREG | mode as libc::mode_t;
+        self.st_nlink = 1;
+        self.st_uid = unsafe { libc::getuid() };
+        self.st_gid = unsafe { libc::getgid() };
+        self.st_size = size as _;
+        self.st_blksize = 4096;
+    }
+}
+
+#[cfg(target_env = "gnu")]
+impl Synthetic for libc::stat64 {
+    fn synthesize(&mut self, mode: u32, size: u64) {
+        *self = unsafe { mem::zeroed() };
+        self.st_mode = (libc::S_IFREG | mode) as _;
+        self.st_nlink = 1;
+        self.st_uid = unsafe { libc::getuid() };
+        self.st_gid = unsafe { libc::getgid() };
+        self.st_size = size as _;
+        self.st_blksize = 4096;
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Synthetic for libc::statx {
+    fn synthesize(&mut self, mode: u32, size: u64) {
+        *self = unsafe { mem::zeroed() };
+        self.stx_mask = libc::STATX_BASIC_STATS;
+        self.stx_mode = (libc::S_IFREG | mode) as _;
+        self.stx_nlink = 1;
+        self.stx_uid = unsafe { libc::getuid() };
+        self.stx_gid = unsafe { libc::getgid() };
+        self.stx_size = size;
+        self.stx_blksize = 4096;
+    }
+}
+
+/// Call the `stat` function `real` with `path` resolved in the fake root, and rewrite its results
+/// with `spoof_stat`. With `ENV_FAKEROOT_ALL` and `ENV_FAKEROOT_SYNTH_STAT` enabled, a file which
+/// doesn't exist in the fake root is described as an empty one instead of failing with `ENOENT`.
+unsafe fn stat_with<T: Identity + Times + Owner + Synthetic>(
+    name: &'static str,
+    path: *const c_char,
+    flags: Option<c_int>,
+    buf: *mut T,
+    real: impl Fn(*const c_char) -> c_int,
+) -> c_int {
+    let _hook = enter_hook(name);
+    let _profile = start_profile(name);
+    if path.is_null() {
+        return real(path);
+    }
+
+    let mut ret = match decide(Hook::File, CStr::from_ptr(path), flags) {
+        Decision::Redirect(c_str) => real(c_str.as_ptr()),
+        Decision::Passthrough => return quiet(real(path), flags),
+        Decision::Deny(errno) => return deny(errno),
+    };
+    if vanished(&ret, CStr::from_ptr(path)) {
+        return quiet(real(path), flags);
+    }
+
+    let options = options();
+    let missing = ret != 0 && *errno_location() == libc::ENOENT;
+    if missing && options.all && options.synth_stat && !buf.is_null() {
+        log!(
+            LOG_REDIRECT,
+            "{}: {} => synthetic file",
+            hook_tag(),
+            CStr::from_ptr(path).to_string_lossy()
+        );
+        let size = options.default_content.as_ref().map_or(0, String::len);
+        (*buf).synthesize(0o644, size as u64);
+        ret = 0;
+    }
+
+    spoof_stat(ret, &CStr::from_ptr(path).to_string_lossy(), buf);
+    ret
+}
+
+/// Rewrite the results of a successful `stat` of the faked `path`: its times are set to
+/// `ENV_FAKEROOT_FAKE_TIME`, its owner to the one given by `chown` (or `ENV_FAKEROOT_UID` and
+/// `ENV_FAKEROOT_GID`), and if `ENV_FAKEROOT_SPOOF_INODE` is enabled its device and inode numbers
+/// are replaced with synthetic ones, so the fake file has the same identity however it's looked at.
+unsafe fn spoof_stat<T: Identity + Times + Owner>(ret: c_int, path: &str, buf: *mut T) {
+    if ret != 0 || buf.is_null() {
+        return;
+    }
+
+    let options = options();
+    if let Some(time) = options.fake_time {
+        (*buf).set_times(time);
+    }
+
+    let spoof_owner = options.uid.is_some() || options.gid.is_some();
+    if !spoof_owner && !options.spoof_inode {
+        return;
+    }
+
+    let logical_path = match get_logical_path(options, path) {
+        Some(path) => path,
+        None => return,
+    };
+    if spoof_owner {
+        let owners = SPOOFED_OWNERS.lock().ok();
+        let (uid, gid) = owners
+            .and_then(|owners| owners.get(&logical_path).copied())
+            .unwrap_or_default();
+        (*buf).set_owner(uid.or(options.uid), gid.or(options.gid));
+    }
+
+    if !options.spoof_inode {
+        return;
+    }
+
+    if let Ok(mut inodes) = SPOOFED_INODES.lock() {
+        // renames can leave gaps, so new numbers always follow the highest one handed out
+        let next = inodes.values().max().map_or(1, |ino| ino + 1);
+        let ino = *inodes.entry(logical_path).or_insert(next);
+        (*buf).set_identity(SPOOFED_DEV, ino);
+    }
+}
+
+/// Rename `old` to `new` with `real`, resolving each of them in the fake root. Renaming a faked
+/// file moves everything remembered about it to its new name.
+unsafe fn rename_with(
+    name: &'static str,
+    old: *const c_char,
+    new: *const c_char,
+    real: impl Fn(*const c_char, *const c_char) -> c_int,
+) -> c_int {
+    let _hook = enter_hook(name);
+    let _profile = start_profile(name);
+    if old.is_null() || new.is_null() {
+        return real(old, new);
+    }
+
+    // neither path is followed if it's a symlink, and the new one is created
+    let nofollow = libc::O_NOFOLLOW;
+    let old_path = match decide(Hook::File, CStr::from_ptr(old), Some(nofollow)) {
+        Decision::Redirect(c_str) => Some(c_str),
+        Decision::Passthrough => None,
+        Decision::Deny(errno) => return deny(errno),
+    };
+
+    let flags = nofollow | libc::O_WRONLY | libc::O_CREAT;
+    create_parents(new, flags);
+    let new_path = match decide(Hook::File, CStr::from_ptr(new), Some(flags)) {
+        Decision::Redirect(c_str) => Some(c_str),
+        Decision::Passthrough => None,
+        Decision::Deny(errno) => return deny(errno),
+    };
+
+    let ret = real(
+        old_path.as_ref().map_or(old, |c_str| c_str.as_ptr()),
+        new_path.as_ref().map_or(new, |c_str| c_str.as_ptr()),
+    );
+    if let (0, Some(_), Some(fake_new)) = (ret, old_path, new_path) {
+        let fake_new = PathBuf::from(OsStr::from_bytes(fake_new.to_bytes()));
+        bypass(|| renamed(CStr::from_ptr(old), CStr::from_ptr(new), &fake_new));
+    }
+
+    ret
+}
+
+/// Load the spoofed inode numbers and owners saved in the `ENV_FAKEROOT_STATE` file by earlier
+/// processes, and save them back to it (with any changes) when this one exits.
+fn load_state(options: &Options) {
+    let state = match &options.state {
+        Some(state) => state,
+        None => return,
+    };
+
+    unsafe { libc::atexit(write_state) };
+    let contents = match bypass(|| fs::read_to_string(state)) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return,
+        Err(e) => {
+            log!(
+                LOG_ERROR,
+                "{}: failed to read {}: {}",
+                hook_tag(),
+                ENV_FAKEROOT_STATE,
+                e
+            );
+            return;
+        }
+    };
+
+    let (inodes, owners) = parse_state(&contents);
+    if let Ok(mut spoofed) = SPOOFED_INODES.lock() {
+        spoofed.extend(inodes);
+    }
+    if let Ok(mut spoofed) = SPOOFED_OWNERS.lock() {
+        spoofed.extend(owners);
+    }
+}
+
+/// Merge the spoofed inode numbers and owners of this process into the `ENV_FAKEROOT_STATE` file.
+extern "C" fn write_state() {
+    FAKEROOT_STATE_WRITTEN.call_once(|| {
+        let state = FAKEROOT_OPTIONS
+            .get()
+            .and_then(|options| options.state.as_ref());
+        if let Some(state) = state {
+            if let Err(e) = bypass(|| merge_state(state)) {
+                log!(
+                    LOG_ERROR,
+                    "{}: failed to write {}: {}",
+                    hook_tag(),
+                    ENV_FAKEROOT_STATE,
+                    e
+                );
+            }
+        }
+    });
+}
+
+/// Merge into the state file, taking this process' entries over the ones already in it.
+fn merge_state(state: &Path) -> io::Result<()> {
+    let mut file = File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(state)?;
+
+    // processes exiting at the same time would otherwise lose each other's entries
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    let (mut inodes, mut owners) = parse_state(&contents);
+    if let Ok(spoofed) = SPOOFED_INODES.lock() {
+        inodes.extend(spoofed.iter().map(|(path, ino)| (path.clone(), *ino)));
+    }
+    if let Ok(spoofed) = SPOOFED_OWNERS.lock() {
+        owners.extend(spoofed.iter().map(|(path, owner)| (path.clone(), *owner)));
+    }
+
+    file.set_len(0)?;
+    file.rewind()?;
+    file.write_all(format_state(&inodes, &owners).as_bytes())
+}
+
+/// Parse the lines of a state file, each of which is a tab separated inode number, user ID, group ID
+/// and logical path, where `-` is used for those which haven't been spoofed.
+fn parse_state(contents: &str) -> (BTreeMap<PathBuf, u64>, BTreeMap<PathBuf, Ownership>) {
+    let (mut inodes, mut owners) = (BTreeMap::new(), BTreeMap::new());
+    for line in contents.lines() {
+        let mut fields = line.splitn(4, '\t');
+        let (Some(ino), Some(uid), Some(gid), Some(path)) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            continue;
+        };
+
+        let path = PathBuf::from(path);
+        if let Ok(ino) = ino.parse() {
+            inodes.insert(path.clone(), ino);
+        }
+        let owner = (uid.parse().ok(), gid.parse().ok());
+        if owner != (None, None) {
+            owners.insert(path, owner);
+        }
+    }
+
+    (inodes, owners)
+}
+
+/// Format the lines of a state file for `parse_state`.
+fn format_state(inodes: &BTreeMap<PathBuf, u64>, owners: &BTreeMap<PathBuf, Ownership>) -> String {
+    let field = |value: Option<String>| value.unwrap_or_else(|| "-".into());
+    let paths = inodes.keys().chain(owners.keys()).collect::<BTreeSet<_>>();
+    let mut contents = String::new();
+    // each entry is a single line, so a path with a newline in it can't be kept
+    for path in paths
+        .into_iter()
+        .filter(|path| !path.as_os_str().as_bytes().contains(&b'\n'))
+    {
+        let (uid, gid) = owners.get(path).copied().unwrap_or_default();
+        contents.push_str(&format!(
+            "{}\t{}\t{}\t{}\n",
+            field(inodes.get(path).map(u64::to_string)),
+            field(uid.map(|uid| uid.to_string())),
+            field(gid.map(|gid| gid.to_string())),
+            path.display()
+        ));
+    }
+
+    contents
+}
+
+/// Move the entries of `map` to the paths they've been `moved` to.
+fn rename_keys<V>(map: &mut BTreeMap<PathBuf, V>, moved: impl Fn(&Path) -> Option<PathBuf>) {
+    let renamed = map
+        .keys()
+        .filter_map(|path| Some((path.clone(), moved(path)?)))
+        .collect::<Vec<_>>();
+    for (path, new_path) in renamed {
+        if let Some(value) = map.remove(&path) {
+            map.insert(new_path, value);
+        }
+    }
+}
+
+/// Change the owner of `path` to `uid` and `gid` with `real`, resolving it in the fake root. With
+/// `ENV_FAKEROOT_UID` or `ENV_FAKEROOT_GID` set, a faked file keeps its real owner (which an
+/// unprivileged user couldn't change) and the new one is only remembered for `stat` to report.
+unsafe fn chown_with(
+    name: &'static str,
+    path: *const c_char,
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+    flags: Option<c_int>,
+    real: impl Fn(*const c_char, libc::uid_t, libc::gid_t) -> c_int,
+) -> c_int {
+    let _hook = enter_hook(name);
+    let _profile = start_profile(name);
+    if path.is_null() {
+        return real(path, uid, gid);
+    }
+
+    let fake_path = match decide(Hook::File, CStr::from_ptr(path), flags) {
+        Decision::Redirect(c_str) => c_str,
+        Decision::Passthrough => return quiet(real(path, uid, gid), flags),
+        Decision::Deny(errno) => return deny(errno),
+    };
+    let options = options();
+    if options.uid.is_none() && options.gid.is_none() {
+        return real(fake_path.as_ptr(), uid, gid);
+    }
+
+    // changing neither still fails as the real call would if the file can't be reached
+    let ret = real(fake_path.as_ptr(), libc::uid_t::MAX, libc::gid_t::MAX);
+    let logical_path =
+        bypass(|| get_logical_path(options, &CStr::from_ptr(path).to_string_lossy()));
+    if let (0, Some(logical_path)) = (ret, logical_path) {
+        log!(
+            LOG_REDIRECT,
+            "{}: {} owned by {}:{}",
+            hook_tag(),
+            logical_path.display(),
+            uid as i32,
+            gid as i32
+        );
+        if let Ok(mut owners) = SPOOFED_OWNERS.lock() {
+            let owner = owners.entry(logical_path).or_default();
+            // like the real call, -1 leaves the user or group as it was
+            if uid != libc::uid_t::MAX {
+                owner.0 = Some(uid);
+            }
+            if gid != libc::gid_t::MAX {
+                owner.1 = Some(gid);
+            }
+        }
+    }
+
+    ret
+}
+
+/// Move what's remembered about the faked file `old` (and anything in it, if it's a directory) to
+/// `new`, which it's just been renamed to at `fake_new` in the fake root. This must be called while
+/// the hooks are bypassed.
+fn renamed(old: &CStr, new: &CStr, fake_new: &Path) {
+    let options = options();
+    let logical = |c_str: &CStr| get_logical_path(options, str::from_utf8(c_str.to_bytes()).ok()?);
+    let (Some(old), Some(new)) = (logical(old), logical(new)) else {
+        return;
+    };
+
+    let moved = |path: &Path| {
+        let rest = path.strip_prefix(&old).ok()?;
+        Some(if rest.as_os_str().is_empty() {
+            new.clone()
+        } else {
+            new.join(rest)
+        })
+    };
+
+    if let Ok(mut inodes) = SPOOFED_INODES.lock() {
+        rename_keys(&mut inodes, moved);
+    }
+    if let Ok(mut owners) = SPOOFED_OWNERS.lock() {
+        rename_keys(&mut owners, moved);
+    }
+
+    // descriptors and streams hold the paths they were opened with
+    let move_path = |path: &mut String| {
+        let logical_path = get_logical_path(options, path);
+        if let Some(new_path) = logical_path.as_deref().and_then(moved) {
+            *path = new_path.to_string_lossy().into_owned();
+        }
+    };
+    if let Ok(mut fds) = FAKE_FDS.lock() {
+        fds.values_mut().for_each(&move_path);
+    }
+    if let Ok(mut streams) = FAKE_STREAMS.lock() {
+        streams.values_mut().for_each(&move_path);
+    }
+
+    // files created in the fake root are logged again under their new names
+    let touched = FAKEROOT_TOUCHED.lock().ok().map(|mut touched| {
+        let renamed = touched
+            .iter()
+            .filter_map(|path| Some((path.clone(), moved(path)?)))
+            .collect::<Vec<_>>();
+        for (path, _) in &renamed {
+            touched.remove(path);
+        }
+        renamed
+    });
+    for (_, new_path) in touched.into_iter().flatten() {
+        let rest = new_path.strip_prefix(&new).unwrap_or(Path::new(""));
+        if rest.as_os_str().is_empty() {
+            touch(options, fake_new);
+        } else {
+            touch(options, &fake_new.join(rest));
+        }
+    }
+}
+
+/// Copy the environment `envp` of a program about to be executed, adding this process's
+/// `LD_PRELOAD` and `FAKEROOT*` variables if it doesn't set them itself, so the program is still
+/// faked when it's launched with a cleared environment. A propagated fake root replaces the
+/// program's own.
+unsafe fn preload_env(envp: *const *const c_char) -> Vec<CString> {
+    let mut vars = Vec::new();
+    let mut keys = BTreeSet::new();
+    if let Some(root) = FAKEROOT_PROPAGATED.get() {
+        let var = [ENV_FAKEROOT.as_bytes(), b"=", root.as_os_str().as_bytes()].concat();
+        keys.insert(ENV_FAKEROOT.as_bytes().to_vec());
+        vars.extend(CString::new(var).ok());
+    }
+
+    let mut var = envp;
+    while !var.is_null() && !(*var).is_null() {
+        let c_str = CStr::from_ptr(*var);
+        let key = c_str
+            .to_bytes()
+            .split(|b| *b == b'=')
+            .next()
+            .unwrap_or_default();
+        var = var.add(1);
+        if key == ENV_FAKEROOT.as_bytes() && FAKEROOT_PROPAGATED.get().is_some() {
+            continue;
+        }
+
+        keys.insert(key.to_vec());
+        vars.push(c_str.to_owned());
+    }
+
+    for (key, value) in env::vars_os() {
+        let key = key.as_bytes();
+        if (key == b"LD_PRELOAD" || key.starts_with(b"FAKEROOT")) && !keys.contains(key) {
+            let var = [key, b"=", value.as_bytes()].concat();
+            vars.extend(CString::new(var).ok());
+        }
+    }
+
+    vars
+}
+
+/// Decide where the program at `path` is. It's only redirected if the fake one exists, since even
+/// with `ENV_FAKEROOT_ALL` there's nothing to run until it's been created.
+unsafe fn decide_exec(path: *const c_char) -> Decision {
+    if path.is_null() {
+        return Decision::Passthrough;
+    }
+
+    match decide(Hook::File, CStr::from_ptr(path), None) {
+        Decision::Redirect(c_str)
+            if !bypass(|| Path::new(OsStr::from_bytes(c_str.to_bytes())).exists()) =>
+        {
+            Decision::Passthrough
+        }
+        decision => decision,
+    }
+}
+
+/// Get a null terminated array of pointers to `vars`, as `execve` expects.
+fn env_pointers(vars: &[CString]) -> Vec<*const c_char> {
+    vars.iter()
+        .map(|var| var.as_ptr())
+        .chain([ptr::null()])
+        .collect()
+}
+
+/// Remember a stream that was opened in the fake root, along with its file descriptor, so later
+/// calls which only receive either of them know that it's been faked.
+unsafe fn track_stream(stream: *mut FILE, path: *const c_char) {
+    if stream.is_null() {
+        return;
+    }
+
+    track_fd(libc::fileno(stream), path);
+    let path = CStr::from_ptr(path).to_string_lossy().into_owned();
+    if let Ok(mut streams) = FAKE_STREAMS.lock() {
+        streams.insert(stream as usize, path);
+    }
+}
+
+/// Translate a stdio mode string (as given to `fopen`) into the equivalent `open` flags.
+/// Invalid modes return `-1`, and are left for the real `fopen` to reject.
+fn fopen_flags(mode: &CStr) -> c_int {
+    let mode = mode.to_bytes();
+    let mut flags = match mode.first() {
+        Some(b'r') => libc::O_RDONLY,
+        Some(b'w') => libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC,
+        Some(b'a') => libc::O_WRONLY | libc::O_CREAT | libc::O_APPEND,
+        _ => return -1,
+    };
+
+    for c in &mode[1..] {
+        match c {
+            b'+' => flags = (flags & !libc::O_ACCMODE) | libc::O_RDWR,
+            b'x' if flags & libc::O_CREAT != 0 => flags |= libc::O_EXCL,
+            b'e' => flags |= libc::O_CLOEXEC,
+            // glibc's `,ccs=` suffix
+            b',' => break,
+            _ => {}
+        }
+    }
+
+    flags
+}
+
+fn is_enabled(env_key: &str) -> bool {
+    env::var(env_key).is_ok_and(|val| is_truthy(&val))
+}
+
+fn is_truthy(val: &str) -> bool {
+    val != "false" && val != "0"
+}
+
+// macros ----------------------------------------------------------------------
+
+macro_rules! do_hook {
+    ($name:ident => $($before_arg:ident, )* [$path:ident] $(, $after_arg:ident)* $(; $redirected:expr)? $(,)?) => {
+        do_hook!($name(Hook::File, None) => $($before_arg, )* [$path] $(, $after_arg)* $(; $redirected)?)
+    };
+
+    ($name:ident($hook:expr, $flags:expr) => $($before_arg:ident, )* [$path:ident] $(, $after_arg:ident)* $(; $redirected:expr)? $(,)?) => {{
+        let real = redhook::real!($name);
+        let _hook = enter_hook(stringify!($name));
+        let _profile = start_profile(stringify!($name));
+        match decide($hook, CStr::from_ptr($path), $flags) {
+            Decision::Redirect(c_str) => {
+                let ret = real($($before_arg, )* c_str.as_ptr() $(, $after_arg)*);
+                if vanished(&ret, CStr::from_ptr($path)) {
+                    return quiet(real($($before_arg, )* $path $(, $after_arg)*), $flags);
+                }
+                $($redirected(ret, $path);)?
+                ret
+            }
+            Decision::Passthrough => quiet(real($($before_arg, )* $path $(, $after_arg)*), $flags),
+            Decision::Deny(errno) => deny(errno),
+        }
+    }};
+}
+
+#[cfg(target_os = "linux")]
+extern "C" {
+    fn fgetpwent(stream: *mut FILE) -> *mut libc::passwd;
+    fn fgetgrent(stream: *mut FILE) -> *mut libc::group;
+    fn fgetpwent_r(
+        stream: *mut FILE,
+        pwd: *mut libc::passwd,
+        buf: *mut c_char,
+        buflen: size_t,
+        result: *mut *mut libc::passwd,
+    ) -> c_int;
+    fn fgetgrent_r(
+        stream: *mut FILE,
+        grp: *mut libc::group,
+        buf: *mut c_char,
+        buflen: size_t,
+        result: *mut *mut libc::group,
+    ) -> c_int;
+    fn fgetspent(stream: *mut FILE) -> *mut libc::spwd;
+}
+
+/// Look up an entry in the fake copy of the database `db` (such as `/etc/passwd`), since libc's NSS
+/// modules open these internally where the `open` hooks can't see them.
+///
+/// Returns `None` if `db` isn't faked (or its fake copy can't be opened, such as when
+/// `ENV_FAKEROOT_ALL` redirects it but it hasn't been created) and the real lookup should be used
+/// instead.
+#[cfg(target_os = "linux")]
+unsafe fn find_entry<T>(
+    db: &CStr,
+    next: unsafe extern "C" fn(*mut FILE) -> *mut T,
+    matches: impl Fn(&T) -> bool,
+) -> Option<*mut T> {
+    match decide(Hook::File, db, None) {
+        Decision::Redirect(c_str) => bypass(|| {
+            let stream = libc::fopen(c_str.as_ptr(), c"r".as_ptr());
+            if stream.is_null() {
+                return None;
+            }
+
+            // like the real lookups, a missing entry is a null result which leaves `errno` alone
+            let mut entry = next(stream);
+            while !entry.is_null() && !matches(&*entry) {
+                entry = next(stream);
+            }
+
+            libc::fclose(stream);
+            Some(entry)
+        }),
+        Decision::Passthrough => None,
+        Decision::Deny(errno) => Some(deny(errno)),
+    }
+}
+
+/// Look up an entry in the fake copy of the database `db` like `find_entry`, for the reentrant
+/// lookups which fill in the caller's `entry` (with its strings in `buf`) and set `result` to it if
+/// it's found, or to null if it isn't. Like them, returns an error number rather than setting
+/// `errno`.
+///
+/// Returns `None` if the real lookup should be used instead.
+#[cfg(target_os = "linux")]
+unsafe fn find_entry_r<T>(
+    db: &CStr,
+    (entry, buf, buflen, result): (*mut T, *mut c_char, size_t, *mut *mut T),
+    next: unsafe extern "C" fn(*mut FILE, *mut T, *mut c_char, size_t, *mut *mut T) -> c_int,
+    matches: impl Fn(&T) -> bool,
+) -> Option<c_int> {
+    match decide(Hook::File, db, None) {
+        Decision::Redirect(c_str) => bypass(|| {
+            let stream = libc::fopen(c_str.as_ptr(), c"r".as_ptr());
+            if stream.is_null() {
+                return None;
+            }
+
+            // reaching the end of the database means there's no such entry, which isn't an error
+            let ret = loop {
+                match next(stream, entry, buf, buflen, result) {
+                    0 if matches(&*entry) => break 0,
+                    0 => continue,
+                    ret => {
+                        *result = ptr::null_mut();
+                        break if ret == libc::ENOENT { 0 } else { ret };
+                    }
+                }
+            };
+
+            libc::fclose(stream);
+            Some(ret)
+        }),
+        Decision::Passthrough => None,
+        Decision::Deny(errno) => {
+            *result = ptr::null_mut();
+            Some(errno)
+        }
+    }
+}
+
+// hooks -----------------------------------------------------------------------
+
+// open
+redhook::hook! {
+    unsafe fn open(path: *const c_char, flags: c_int, mode: c_int) -> c_int => my_open {
+        let _hook = enter_hook("open");
+        if let Some(fd) = open_null(path, flags).or_else(|| open_inline(path, flags)) {
+            return fd;
+        }
+
+        copy_on_write(path, flags);
+        let new_file = creating(path, flags);
+        create_parents(path, flags);
+        let mode = forced_mode(&new_file, mode as libc::mode_t) as c_int;
+        let real = |path| redhook::real!(open)(path, flags, mode);
+        if let Some(fd) = open_confined(Hook::File, path, flags, mode, real) {
+            return created(new_file, fd);
+        }
+        created(new_file, do_hook!(open(Hook::File, Some(flags)) => [path], flags, mode; track_fd))
+    }
+}
+
+// open64
+#[cfg(target_env = "gnu")]
+redhook::hook! {
+    unsafe fn open64(path: *const c_char, flags: c_int, mode: c_int) -> c_int => my_open64 {
+        let _hook = enter_hook("open64");
+        if let Some(fd) = open_null(path, flags).or_else(|| open_inline(path, flags)) {
+            return fd;
+        }
+
+        copy_on_write(path, flags);
+        let new_file = creating(path, flags);
+        create_parents(path, flags);
+        let mode = forced_mode(&new_file, mode as libc::mode_t) as c_int;
+        let real = |path| redhook::real!(open64)(path, flags, mode);
+        if let Some(fd) = open_confined(Hook::File, path, flags, mode, real) {
+            return created(new_file, fd);
+        }
+        created(new_file, do_hook!(open64(Hook::File, Some(flags)) => [path], flags, mode; track_fd))
+    }
+}
+
+// openat
+redhook::hook! {
+    unsafe fn openat(dirfd: c_int, path: *const c_char, flags: c_int, mode: c_int) -> c_int => my_openat {
+        let _hook = enter_hook("openat");
+        if path.is_null() || (dirfd != libc::AT_FDCWD && *path != b'/' as c_char) {
+            let fd = redhook::real!(openat)(dirfd, path, flags, mode);
+            track_relative_fd(fd, dirfd, path);
+            return fd;
+        }
+
+        if let Some(fd) = open_null(path, flags).or_else(|| open_inline(path, flags)) {
+            return fd;
+        }
+
+        copy_on_write(path, flags);
+        let new_file = creating(path, flags);
+        create_parents(path, flags);
+        let mode = forced_mode(&new_file, mode as libc::mode_t) as c_int;
+        let real = |path| redhook::real!(openat)(dirfd, path, flags, mode);
+        if let Some(fd) = open_confined(open_hook(flags), path, flags, mode, real) {
+            return created(new_file, fd);
+        }
+        created(new_file, do_hook!(openat(open_hook(flags), Some(flags)) => dirfd, [path], flags, mode; track_fd))
+    }
+}
+
+// openat64
+#[cfg(target_env = "gnu")]
+redhook::hook! {
+    unsafe fn openat64(dirfd: c_int, path: *const c_char, flags: c_int, mode: c_int) -> c_int => my_openat64 {
+        let _hook = enter_hook("openat64");
+        if path.is_null() || (dirfd != libc::AT_FDCWD && *path != b'/' as c_char) {
+            let fd = redhook::real!(openat64)(dirfd, path, flags, mode);
+            track_relative_fd(fd, dirfd, path);
+            return fd;
+        }
+
+        if let Some(fd) = open_null(path, flags).or_else(|| open_inline(path, flags)) {
+            return fd;
+        }
+
+        copy_on_write(path, flags);
+        let new_file = creating(path, flags);
+        create_parents(path, flags);
+        let mode = forced_mode(&new_file, mode as libc::mode_t) as c_int;
+        let real = |path| redhook::real!(openat64)(dirfd, path, flags, mode);
+        if let Some(fd) = open_confined(open_hook(flags), path, flags, mode, real) {
+            return created(new_file, fd);
+        }
+        created(new_file, do_hook!(openat64(open_hook(flags), Some(flags)) => dirfd, [path], flags, mode; track_fd))
+    }
+}
+
+// creat
+redhook::hook! {
+    unsafe fn creat(path: *const c_char, mode: libc::mode_t) -> c_int => my_creat {
+        let _hook = enter_hook("creat");
+        let flags = libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC;
+        if let Some(fd) = open_null(path, flags) {
+            return fd;
+        }
+        copy_on_write(path, flags);
+        let new_file = creating(path, flags);
+        create_parents(path, flags);
+        let mode = forced_mode(&new_file, mode);
+        created(new_file, do_hook!(creat(Hook::File, Some(flags)) => [path], mode; track_fd))
+    }
+}
+
+// creat64
+#[cfg(target_env = "gnu")]
+redhook::hook! {
+    unsafe fn creat64(path: *const c_char, mode: libc::mode_t) -> c_int => my_creat64 {
+        let _hook = enter_hook("creat64");
+        let flags = libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC;
+        if let Some(fd) = open_null(path, flags) {
+            return fd;
+        }
+        copy_on_write(path, flags);
+        let new_file = creating(path, flags);
+        create_parents(path, flags);
+        let mode = forced_mode(&new_file, mode);
+        created(new_file, do_hook!(creat64(Hook::File, Some(flags)) => [path], mode; track_fd))
+    }
+}
+
+// fopen
+redhook::hook! {
+    unsafe fn fopen(path: *const c_char, mode: *const c_char) -> *mut FILE => my_fopen {
+        let _hook = enter_hook("fopen");
+        let flags = fopen_flags(CStr::from_ptr(mode));
+        if let Some(fd) = open_null(path, flags).or_else(|| open_inline(path, flags)) {
+            return if fd < 0 { ptr::null_mut() } else { libc::fdopen(fd, mode) };
+        }
+        copy_on_write(path, flags);
+        let new_file = creating(path, flags);
+        create_parents(path, flags);
+        created(new_file, do_hook!(fopen(Hook::File, Some(flags)) => [path], mode; track_stream))
+    }
+}
+
+// fopen64
+#[cfg(target_env = "gnu")]
+redhook::hook! {
+    unsafe fn fopen64(path: *const c_char, mode: *const c_char) -> *mut FILE => my_fopen64 {
+        let _hook = enter_hook("fopen64");
+        let flags = fopen_flags(CStr::from_ptr(mode));
+        if let Some(fd) = open_null(path, flags).or_else(|| open_inline(path, flags)) {
+            return if fd < 0 { ptr::null_mut() } else { libc::fdopen(fd, mode) };
+        }
+        copy_on_write(path, flags);
+        let new_file = creating(path, flags);
+        create_parents(path, flags);
+        created(new_file, do_hook!(fopen64(Hook::File, Some(flags)) => [path], mode; track_stream))
+    }
+}
+
+// stat
+redhook::hook! {
+    unsafe fn stat(path: *const c_char, buf: *mut libc::stat) -> c_int => my_stat {
+        stat_with("stat", path, None, buf, |path| redhook::real!(stat)(path, buf))
+    }
+}
+
+// lstat
+redhook::hook! {
+    unsafe fn lstat(path: *const c_char, buf: *mut libc::stat) -> c_int => my_lstat {
+        let nofollow = Some(libc::O_NOFOLLOW);
+        stat_with("lstat", path, nofollow, buf, |path| redhook::real!(lstat)(path, buf))
+    }
+}
+
+// stat64
+#[cfg(target_env = "gnu")]
+redhook::hook! {
+    unsafe fn stat64(path: *const c_char, buf: *mut libc::stat64) -> c_int => my_stat64 {
+        stat_with("stat64", path, None, buf, |path| redhook::real!(stat64)(path, buf))
+    }
+}
+
+// lstat64
+#[cfg(target_env = "gnu")]
+redhook::hook! {
+    unsafe fn lstat64(path: *const c_char, buf: *mut libc::stat64) -> c_int => my_lstat64 {
+        let nofollow = Some(libc::O_NOFOLLOW);
+        stat_with("lstat64", path, nofollow, buf, |path| redhook::real!(lstat64)(path, buf))
+    }
+}
+
+// statx
+#[cfg(target_os = "linux")]
+redhook::hook! {
+    unsafe fn statx(dirfd: c_int, path: *const c_char, flags: c_int, mask: libc::c_uint, buf: *mut libc::statx) -> c_int => my_statx {
+        // an empty path with `AT_EMPTY_PATH` refers to `dirfd` itself, and other relative paths are
+        // relative to `dirfd` rather than the current directory, so neither can be rewritten
+        if path.is_null()
+            || (flags & libc::AT_EMPTY_PATH != 0 && *path == 0)
+            || (dirfd != libc::AT_FDCWD && *path != b'/' as c_char)
+        {
+            return redhook::real!(statx)(dirfd, path, flags, mask, buf);
+        }
+
+        let nofollow = (flags & libc::AT_SYMLINK_NOFOLLOW != 0).then_some(libc::O_NOFOLLOW);
+        stat_with("statx", path, nofollow, buf, |path| {
+            redhook::real!(statx)(dirfd, path, flags, mask, buf)
+        })
+    }
+}
+
+// chown
+redhook::hook! {
+    unsafe fn chown(path: *const c_char, uid: libc::uid_t, gid: libc::gid_t) -> c_int => my_chown {
+        chown_with("chown", path, uid, gid, None, |path, uid, gid| redhook::real!(chown)(path, uid, gid))
+    }
+}
+
+// lchown
+redhook::hook! {
+    unsafe fn lchown(path: *const c_char, uid: libc::uid_t, gid: libc::gid_t) -> c_int => my_lchown {
+        let nofollow = Some(libc::O_NOFOLLOW);
+        chown_with("lchown", path, uid, gid, nofollow, |path, uid, gid| redhook::real!(lchown)(path, uid, gid))
+    }
+}
+
+// fchownat
+redhook::hook! {
+    unsafe fn fchownat(dirfd: c_int, path: *const c_char, uid: libc::uid_t, gid: libc::gid_t, flags: c_int) -> c_int => my_fchownat {
+        // relative paths are resolved by the kernel within `dirfd`, as is `dirfd` itself with
+        // `AT_EMPTY_PATH`
+        if path.is_null() || (dirfd != libc::AT_FDCWD && *path != b'/' as c_char) {
+            return redhook::real!(fchownat)(dirfd, path, uid, gid, flags);
+        }
+
+        let nofollow = (flags & libc::AT_SYMLINK_NOFOLLOW != 0).then_some(libc::O_NOFOLLOW);
+        chown_with("fchownat", path, uid, gid, nofollow, |path, uid, gid| {
+            redhook::real!(fchownat)(dirfd, path, uid, gid, flags)
+        })
+    }
+}
+
+// access
+redhook::hook! {
+    unsafe fn access(path: *const c_char, mode: c_int) -> c_int => my_access {
+        // with `ENV_FAKEROOT_ALL` this checks the fake path, so files only exist once they're created
+        do_hook!(access => [path], mode)
+    }
+}
+
+// faccessat
+redhook::hook! {
+    unsafe fn faccessat(dirfd: c_int, path: *const c_char, mode: c_int, flags: c_int) -> c_int => my_faccessat {
+        // relative paths are resolved by the kernel within `dirfd`
+        if path.is_null() || (dirfd != libc::AT_FDCWD && *path != b'/' as c_char) {
+            return redhook::real!(faccessat)(dirfd, path, mode, flags);
+        }
+
+        // `flags` (such as `AT_EACCESS`) are passed on, so the fake file is checked the same way
+        do_hook!(faccessat => dirfd, [path], mode, flags)
+    }
+}
+
+// readlink
+redhook::hook! {
+    unsafe fn readlink(path: *const c_char, buf: *mut c_char, size: size_t) -> ssize_t => my_readlink {
+        do_hook!(readlink(Hook::File, Some(libc::O_NOFOLLOW)) => [path], buf, size)
+    }
+}
+
+// readlinkat
+redhook::hook! {
+    unsafe fn readlinkat(dirfd: c_int, path: *const c_char, buf: *mut c_char, size: size_t) -> ssize_t => my_readlinkat {
+        // an empty path reads the link `dirfd` itself refers to (it was opened with `O_PATH`), and
+        // other relative paths are resolved by the kernel within `dirfd`
+        if path.is_null() || *path == 0 || (dirfd != libc::AT_FDCWD && *path != b'/' as c_char) {
+            return redhook::real!(readlinkat)(dirfd, path, buf, size);
+        }
+
+        do_hook!(readlinkat(Hook::File, Some(libc::O_NOFOLLOW)) => dirfd, [path], buf, size)
+    }
+}
+
+// rename
+redhook::hook! {
+    unsafe fn rename(old: *const c_char, new: *const c_char) -> c_int => my_rename {
+        rename_with("rename", old, new, |old, new| redhook::real!(rename)(old, new))
+    }
+}
+
+// renameat
+redhook::hook! {
+    unsafe fn renameat(olddirfd: c_int, old: *const c_char, newdirfd: c_int, new: *const c_char) -> c_int => my_renameat {
+        // relative paths are resolved by the kernel within their directories
+        let relative = |dirfd: c_int, path: *const c_char| {
+            path.is_null() || (dirfd != libc::AT_FDCWD && *path != b'/' as c_char)
+        };
+        if relative(olddirfd, old) || relative(newdirfd, new) {
+            return redhook::real!(renameat)(olddirfd, old, newdirfd, new);
+        }
+
+        rename_with("renameat", old, new, |old, new| {
+            redhook::real!(renameat)(olddirfd, old, newdirfd, new)
+        })
+    }
+}
+
+// renameat2
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+redhook::hook! {
+    unsafe fn renameat2(olddirfd: c_int, old: *const c_char, newdirfd: c_int, new: *const c_char, flags: libc::c_uint) -> c_int => my_renameat2 {
+        let relative = |dirfd: c_int, path: *const c_char| {
+            path.is_null() || (dirfd != libc::AT_FDCWD && *path != b'/' as c_char)
+        };
+        if relative(olddirfd, old) || relative(newdirfd, new) {
+            return redhook::real!(renameat2)(olddirfd, old, newdirfd, new, flags);
+        }
+
+        rename_with("renameat2", old, new, |old, new| {
+            redhook::real!(renameat2)(olddirfd, old, newdirfd, new, flags)
+        })
+    }
+}
+
+// utimes
+redhook::hook! {
+    unsafe fn utimes(path: *const c_char, times: *const libc::timeval) -> c_int => my_utimes {
+        do_hook!(utimes => [path], times)
+    }
+}
+
+// lutimes
+redhook::hook! {
+    unsafe fn lutimes(path: *const c_char, times: *const libc::timeval) -> c_int => my_lutimes {
+        // sets the times of a faked symlink itself, since the redirected path still ends with it
+        do_hook!(lutimes(Hook::File, Some(libc::O_NOFOLLOW)) => [path], times)
+    }
+}
+
+// chflags
+#[cfg(any(target_os = "freebsd", target_os = "macos"))]
+redhook::hook! {
+    unsafe fn chflags(path: *const c_char, flags: FileFlags) -> c_int => my_chflags {
+        do_hook!(chflags => [path], flags)
+    }
+}
+
+// lchflags
+#[cfg(any(target_os = "freebsd", target_os = "macos"))]
+redhook::hook! {
+    unsafe fn lchflags(path: *const c_char, flags: FileFlags) -> c_int => my_lchflags {
+        // sets the flags of a faked symlink itself, since the redirected path still ends with it
+        do_hook!(lchflags(Hook::File, Some(libc::O_NOFOLLOW)) => [path], flags)
+    }
+}
+
+// opendir
+redhook::hook! {
+    unsafe fn opendir(path: *const c_char) -> *mut DIR => my_opendir {
+        create_missing_dir(path);
+        let dir = do_hook!(opendir(Hook::Dir, None) => [path]);
+        track_whiteout_dir(dir, path);
+        track_sorted_dir(dir);
+        dir
+    }
+}
+
+// readdir
+redhook::hook! {
+    unsafe fn readdir(dirp: *mut DIR) -> *mut libc::dirent => my_readdir {
+        read_sorted(&SORTED_DIRS, dirp, redhook::real!(readdir))
+    }
+}
+
+// readdir64
+#[cfg(target_env = "gnu")]
+redhook::hook! {
+    unsafe fn readdir64(dirp: *mut DIR) -> *mut libc::dirent64 => my_readdir64 {
+        read_sorted(&SORTED_DIRS64, dirp, redhook::real!(readdir64))
+    }
+}
+
+// closedir
+redhook::hook! {
+    unsafe fn closedir(dirp: *mut DIR) -> c_int => my_closedir {
+        if let Ok(mut dirs) = WHITEOUT_DIRS.lock() {
+            dirs.remove(&(dirp as usize));
+        }
+        if let Ok(mut dirs) = SORTED_DIRS.lock() {
+            dirs.remove(&(dirp as usize));
+        }
+        #[cfg(target_env = "gnu")]
+        if let Ok(mut dirs) = SORTED_DIRS64.lock() {
+            dirs.remove(&(dirp as usize));
+        }
+
+        redhook::real!(closedir)(dirp)
+    }
+}
+
+// getdents64
+#[cfg(target_os = "linux")]
+redhook::hook! {
+    unsafe fn getdents64(fd: c_int, dirp: *mut c_void, count: size_t) -> ssize_t => my_getdents64 {
+        let _hook = enter_hook("getdents64");
+        // the descriptor already points at the fake directory, so its entries only need rewriting
+        // to hide whiteouts
+        let real = redhook::real!(getdents64);
+        let path = match FAKE_FDS.lock().ok().and_then(|fds| fds.get(&fd).cloned()) {
+            Some(path) => path,
+            None => return real(fd, dirp, count),
+        };
+        log!(LOG_REDIRECT, "{}: getdents64 on fake directory {} (fd {})", hook_tag(), path, fd);
+
+        let logical_path = get_logical_path(options(), &path);
+        let relative = logical_path.as_deref().and_then(|path| mounted_path(options(), path));
+        let fake_dir = match (&options().root, relative) {
+            (Ok(root), Some(relative)) if options().whiteout => root.join(relative),
+            _ => return real(fd, dirp, count),
+        };
+
+        // an empty buffer means the end of the directory, so keep reading if everything was hidden
+        loop {
+            let n = real(fd, dirp, count);
+            if n <= 0 {
+                return n;
+            }
+
+            let filtered = filter_dirents(&fake_dir, dirp, n as usize);
+            if filtered > 0 {
+                return filtered as ssize_t;
+            }
+        }
+    }
+}
+
+// fstat
+redhook::hook! {
+    unsafe fn fstat(fd: c_int, buf: *mut libc::stat) -> c_int => my_fstat {
+        let _hook = enter_hook("fstat");
+        // the descriptor already points at the fake file, so this reports its size and type, and
+        // only its identity and times may need rewriting
+        let ret = redhook::real!(fstat)(fd, buf);
+        if let Some(path) = FAKE_FDS.lock().ok().and_then(|fds| fds.get(&fd).cloned()) {
+            log!(LOG_REDIRECT, "{}: fstat on fake file {} (fd {})", hook_tag(), path, fd);
+            spoof_stat(ret, &path, buf);
+        }
+
+        ret
+    }
+}
+
+// fstat64
+#[cfg(target_env = "gnu")]
+redhook::hook! {
+    unsafe fn fstat64(fd: c_int, buf: *mut libc::stat64) -> c_int => my_fstat64 {
+        let _hook = enter_hook("fstat64");
+        let ret = redhook::real!(fstat64)(fd, buf);
+        if let Some(path) = FAKE_FDS.lock().ok().and_then(|fds| fds.get(&fd).cloned()) {
+            log!(LOG_REDIRECT, "{}: fstat on fake file {} (fd {})", hook_tag(), path, fd);
+            spoof_stat(ret, &path, buf);
+        }
+
+        ret
+    }
+}
+
+// fclose
+redhook::hook! {
+    unsafe fn fclose(stream: *mut FILE) -> c_int => my_fclose {
+        let _hook = enter_hook("fclose");
+        // streams which weren't faked are simply forwarded
+        let removed = FAKE_STREAMS.lock().ok().and_then(|mut streams| {
+            streams.remove(&(stream as usize)).map(|path| (path, streams.len()))
+        });
+
+        // libc closes the descriptor itself, without going through the `close` hook
+        if let Some((path, remaining)) = removed {
+            log!(LOG_REDIRECT, "{}: fclose on fake stream {} ({} still open)", hook_tag(), path, remaining);
+            let fd = libc::fileno(stream);
+            if let Ok(mut fds) = FAKE_FDS.lock() {
+                fds.remove(&fd);
+            }
+
+            // the stream's buffer is only written out by closing it, but should be counted too
+            libc::fflush(stream);
+            closing_written(fd);
+        }
+
+        redhook::real!(fclose)(stream)
+    }
+}
+
+// close
+redhook::hook! {
+    unsafe fn close(fd: c_int) -> c_int => my_close {
+        if let Ok(mut fds) = FAKE_FDS.lock() {
+            fds.remove(&fd);
+        }
+
+        closing_written(fd);
+        redhook::real!(close)(fd)
+    }
+}
+
+// execve
+redhook::hook! {
+    unsafe fn execve(path: *const c_char, argv: *const *const c_char, envp: *const *const c_char) -> c_int => my_execve {
+        let _hook = enter_hook("execve");
+        let vars = preload_env(envp);
+        let pointers = env_pointers(&vars);
+        let envp = pointers.as_ptr();
+        let real = redhook::real!(execve);
+        match decide_exec(path) {
+            Decision::Redirect(c_str) => real(c_str.as_ptr(), argv, envp),
+            Decision::Passthrough => real(path, argv, envp),
+            Decision::Deny(errno) => deny(errno),
+        }
+    }
+}
+
+// execveat
+#[cfg(target_os = "linux")]
+redhook::hook! {
+    unsafe fn execveat(dirfd: c_int, path: *const c_char, argv: *const *const c_char, envp: *const *const c_char, flags: c_int) -> c_int => my_execveat {
+        let _hook = enter_hook("execveat");
+        let vars = preload_env(envp);
+        let pointers = env_pointers(&vars);
+        let envp = pointers.as_ptr();
+
+        // an empty path executes `dirfd` itself, and other relative paths are resolved by the
+        // kernel within `dirfd`
+        if path.is_null() || *path == 0 || (dirfd != libc::AT_FDCWD && *path != b'/' as c_char) {
+            return redhook::real!(execveat)(dirfd, path, argv, envp, flags);
+        }
+
+        let real = redhook::real!(execveat);
+        match decide_exec(path) {
+            Decision::Redirect(c_str) => real(dirfd, c_str.as_ptr(), argv, envp, flags),
+            Decision::Passthrough => real(dirfd, path, argv, envp, flags),
+            Decision::Deny(errno) => deny(errno),
+        }
+    }
+}
+
+// chdir
+redhook::hook! {
+    unsafe fn chdir(path: *const c_char) -> c_int => my_chdir {
+        let _hook = enter_hook("chdir");
+        let real = redhook::real!(chdir);
+        let (ret, redirected) = match decide(Hook::Dir, CStr::from_ptr(path), None) {
+            Decision::Redirect(c_str) => (real(c_str.as_ptr()), true),
+            Decision::Passthrough => (real(path), false),
+            Decision::Deny(errno) => (deny(errno), false),
+        };
+
+        // keep track of where the program thinks it is, so `getcwd` and relative paths agree
+        if ret == 0 {
+            let cwd = if redirected {
+                get_logical_path(options(), &CStr::from_ptr(path).to_string_lossy())
+            } else {
+                None
+            };
+            if let Ok(mut fakeroot_cwd) = FAKEROOT_CWD.lock() {
+                *fakeroot_cwd = cwd;
+            }
+        }
+
+        ret
+    }
+}
+
+// fchdir
+redhook::hook! {
+    unsafe fn fchdir(fd: c_int) -> c_int => my_fchdir {
+        let ret = redhook::real!(fchdir)(fd);
+        if ret == 0 {
+            let cwd = FAKE_FDS.lock().ok().and_then(|fds| fds.get(&fd).cloned());
+            if let Ok(mut fakeroot_cwd) = FAKEROOT_CWD.lock() {
+                *fakeroot_cwd = cwd.and_then(|path| get_logical_path(options(), &path));
+            }
+        }
+
+        ret
+    }
+}
+
+// getcwd
+redhook::hook! {
+    unsafe fn getcwd(buf: *mut c_char, size: size_t) -> *mut c_char => my_getcwd {
+        let cwd = match FAKEROOT_CWD.lock().ok().and_then(|cwd| cwd.clone()) {
+            Some(cwd) => CString::new(cwd.as_os_str().as_bytes()).unwrap(),
+            None => return redhook::real!(getcwd)(buf, size),
+        };
+
+        // like glibc, allocate a buffer if one wasn't given
+        let len = cwd.as_bytes_with_nul().len();
+        let buf = if buf.is_null() {
+            libc::malloc(len.max(size)).cast::<c_char>()
+        } else if size < len {
+            *errno_location() = libc::ERANGE;
+            return ptr::null_mut();
+        } else {
+            buf
+        };
+
+        if !buf.is_null() {
+            ptr::copy_nonoverlapping(cwd.as_ptr(), buf, len);
+        }
+
+        buf
+    }
+}
+
+// realpath
+redhook::hook! {
+    unsafe fn realpath(path: *const c_char, resolved: *mut c_char) -> *mut c_char => my_realpath {
+        let _hook = enter_hook("realpath");
+        let real = redhook::real!(realpath);
+        match decide(Hook::File, CStr::from_ptr(path), None) {
+            Decision::Redirect(c_str) => {
+                // let libc allocate the canonical path: the fake path may be longer than the real
+                // one, and the caller only sized `resolved` for the real one
+                let canonical = real(c_str.as_ptr(), ptr::null_mut());
+
+                // without a buffer the caller will `free` the result, which is fine since libc
+                // allocated it
+                if canonical.is_null() || resolved.is_null() {
+                    return canonical;
+                }
+
+                // refuse to overflow the caller's `PATH_MAX` buffer
+                let len = CStr::from_ptr(canonical).to_bytes().len();
+                if len >= PATH_MAX as usize {
+                    log!(LOG_ERROR, "{}: fake path too long: {}", hook_tag(), len);
+                    libc::free(canonical.cast());
+                    *errno_location() = libc::ENAMETOOLONG;
+                    return ptr::null_mut();
+                }
+
+                ptr::copy_nonoverlapping(canonical, resolved, len + 1);
+                libc::free(canonical.cast());
+                resolved
+            }
+            Decision::Passthrough => real(path, resolved),
+            Decision::Deny(errno) => deny(errno),
+        }
+    }
+}
+
+// getpwnam
+#[cfg(target_os = "linux")]
+redhook::hook! {
+    unsafe fn getpwnam(name: *const c_char) -> *mut libc::passwd => my_getpwnam {
+        let _hook = enter_hook("getpwnam");
+        let name = CStr::from_ptr(name);
+        find_entry(c"/etc/passwd", fgetpwent, |pw| CStr::from_ptr(pw.pw_name) == name)
+            .unwrap_or_else(|| redhook::real!(getpwnam)(name.as_ptr()))
+    }
+}
+
+// getpwnam_r
+#[cfg(target_os = "linux")]
+redhook::hook! {
+    unsafe fn getpwnam_r(name: *const c_char, pwd: *mut libc::passwd, buf: *mut c_char, buflen: size_t, result: *mut *mut libc::passwd) -> c_int => my_getpwnam_r {
+        let _hook = enter_hook("getpwnam_r");
+        let name = CStr::from_ptr(name);
+        find_entry_r(c"/etc/passwd", (pwd, buf, buflen, result), fgetpwent_r, |pw| CStr::from_ptr(pw.pw_name) == name)
+            .unwrap_or_else(|| redhook::real!(getpwnam_r)(name.as_ptr(), pwd, buf, buflen, result))
+    }
+}
+
+// getpwuid
+#[cfg(target_os = "linux")]
+redhook::hook! {
+    unsafe fn getpwuid(uid: libc::uid_t) -> *mut libc::passwd => my_getpwuid {
+        let _hook = enter_hook("getpwuid");
+        find_entry(c"/etc/passwd", fgetpwent, |pw| pw.pw_uid == uid)
+            .unwrap_or_else(|| redhook::real!(getpwuid)(uid))
+    }
+}
+
+// getpwuid_r
+#[cfg(target_os = "linux")]
+redhook::hook! {
+    unsafe fn getpwuid_r(uid: libc::uid_t, pwd: *mut libc::passwd, buf: *mut c_char, buflen: size_t, result: *mut *mut libc::passwd) -> c_int => my_getpwuid_r {
+        let _hook = enter_hook("getpwuid_r");
+        find_entry_r(c"/etc/passwd", (pwd, buf, buflen, result), fgetpwent_r, |pw| pw.pw_uid == uid)
+            .unwrap_or_else(|| redhook::real!(getpwuid_r)(uid, pwd, buf, buflen, result))
+    }
+}
+
+// getgrnam
+#[cfg(target_os = "linux")]
+redhook::hook! {
+    unsafe fn getgrnam(name: *const c_char) -> *mut libc::group => my_getgrnam {
+        let _hook = enter_hook("getgrnam");
+        let name = CStr::from_ptr(name);
+        find_entry(c"/etc/group", fgetgrent, |gr| CStr::from_ptr(gr.gr_name) == name)
+            .unwrap_or_else(|| redhook::real!(getgrnam)(name.as_ptr()))
+    }
+}
+
+// getgrnam_r
+#[cfg(target_os = "linux")]
+redhook::hook! {
+    unsafe fn getgrnam_r(name: *const c_char, grp: *mut libc::group, buf: *mut c_char, buflen: size_t, result: *mut *mut libc::group) -> c_int => my_getgrnam_r {
+        let _hook = enter_hook("getgrnam_r");
+        let name = CStr::from_ptr(name);
+        find_entry_r(c"/etc/group", (grp, buf, buflen, result), fgetgrent_r, |gr| CStr::from_ptr(gr.gr_name) == name)
+            .unwrap_or_else(|| redhook::real!(getgrnam_r)(name.as_ptr(), grp, buf, buflen, result))
+    }
+}
+
+// getgrgid
+#[cfg(target_os = "linux")]
+redhook::hook! {
+    unsafe fn getgrgid(gid: libc::gid_t) -> *mut libc::group => my_getgrgid {
+        let _hook = enter_hook("getgrgid");
+        find_entry(c"/etc/group", fgetgrent, |gr| gr.gr_gid == gid)
+            .unwrap_or_else(|| redhook::real!(getgrgid)(gid))
+    }
+}
+
+// getgrgid_r
+#[cfg(target_os = "linux")]
+redhook::hook! {
+    unsafe fn getgrgid_r(gid: libc::gid_t, grp: *mut libc::group, buf: *mut c_char, buflen: size_t, result: *mut *mut libc::group) -> c_int => my_getgrgid_r {
+        let _hook = enter_hook("getgrgid_r");
+        find_entry_r(c"/etc/group", (grp, buf, buflen, result), fgetgrent_r, |gr| gr.gr_gid == gid)
+            .unwrap_or_else(|| redhook::real!(getgrgid_r)(gid, grp, buf, buflen, result))
+    }
+}
+
+// getspnam
+#[cfg(target_os = "linux")]
+redhook::hook! {
+    unsafe fn getspnam(name: *const c_char) -> *mut libc::spwd => my_getspnam {
+        let _hook = enter_hook("getspnam");
+        let name = CStr::from_ptr(name);
+        find_entry(c"/etc/shadow", fgetspent, |sp| CStr::from_ptr(sp.sp_namp) == name)
+            .unwrap_or_else(|| redhook::real!(getspnam)(name.as_ptr()))
+    }
+}
+
+// _exit
+redhook::hook! {
+    unsafe fn _exit(status: c_int) => my_exit {
+        // `atexit` handlers are skipped, but the report and state should still be written
+        write_report();
+        write_state();
+        redhook::real!(_exit)(status)
+    }
+}
+
+// c api -----------------------------------------------------------------------
+
+/// Configure the hooks without environment variables, for programs which link against this library
+/// rather than loading it via `LD_PRELOAD`. `flags` is a combination of the `FAKEROOT_FLAG_*`
+/// constants.
+///
+/// This must be called before any hooked function is, since the options can only be set once.
+/// Returns `0` on success, or `-1` with `errno` set to `EINVAL` if the root isn't usable, or to
+/// `EBUSY` if the options were already set.
+///
+/// # Safety
+///
+/// `root` must be a valid nul-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn fakeroot_init(root: *const c_char, flags: u32) -> c_int {
+    if root.is_null() {
+        *errno_location() = libc::EINVAL;
+        return -1;
+    }
+
+    let root = PathBuf::from(OsStr::from_bytes(CStr::from_ptr(root).to_bytes()));
+    let root = match bypass(|| check_fake_root(root)) {
+        Ok(root) => root,
+        Err(e) => {
+            log!(LOG_ERROR, "{}: {}", hook_tag(), e);
+            *errno_location() = libc::EINVAL;
+            return -1;
+        }
+    };
+
+    let settings = Settings::load();
+    if settings.is_enabled(ENV_FAKEROOT_PROPAGATE) {
+        propagate(&root);
+    }
+
+    let options = Options {
+        root: Ok(root),
+        dirs: flags & FAKEROOT_FLAG_DIRS != 0,
+        exact: flags & FAKEROOT_FLAG_EXACT != 0,
+        all: flags & FAKEROOT_FLAG_ALL != 0,
+        relative: flags & FAKEROOT_FLAG_RELATIVE != 0,
+        follow: flags & FAKEROOT_FLAG_NO_FOLLOW == 0,
+        cow: flags & FAKEROOT_FLAG_COW != 0,
+        copy_on_open: flags & FAKEROOT_FLAG_COPY_ON_OPEN != 0,
+        order: if flags & FAKEROOT_FLAG_REAL_FIRST != 0 {
+            Order::RealFirst
+        } else {
+            Order::FakeFirst
+        },
+        proc: flags & FAKEROOT_FLAG_PROC != 0,
+        whiteout: flags & FAKEROOT_FLAG_WHITEOUT != 0,
+        spoof_inode: flags & FAKEROOT_FLAG_SPOOF_INODE != 0,
+        gzip: flags & FAKEROOT_FLAG_GZIP != 0,
+        synth_stat: flags & FAKEROOT_FLAG_SYNTH_STAT != 0,
+        create_parents: flags & FAKEROOT_FLAG_NO_CREATE_PARENTS == 0,
+        strip_suid: flags & FAKEROOT_FLAG_STRIP_SUID != 0,
+        on_error: if flags & FAKEROOT_FLAG_FAIL_ON_ERROR != 0 {
+            OnError::Fail
+        } else {
+            OnError::Passthrough
+        },
+        quiet: flags & FAKEROOT_FLAG_QUIET != 0,
+        strict_sys: flags & FAKEROOT_FLAG_STRICT_SYS != 0,
+        confine: flags & FAKEROOT_FLAG_CONFINE != 0,
+        // everything else is read as usual, except the fake root from the environment: this one was
+        // propagated first, so it can't replace it
+        ..bypass(|| Options::from_settings(&settings))
+    };
+
+    match FAKEROOT_OPTIONS.set(options) {
+        Ok(()) => 0,
+        Err(_) => {
+            *errno_location() = libc::EBUSY;
+            -1
+        }
+    }
+}
+
+/// Resolve `path` the same way the hooks do, and write the path they would use into `resolved`.
+/// Returns `1` if the path was redirected into the fake root, `0` if it passes through unchanged,
+/// or `-1` with `errno` set to `ERANGE` if `resolved` is too small, or to `EINVAL` if it couldn't
+/// be resolved.
+///
+/// # Safety
+///
+/// `path` must be a valid nul-terminated string, and `resolved` must point to at least `size`
+/// writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn fakeroot_resolve(
+    path: *const c_char,
+    resolved: *mut c_char,
+    size: size_t,
+) -> c_int {
+    if path.is_null() || resolved.is_null() {
+        *errno_location() = libc::EINVAL;
+        return -1;
+    }
+
+    let path = CStr::from_ptr(path);
+    let (c_str, ret) = match get_fake_path(path) {
+        Ok(Some(c_str)) => (c_str, 1),
+        Ok(None) => (path.to_owned(), 0),
+        Err(e) => {
+            log!(LOG_ERROR, "{}: {}", hook_tag(), e);
+            let errno = e
+                .downcast_ref::<Denied>()
+                .map_or(libc::EINVAL, |denied| denied.errno);
+            return deny(errno);
+        }
+    };
+
+    let bytes = c_str.as_bytes_with_nul();
+    if bytes.len() > size {
+        *errno_location() = libc::ERANGE;
+        return -1;
+    }
+
+    ptr::copy_nonoverlapping(bytes.as_ptr().cast(), resolved, bytes.len());
+    ret
+}
+
+/// The names of the functions this library hooks, which depend on the platform it was built for.
+pub fn available_hooks() -> &'static [&'static str] {
+    static NAMES: OnceLock<Vec<&str>> = OnceLock::new();
+    NAMES.get_or_init(|| HOOKS.iter().filter_map(|hook| hook.to_str().ok()).collect())
+}
+
+/// Return the name of the hooked function at `index` in `available_hooks`, as a nul-terminated
+/// string which mustn't be freed, or null once `index` is past the end of the list.
+#[no_mangle]
+pub extern "C" fn fakeroot_hook_name(index: size_t) -> *const c_char {
+    HOOKS.get(index).map_or(ptr::null(), |hook| hook.as_ptr())
+}
+
+/// Return the version of this library, as a nul-terminated string which mustn't be freed.
+#[no_mangle]
+pub extern "C" fn fakeroot_version() -> *const c_char {
+    VERSION.as_ptr().cast()
+}
+
+// tests -----------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        alloc::{GlobalAlloc, Layout, System},
+        env, fs,
+        path::{Path, PathBuf},
+        process::{self, Command},
+    };
+
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    use super::*;
+
+    /// Counts the allocations made on each thread, so tests can check how many the hooks make.
+    struct CountingAlloc;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for CountingAlloc {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+            System.realloc(ptr, layout, new_size)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAlloc = CountingAlloc;
+
+    #[test]
+    fn test_is_enabled() {
+        let test_var = "test_var";
+
+        env::remove_var(test_var);
+        assert!(!is_enabled(test_var));
+
+        env::set_var(test_var, "false");
+        assert!(!is_enabled(test_var));
+
+        env::set_var(test_var, "0");
+        assert!(!is_enabled(test_var));
+
+        env::set_var(test_var, "true");
+        assert!(is_enabled(test_var));
+
+        env::set_var(test_var, "1");
+        assert!(is_enabled(test_var));
+
+        env::set_var(test_var, "anything");
+        assert!(is_enabled(test_var));
+    }
+
+    #[test]
+    fn test_fopen_flags() {
+        let flags = |mode: &str| fopen_flags(&CString::new(mode).unwrap());
+
+        assert_eq!(flags("r"), libc::O_RDONLY);
+        assert_eq!(flags("rb+"), libc::O_RDWR);
+        assert_eq!(flags("w"), libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC);
+        assert_eq!(
+            flags("wx"),
+            libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC | libc::O_EXCL
+        );
+        assert_eq!(
+            flags("a+e"),
+            libc::O_RDWR | libc::O_CREAT | libc::O_APPEND | libc::O_CLOEXEC
+        );
+        assert_eq!(flags("rx"), libc::O_RDONLY);
+        assert_eq!(
+            flags("w,ccs=UTF-8x"),
+            libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC
+        );
+        assert_eq!(flags("q"), -1);
+    }
+
+    #[test]
+    fn test_parse_env_file() {
+        let vars = parse_env_file(
+            "# comment\n\nFAKEROOT=/tmp/a\nexport FAKEROOT_DIRS=1\n FAKEROOT_SEP = \"; \"\n",
+        )
+        .unwrap();
+        assert_eq!(vars.get("FAKEROOT").unwrap(), "/tmp/a");
+        assert_eq!(vars.get("FAKEROOT_DIRS").unwrap(), "1");
+        assert_eq!(vars.get("FAKEROOT_SEP").unwrap(), "; ");
+
+        assert!(parse_env_file("FAKEROOT").is_err());
+    }
+
+    #[test]
+    fn test_apply_diff() {
+        let diff = "diff --git a/etc/conf b/etc/conf
+--- a/etc/conf
++++ b/etc/conf
+@@ -1,3 +1,3 @@
+ one
+-two
++TWO
+ three
+@@ -5 +5,2 @@
+-five
+\\ No newline at end of file
++FIVE
++six
+\\ No newline at end of file
+--- /dev/null
++++ /etc/new	2024-01-01 00:00:00
+@@ -0,0 +1 @@
++new
+";
+        let patches = parse_diff(diff).unwrap();
+        assert_eq!(
+            patches,
+            vec![
+                (
+                    PathBuf::from("/etc/conf"),
+                    vec![
+                        Hunk {
+                            start: 0,
+                            lines: vec![
+                                " one\n".into(),
+                                "-two\n".into(),
+                                "+TWO\n".into(),
+                                " three\n".into()
+                            ]
+                        },
+                        Hunk {
+                            start: 4,
+                            lines: vec!["-five".into(), "+FIVE\n".into(), "+six".into()]
+                        }
+                    ]
+                ),
+                (
+                    PathBuf::from("/etc/new"),
+                    vec![Hunk {
+                        start: 0,
+                        lines: vec!["+new\n".into()]
+                    }]
+                )
+            ]
+        );
+
+        let (_, hunks) = &patches[0];
+        assert_eq!(
+            apply_hunks("one\ntwo\nthree\nfour\nfive", hunks).unwrap(),
+            "one\nTWO\nthree\nfour\nFIVE\nsix"
+        );
+        assert!(apply_hunks("one\n2\nthree\nfour\nfive", hunks).is_err());
+        assert!(apply_hunks("one\ntwo\n", hunks).is_err());
+        assert_eq!(apply_hunks("", &patches[1].1).unwrap(), "new\n");
+
+        assert!(parse_diff("+++ b/etc/conf\n@@ -1 +1 @@\n").is_err());
+        assert!(parse_diff("@@ -1 +1 @@\n-a\n+b\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_state() {
+        let inodes = BTreeMap::from([(PathBuf::from("/etc/a b"), 1), (PathBuf::from("/etc/c"), 2)]);
+        let owners = BTreeMap::from([
+            (PathBuf::from("/etc/a b"), (Some(0), None)),
+            (PathBuf::from("/etc/d"), (Some(1), Some(2))),
+        ]);
+        let state = format_state(&inodes, &owners);
+        assert_eq!(
+            state,
+            "1\t0\t-\t/etc/a b\n2\t-\t-\t/etc/c\n-\t1\t2\t/etc/d\n"
+        );
+        assert_eq!(parse_state(&state), (inodes, owners));
+
+        assert_eq!(parse_state("junk\n"), (BTreeMap::new(), BTreeMap::new()));
+    }
+
+    #[test]
+    fn test_parse_config() {
+        let config =
+            parse_config("# comment\n\nroot = \"/tmp/a # b\"\nall=true # trailing\n  dirs = 0\n")
+                .unwrap();
+        assert_eq!(config.get("root").unwrap(), "/tmp/a # b");
+        assert_eq!(config.get("all").unwrap(), "true");
+        assert_eq!(config.get("dirs").unwrap(), "0");
+
+        assert!(parse_config("all").is_err());
+        assert!(parse_config("root = \"/tmp").is_err());
+    }
+
+    // NOTE: this requires that `cargo build` be run before the tests are run
+    // - is there a way to use one that's built when the tests are built?
+    fn get_so() -> PathBuf {
+        env::current_exe() // target/debug/deps/<file>
+            .unwrap()
+            .parent() // target/debug/deps
+            .unwrap()
+            .parent() // target/debug
+            .unwrap()
+            .join(format!(
+                "{}fakeroot{}",
+                env::consts::DLL_PREFIX,
+                env::consts::DLL_SUFFIX
+            ))
+    }
+
+    /// Compiles a small C program into `dir`, for tests which need to call libc directly
+    fn compile(dir: &Path, name: &str, src: &str, args: &[&str]) -> PathBuf {
+        let src_path = dir.join(format!("{}.c", name));
+        let bin_path = dir.join(name);
+        fs::write(&src_path, src).unwrap();
+
+        let output = Command::new("cc")
+            .arg("-o")
+            .arg(&bin_path)
+            .arg(&src_path)
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "failed to compile {}:\n{}",
+            name,
+            String::from_utf8_lossy(&output.stderr)
+        );
+
+        bin_path
+    }
+
+    macro_rules! cat {
+        ($p:expr) => {
+            fs::read_to_string($p).unwrap()
+        };
+    }
+
+    macro_rules! cmd {
+        (
+            $fake_root:expr,
+            $cmd:expr
+            $(, all = $all:literal)?
+            $(, dirs = $dirs:literal)?
+            $(, debug = $debug:literal)?
+            $(, env = [$(($key:expr, $val:expr)),* $(,)?])?
+            $(,)?
+        ) => {{
+            let mut cmd = Command::new("sh");
+            cmd.arg("-c")
+                .arg($cmd)
+                .env("LD_PRELOAD", get_so().display().to_string())
+                .env(ENV_FAKEROOT, $fake_root);
+
+            $(
+                if $all {
+                    cmd.env(ENV_FAKEROOT_ALL, "1");
+                }
+            )?
+
+            $(
+                if $dirs {
+                    cmd.env(ENV_FAKEROOT_DIRS, "1");
+                }
+            )?
+
+            $(
+                if $debug {
+                    cmd.env(ENV_FAKEROOT_DEBUG, "1");
+                }
+            )?
+
+            $($(
+                cmd.env($key, $val);
+            )*)?
+
+            let output = cmd.output()
+                .unwrap();
+
+            let success = output.status.success();
+            if !success {
+                assert!(
+                    false,
+                    "\"{}\" -> {}\n{}",
+                    $cmd,
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                );
+            }
+
+            output
+        }};
+    }
+
+    // TODO: include doc comments so can add #[should_panic] on top
+    macro_rules! test {
+        ($(#[$($attr:tt)+] )? $name:ident, $f:expr) => {
+            #[test]
+            $(#[$($attr)+])?
+            fn $name() {
+                let tmp_dir = env::temp_dir().join(format!(
+                    "fakehook-{}-{}",
+                    stringify!($name),
+                    process::id()
+                ));
+                std::fs::create_dir_all(&tmp_dir).unwrap();
+                $f(&tmp_dir);
+                std::fs::remove_dir_all(&tmp_dir).unwrap();
+            }
+        };
+    }
+
+    test!(resolve_allocations, |dir: &Path| {
+        fs::create_dir_all(dir.join("etc")).unwrap();
+        fs::write(dir.join("etc/hosts"), "").unwrap();
+
+        let options = Options {
+            root: Ok(dir.to_path_buf()),
+            ..Options::from_env()
+        };
+        let allocations = |path: &CStr| {
+            // the first call sets up the reused buffer
+            find_fake_path(&options, path, false).unwrap();
+            let before = ALLOCATIONS.get();
+            for _ in 0..100 {
+                find_fake_path(&options, path, false).unwrap();
+            }
+            (ALLOCATIONS.get() - before) / 100
+        };
+
+        // the fake path is only allocated if it's redirected to, leaving the logical path (and
+        // whatever the standard library needs to look at the file) as the only other allocations
+        let passthrough = allocations(c"/etc/fstab");
+        assert!(passthrough <= 2, "{} allocations per resolve", passthrough);
+        assert_eq!(allocations(c"/etc/hosts"), passthrough + 1);
+    });
+
+    test!(decide, |dir: &Path| {
+        fs::create_dir_all(dir.join("etc")).unwrap();
+        fs::write(dir.join("etc/hosts"), "").unwrap();
+        fs::write(dir.join("etc/.wh.group"), "").unwrap();
+        std::os::unix::fs::symlink("/etc/passwd", dir.join("etc/passwd")).unwrap();
+
+        let options = |update: fn(&mut Options)| {
+            let mut options = Options {
+                root: Ok(dir.to_path_buf()),
+                ..Options::from_env()
+            };
+            update(&mut options);
+            options
+        };
+        let decide = |options: &Options, hook: Hook, path: &str| {
+            decide_with(options, hook, &CString::new(path).unwrap(), None)
+        };
+        let redirect = |path: &str| {
+            Decision::Redirect(CString::new(format!("{}{}", dir.display(), path)).unwrap())
+        };
+
+        let defaults = options(|_| {});
+        assert_eq!(
+            decide(&defaults, Hook::File, "/etc/hosts"),
+            redirect("/etc/hosts")
+        );
+        assert_eq!(
+            decide(&defaults, Hook::File, "/etc/fstab"),
+            Decision::Passthrough
+        );
+        assert_eq!(
+            decide(&defaults, Hook::File, "etc/hosts"),
+            Decision::Passthrough
+        );
+        assert_eq!(
+            decide(&defaults, Hook::File, "/proc/self"),
+            Decision::Passthrough
+        );
+        assert_eq!(
+            decide(&defaults, Hook::File, "/etc/group"),
+            Decision::Passthrough
+        );
+        assert_eq!(decide(&defaults, Hook::Dir, "/etc"), Decision::Passthrough);
+
+        let dirs = options(|options| options.dirs = true);
+        assert_eq!(decide(&dirs, Hook::Dir, "/etc"), redirect("/etc"));
+
+        let all = options(|options| options.all = true);
+        assert_eq!(
+            decide(&all, Hook::File, "/etc/fstab"),
+            redirect("/etc/fstab")
+        );
+
+        let real_first = options(|options| options.order = Order::RealFirst);
+        assert_eq!(
+            decide(&real_first, Hook::File, "/etc/hosts"),
+            Decision::Passthrough
+        );
+
+        let whiteout = options(|options| options.whiteout = true);
+        assert_eq!(
+            decide(&whiteout, Hook::File, "/etc/group"),
+            Decision::Deny(libc::ENOENT)
+        );
+
+        let no_follow = options(|options| options.follow = false);
+        assert_eq!(
+            decide(&no_follow, Hook::File, "/etc/passwd"),
+            Decision::Deny(libc::EACCES)
+        );
+
+        let misconfigured = options(|options| options.root = Err("misconfigured".into()));
+        assert_eq!(
+            decide(&misconfigured, Hook::File, "/etc/hosts"),
+            Decision::Passthrough
+        );
+
+        let fail = options(|options| {
+            options.root = Err("misconfigured".into());
+            options.on_error = OnError::Fail;
+        });
+        assert_eq!(
+            decide(&fail, Hook::File, "/etc/hosts"),
+            Decision::Deny(libc::EINVAL)
+        );
+    });
+
+    test!(simple, |dir: &Path| {
+        let fake_etc = dir.join("etc");
+        fs::create_dir_all(&fake_etc).unwrap();
+        fs::write(fake_etc.join("hosts"), "🎉").unwrap();
+
+        // check hook worked
+        let output = cmd!(&dir, "cat /etc/hosts");
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "🎉");
+
+        // check other files aren't hooked
+        let output = cmd!(&dir, "cat /etc/passwd", debug = true);
+        assert_eq!(output.stdout, fs::read("/etc/passwd").unwrap());
+    });
+    test!(config, |dir: &Path| {
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        fs::write(fake_root.join("etc/hosts"), "from config").unwrap();
+        fs::write(
+            dir.join("fakeroot.toml"),
+            format!(
+                "# fakeroot options\nroot = \"{}\"\norder = fake-first # comment\n",
+                fake_root.display()
+            ),
+        )
+        .unwrap();
+
+        let mut cmd = Command::new("sh");
+        let output = cmd
+            .arg("-c")
+            .arg("cat /etc/hosts")
+            .env("LD_PRELOAD", get_so().display().to_string())
+            .env(ENV_FAKEROOT_CONFIG, dir.join("fakeroot.toml"))
+            .output()
+            .unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "from config");
+
+        // environment variables take precedence
+        let output = cmd!(
+            dir,
+            "cat /etc/hosts",
+            env = [(ENV_FAKEROOT_CONFIG, dir.join("fakeroot.toml"))]
+        );
+        assert_ne!(String::from_utf8_lossy(&output.stdout), "from config");
+
+        // the files written about what was faked can be set too
+        let (touch_log, report) = (dir.join("touched"), dir.join("report.json"));
+        fs::write(
+            dir.join("output.toml"),
+            format!(
+                "root = \"{}\"\nall = 1\ntouch_log = \"{}\"\nreport = \"{}\"\n",
+                fake_root.display(),
+                touch_log.display(),
+                report.display()
+            ),
+        )
+        .unwrap();
+        Command::new("sh")
+            .arg("-c")
+            .arg("echo new > /etc/new")
+            .env("LD_PRELOAD", get_so().display().to_string())
+            .env(ENV_FAKEROOT_CONFIG, dir.join("output.toml"))
+            .output()
+            .unwrap();
+        assert_eq!(cat!(fake_root.join("etc/new")), "new\n");
+        assert_eq!(cat!(&touch_log), "/etc/new\n");
+        assert!(cat!(&report).contains(r#"{"path": "/etc/new", "action": "redirect""#));
+    });
+
+    test!(mount_at, |dir: &Path| {
+        fs::write(dir.join("hosts"), "mounted").unwrap();
+        fs::create_dir_all(dir.join("usr")).unwrap();
+        fs::write(dir.join("usr/hosts"), "not mounted").unwrap();
+
+        let output = cmd!(
+            dir,
+            "cat /etc/hosts; cat /usr/hosts 2>&1 || true",
+            env = [(ENV_FAKEROOT_MOUNT_AT, "/etc")]
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "mountedcat: /usr/hosts: No such file or directory\n"
+        );
+    });
+
+    test!(propagate, |dir: &Path| {
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        fs::write(fake_root.join("etc/hosts"), "propagated").unwrap();
+        std::os::unix::fs::symlink(&fake_root, dir.join("link")).unwrap();
+
+        // the shell resolves the root when it opens `/etc/hosts`, before changing directory
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg("exec 3< /etc/hosts && cd / && cat /etc/hosts && printenv FAKEROOT")
+            .current_dir(dir)
+            .env("LD_PRELOAD", get_so().display().to_string())
+            .env(ENV_FAKEROOT, "link")
+            .env(ENV_FAKEROOT_PROPAGATE, "1")
+            .output()
+            .unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            format!(
+                "propagated{}\n",
+                fake_root.canonicalize().unwrap().display()
+            )
+        );
+    });
+
+    test!(env_file, |dir: &Path| {
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        fs::write(fake_root.join("etc/hosts"), "from env file").unwrap();
+        fs::write(
+            dir.join("fakeroot.env"),
+            format!(
+                "# fakeroot options\nFAKEROOT=\"{}\"\nexport FAKEROOT_ORDER=fake-first\n",
+                fake_root.display()
+            ),
+        )
+        .unwrap();
+
+        let output = Command::new("cat")
+            .arg("/etc/hosts")
+            .env("LD_PRELOAD", get_so().display().to_string())
+            .env(ENV_FAKEROOT_ENV_FILE, dir.join("fakeroot.env"))
+            .output()
+            .unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "from env file");
+
+        // environment variables take precedence
+        let output = cmd!(
+            dir,
+            "cat /etc/hosts",
+            env = [(ENV_FAKEROOT_ENV_FILE, dir.join("fakeroot.env"))]
+        );
+        assert_ne!(String::from_utf8_lossy(&output.stdout), "from env file");
+    });
+
+    test!(debug, |dir: &Path| {
+        let fake_etc = dir.join("etc");
+        fs::create_dir_all(&fake_etc).unwrap();
+        fs::write(fake_etc.join("hosts"), "🎉").unwrap();
+
+        // this checks ENV_DEBUG behaviour, so ensure it's not set
+        assert!(
+            env::var(ENV_FAKEROOT_DEBUG).is_err(),
+            "DEBUG must not be defined during tests"
+        );
+
+        // should be no logs
+        let output = cmd!(&dir, "cat /etc/hosts");
+        assert_eq!(String::from_utf8_lossy(&output.stderr), "");
+
+        // should be logs
+        let output = cmd!(&dir, "cat /etc/passwd", debug = true);
+        assert!(String::from_utf8_lossy(&output.stderr)
+            .contains("@HOOK@: not in fake root: /etc/passwd"));
+    });
+
+    test!(debug_levels, |dir: &Path| {
+        let fake_etc = dir.join("etc");
+        fs::create_dir_all(&fake_etc).unwrap();
+        fs::write(fake_etc.join("hosts"), "🎉").unwrap();
+
+        // errors only
+        let output = cmd!(
+            &dir,
+            "cat /etc/hosts /etc/passwd",
+            env = [(ENV_FAKEROOT_DEBUG, "2")]
+        );
+        assert_eq!(String::from_utf8_lossy(&output.stderr), "");
+
+        let output = cmd!(
+            dir.join("missing"),
+            "cat /etc/hosts",
+            env = [(ENV_FAKEROOT_DEBUG, "2")]
+        );
+        assert!(String::from_utf8_lossy(&output.stderr)
+            .contains("@HOOK@: FAKEROOT does not exist on disk"));
+
+        // a misconfigured root is only reported once per process, and everything passes through
+        let output = Command::new("cat")
+            .args(["/etc/hosts", "/etc/passwd", "/etc/group", "/etc/hosts"])
+            .env("LD_PRELOAD", get_so().display().to_string())
+            .env(ENV_FAKEROOT, dir.join("missing"))
+            .env(ENV_FAKEROOT_DEBUG, "2")
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(stderr.matches("FAKEROOT does not exist on disk").count(), 1);
+        assert!(String::from_utf8_lossy(&output.stdout).contains("root:"));
+
+        // redirects too
+        let output = cmd!(
+            &dir,
+            "cat /etc/hosts /etc/passwd",
+            env = [(ENV_FAKEROOT_DEBUG, "3")]
+        );
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("@HOOK@: /etc/hosts => "));
+        assert!(!stderr.contains("@HOOK@: not in fake root: /etc/passwd"));
+
+        // passthroughs too
+        let output = cmd!(
+            &dir,
+            "cat /etc/hosts /etc/passwd",
+            env = [(ENV_FAKEROOT_DEBUG, "4")]
+        );
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("@HOOK@: /etc/hosts => "));
+        assert!(stderr.contains("@HOOK@: not in fake root: /etc/passwd"));
+    });
+
+    test!(debug_hooks, |dir: &Path| {
+        let bin = compile(
+            dir,
+            "debug_hooks",
+            r#"
+                #include <fcntl.h>
+                #include <sys/stat.h>
+
+                int main(int argc, char **argv) {
+                    struct stat st;
+                    stat(argv[1], &st);
+                    open(argv[2], O_RDONLY);
+                    return 0;
+                }
+            "#,
+            &[],
+        );
+
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        fs::write(fake_root.join("etc/stat"), "").unwrap();
+        fs::write(fake_root.join("etc/open"), "").unwrap();
+
+        let command = format!("{} /etc/stat /etc/open", bin.display());
+        for (hooks, logged, filtered) in [
+            ("open", "/etc/open", "/etc/stat"),
+            ("stat, access", "/etc/stat", "/etc/open"),
+        ] {
+            let output = cmd!(
+                &fake_root,
+                &command,
+                debug = true,
+                env = [(ENV_FAKEROOT_DEBUG_HOOKS, hooks)]
+            );
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            assert!(stderr.contains(&format!("@HOOK@: {} => ", logged)));
+            assert!(!stderr.contains(filtered));
+        }
+    });
+
+    test!(log_rotate, |dir: &Path| {
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        fs::write(fake_root.join("etc/hosts"), "").unwrap();
+
+        let log = dir.join("fakeroot.log");
+        let output = cmd!(
+            &fake_root,
+            "for i in 1 2 3 4 5 6 7 8 9 10; do cat /etc/hosts /etc/passwd > /dev/null; done",
+            debug = true,
+            env = [
+                (ENV_FAKEROOT_LOG, &log),
+                (ENV_FAKEROOT_LOG_MAXSIZE, Path::new("512"))
+            ]
+        );
+        assert_eq!(String::from_utf8_lossy(&output.stderr), "");
+
+        let backup = fs::read_to_string(dir.join("fakeroot.log.1")).unwrap();
+        assert!(backup.contains("@HOOK@: /etc/hosts => "));
+        assert!(backup.len() <= 512);
+        assert!(fs::metadata(&log).unwrap().len() <= 512);
+    });
+
+    #[cfg(target_os = "linux")]
+    test!(debug_fd, |dir: &Path| {
+        use std::os::unix::process::CommandExt;
+
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        fs::write(fake_root.join("etc/hosts"), "").unwrap();
+
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) }, 0);
+        let (read_fd, write_fd) = (fds[0], fds[1]);
+
+        // only the command inherits the write end, so the read end is closed once it exits
+        let output = unsafe {
+            Command::new("sh")
+                .arg("-c")
+                .arg("cat /etc/hosts")
+                .env("LD_PRELOAD", get_so().display().to_string())
+                .env(ENV_FAKEROOT, &fake_root)
+                .env(ENV_FAKEROOT_DEBUG, "1")
+                .env(ENV_FAKEROOT_DEBUG_FD, write_fd.to_string())
+                .pre_exec(move || {
+                    libc::fcntl(write_fd, libc::F_SETFD, 0);
+                    Ok(())
+                })
+                .output()
+                .unwrap()
+        };
+        unsafe { libc::close(write_fd) };
+        let mut log = String::new();
+        unsafe { File::from_raw_fd(read_fd) }
+            .read_to_string(&mut log)
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8_lossy(&output.stderr), "");
+        assert!(log.contains("@HOOK@: /etc/hosts => "), "{}", log);
+
+        // descriptors which aren't open fall back to STDERR
+        let output = cmd!(
+            &fake_root,
+            "cat /etc/hosts",
+            debug = true,
+            env = [(ENV_FAKEROOT_DEBUG_FD, "999")]
+        );
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("@HOOK@: FAKEROOT_DEBUG_FD is not open for writing: 999"));
+        assert!(stderr.contains("@HOOK@: /etc/hosts => "));
+    });
+
+    test!(debug_backtrace, |dir: &Path| {
+        let output = cmd!(
+            dir,
+            "cat /etc/hosts",
+            env = [
+                (ENV_FAKEROOT_DEBUG, "2"),
+                (ENV_FAKEROOT_DEBUG_BACKTRACE, "1"),
+                (ENV_FAKEROOT_MATCH_REGEX, "^/etc/hosts$")
+            ]
+        );
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(stderr.matches("backtrace for").count(), 1, "{}", stderr);
+        assert!(stderr.contains("@HOOK@: backtrace for /etc/hosts:\n"));
+        assert!(stderr.contains("fakeroot::decide"));
+    });
+
+    test!(profile, |dir: &Path| {
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        fs::write(fake_root.join("etc/hosts"), "").unwrap();
+
+        let output = cmd!(
+            &fake_root,
+            "exec cat /etc/hosts /etc/passwd",
+            env = [(ENV_FAKEROOT_PROFILE, "1")]
+        );
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let profile = stderr
+            .lines()
+            .find_map(|line| line.strip_prefix("@HOOK@: profile: open: "))
+            .unwrap_or_else(|| panic!("no profile for open:\n{}", stderr));
+        let total = profile
+            .split(", ")
+            .find_map(|part| part.strip_suffix("ns total"))
+            .unwrap();
+        assert!(profile.starts_with("2 calls"));
+        assert!(total.parse::<u64>().unwrap() > 0);
+    });
+
+    test!(audit_denied, |fake_dir: &Path| {
+        let output = cmd!(
+            &fake_dir,
+            "exec cat /proc/self/comm /etc/hostname",
+            env = [
+                (ENV_FAKEROOT_STRICT_SYS, "1"),
+                (ENV_FAKEROOT_AUDIT_DENIED, "1")
+            ]
+        );
+        assert!(String::from_utf8_lossy(&output.stdout).starts_with("cat\n"));
+        assert!(!String::from_utf8_lossy(&output.stderr).contains("denied"));
+
+        // denials are logged and counted apart from everything else that's looked at
+        let output = cmd!(
+            &fake_dir,
+            format!(
+                "tee /proc/self/comm {} /sys/kernel/profiling < /dev/null; true",
+                fake_dir.join("written").display()
+            ),
+            env = [
+                (ENV_FAKEROOT_STRICT_SYS, "1"),
+                (ENV_FAKEROOT_AUDIT_DENIED, "1"),
+                (ENV_FAKEROOT_PROFILE, "1")
+            ]
+        );
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("@HOOK@: denied: fopen: /proc/self/comm: Read-only file system"));
+        assert!(stderr.contains("@HOOK@: denied: fopen: /sys/kernel/profiling: Read-only"));
+        assert!(!stderr.contains("written"));
+        assert!(fake_dir.join("written").exists());
+        assert!(stderr.contains("@HOOK@: audit: 2 calls denied\n"));
+        assert!(stderr.contains("@HOOK@: profile: denied: 2 calls\n"));
+    });
+
+    test!(report, |dir: &Path| {
+        let bin = compile(
+            dir,
+            "report",
+            r#"
+                #include <fcntl.h>
+                #include <unistd.h>
+
+                int main(int argc, char **argv) {
+                    open(argv[1], O_RDONLY);
+                    _exit(0);
+                }
+            "#,
+            &[],
+        );
+
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        fs::write(fake_root.join("etc/hosts"), "").unwrap();
+        fs::write(fake_root.join("etc/.wh.group"), "").unwrap();
+
+        let report = dir.join("report.json");
+        cmd!(
+            &fake_root,
+            format!(
+                "cat /etc/hosts /etc/passwd; cat /etc/group; {} /etc/fstab",
+                bin.display()
+            ),
+            env = [
+                (ENV_FAKEROOT_REPORT, &report),
+                (ENV_FAKEROOT_WHITEOUT, Path::new("1"))
+            ]
+        );
+
+        let report = cat!(report);
+        for entry in [
+            r#"{"path": "/etc/hosts", "action": "redirect", "hook": "open"}"#,
+            r#"{"path": "/etc/passwd", "action": "passthrough", "hook": "open"}"#,
+            r#"{"path": "/etc/group", "action": "deny", "hook": "open"}"#,
+            r#"{"path": "/etc/fstab", "action": "passthrough", "hook": "open"}"#,
+        ] {
+            assert!(report.contains(entry), "{} not in:\n{}", entry, report);
+        }
+        assert!(report.starts_with("[\n  {") && report.ends_with("}\n]\n"));
+    });
+
+    test!(log_prefix, |dir: &Path| {
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        fs::write(fake_root.join("etc/hosts"), "").unwrap();
+
+        let output = cmd!(
+            &fake_root,
+            "exec cat /etc/hosts",
+            debug = true,
+            env = [(ENV_FAKEROOT_LOG_PREFIX, "[fakeroot]")]
+        );
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains(&format!(
+            "[fakeroot]: /etc/hosts => {}",
+            fake_root.join("etc/hosts").display()
+        )));
+        assert!(stderr.lines().all(|line| line.starts_with("[fakeroot]: ")));
+    });
+
+    test!(trace_pid, |dir: &Path| {
+        let fake_etc = dir.join("etc");
+        fs::create_dir_all(&fake_etc).unwrap();
+        fs::write(fake_etc.join("hosts"), "🎉").unwrap();
+
+        // only the last `cat` runs with the shell's PID
+        let output = cmd!(
+            &dir,
+            "export FAKEROOT_TRACE_PID=$$; cat /etc/hosts; cat /etc/hosts; exec cat /etc/hosts",
+            debug = true
+        );
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(stderr.matches("@HOOK@: /etc/hosts => ").count(), 1);
+
+        // everything logs without it
+        let output = cmd!(&dir, "cat /etc/hosts; cat /etc/hosts", debug = true);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(stderr.matches("@HOOK@: /etc/hosts => ").count(), 2);
+    });
+
+    test!(dir, |dir: &PathBuf| {
+        let fake_etc = dir.join("etc");
+        fs::create_dir_all(&fake_etc).unwrap();
+        fs::write(fake_etc.join("FAKED"), "💥").unwrap();
+
+        // check dir not hooked
+        let output = cmd!(&dir, "ls /etc");
+        assert_ne!(String::from_utf8_lossy(&output.stdout).trim(), "FAKED");
+
+        // check dir hooked
+        let output = cmd!(&dir, "ls /etc", dirs = true);
+        assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "FAKED");
+    });
+
+    test!(dir_excluded, |dir: &Path| {
+        for fake_dir in ["proc", "sys", "etc"] {
+            fs::create_dir_all(dir.join(fake_dir)).unwrap();
+            fs::write(dir.join(fake_dir).join("FAKED"), "").unwrap();
+        }
+
+        // virtual filesystems are never faked, even as directories
+        let output = cmd!(&dir, "ls /proc /sys", dirs = true);
+        assert!(!String::from_utf8_lossy(&output.stdout).contains("FAKED"));
+
+        let output = cmd!(
+            &dir,
+            "ls /etc",
+            dirs = true,
+            env = [(ENV_FAKEROOT_MATCH_REGEX, "^/usr")]
+        );
+        assert!(!String::from_utf8_lossy(&output.stdout).contains("FAKED"));
+    });
+
+    test!(stdio, |dir: &Path| {
+        fs::create_dir_all(dir.join("dev/fd")).unwrap();
+        for name in ["stdin", "stdout", "stderr", "fd/0"] {
+            fs::write(dir.join("dev").join(name), "FAKED\n").unwrap();
+        }
+
+        // the standard streams are the process' own, whatever's in the fake root
+        let output = cmd!(
+            &dir,
+            "echo in | cat /dev/stdin; echo fd | cat /dev/fd/0; echo out > /dev/stdout; echo err > /dev/stderr"
+        );
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "in\nfd\nout\n");
+        assert_eq!(String::from_utf8_lossy(&output.stderr), "err\n");
+
+        // even with everything else faked, which only leaves the shell to read and write them
+        let output = cmd!(
+            &dir,
+            "echo in | { read -r line < /dev/stdin; echo \"$line\" > /dev/stdout; }",
+            all = true
+        );
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "in\n");
+        assert_eq!(cat!(dir.join("dev/stdout")), "FAKED\n");
+    });
+
+    // tests fopen by using `tee`
+    // https://github.com/coreutils/coreutils/blob/master/src/tee.c#L263
+    test!(fopen, |dir: &Path| {
+        let fake_opt = dir.join("opt");
+        fs::create_dir_all(&fake_opt).unwrap();
+        fs::write(fake_opt.join("foo"), "not 1").unwrap();
+        fs::write(fake_opt.join("bar"), "not 1").unwrap();
+
+        cmd!(
+            &dir,
+            "echo 1 | tee /opt/foo /opt/bar",
+            dirs = true,
+            debug = true
+        );
+        assert_eq!(cat!(fake_opt.join("foo")).trim(), "1");
+        assert_eq!(cat!(fake_opt.join("bar")).trim(), "1");
+    });
+
+    #[cfg(target_os = "linux")]
+    test!(getpwnam, |dir: &Path| {
+        let bin = compile(
+            dir,
+            "getpwnam",
+            r#"
+                #include <grp.h>
+                #include <pwd.h>
+                #include <stdio.h>
+
+                int main(int argc, char **argv) {
+                    struct passwd *pw = getpwnam(argv[1]);
+                    if (pw == NULL) {
+                        printf("no user\n");
+                        return 0;
+                    }
+
+                    struct group *gr = getgrgid(pw->pw_gid);
+                    printf("%s %d %s %s\n", pw->pw_name, pw->pw_uid, pw->pw_dir, gr ? gr->gr_name : "-");
+                    return 0;
+                }
+            "#,
+            &[],
+        );
+
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        fs::write(
+            fake_root.join("etc/passwd"),
+            "root:x:0:0:root:/root:/bin/sh\nfake:x:4242:4343:Fake:/home/fake:/bin/sh\n",
+        )
+        .unwrap();
+        fs::write(fake_root.join("etc/group"), "fakers:x:4343:fake\n").unwrap();
+
+        let output = cmd!(&fake_root, format!("{} fake", bin.display()));
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "fake 4242 /home/fake fakers\n"
+        );
+
+        // users only in the real database aren't found in the fake one
+        let output = cmd!(&fake_root, format!("{} nobody", bin.display()));
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "no user\n");
+
+        // without fake copies (even if they'd be redirected) the real databases are used
+        let empty_root = dir.join("empty");
+        fs::create_dir_all(&empty_root).unwrap();
+        let output = cmd!(&empty_root, format!("{} root", bin.display()), all = true);
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "root 0 /root root\n"
+        );
+    });
+
+    #[cfg(target_os = "linux")]
+    test!(getpwnam_r, |dir: &Path| {
+        let bin = compile(
+            dir,
+            "getpwnam_r",
+            r#"
+                #include <grp.h>
+                #include <pwd.h>
+                #include <stdio.h>
+
+                int main(int argc, char **argv) {
+                    struct passwd pw, *pwp;
+                    struct group gr, *grp;
+                    char buf[4096];
+
+                    getpwnam_r(argv[1], &pw, buf, sizeof(buf), &pwp);
+                    if (pwp == NULL) {
+                        printf("no user\n");
+                        return 0;
+                    }
+                    printf("%s %d ", pw.pw_name, pw.pw_uid);
+
+                    getpwuid_r(pw.pw_uid, &pw, buf, sizeof(buf), &pwp);
+                    printf("%s ", pwp ? pw.pw_name : "-");
+                    getgrgid_r(pw.pw_gid, &gr, buf, sizeof(buf), &grp);
+                    printf("%s ", grp ? gr.gr_name : "-");
+                    getgrnam_r(argv[2], &gr, buf, sizeof(buf), &grp);
+                    printf("%d\n", grp ? (int)gr.gr_gid : -1);
+                    return 0;
+                }
+            "#,
+            &[],
+        );
+
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        fs::write(
+            fake_root.join("etc/passwd"),
+            "fake:x:4242:4343:Fake:/home/fake:/bin/sh\n",
+        )
+        .unwrap();
+        fs::write(fake_root.join("etc/group"), "fakers:x:4343:fake\n").unwrap();
+
+        let output = cmd!(&fake_root, format!("{} fake fakers", bin.display()));
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "fake 4242 fake fakers 4343\n"
+        );
+
+        let output = cmd!(&fake_root, format!("{} nobody fakers", bin.display()));
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "no user\n");
+
+        // without fake copies the real databases are used
+        let empty_root = dir.join("empty");
+        fs::create_dir_all(&empty_root).unwrap();
+        let output = cmd!(
+            &empty_root,
+            format!("{} root root", bin.display()),
+            all = true
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "root 0 root root 0\n"
+        );
+    });
+
+    #[cfg(target_os = "linux")]
+    test!(passwd_recipe, |dir: &Path| {
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        for (db, line) in [
+            ("passwd", "custom:x:4242:4343:Custom:/home/custom:/bin/sh"),
+            ("group", "customgrp:x:4343:custom"),
+            ("shadow", "custom:!:19000:0:99999:7:::"),
+        ] {
+            // `/etc/shadow` usually can't be read, so it isn't copied
+            let real = fs::read_to_string(Path::new("/etc").join(db)).unwrap_or_default();
+            fs::write(
+                fake_root.join("etc").join(db),
+                format!("{}{}\n", real, line),
+            )
+            .unwrap();
+        }
+
+        let output = cmd!(&fake_root, "id custom");
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "uid=4242(custom) gid=4343(customgrp) groups=4343(customgrp)\n"
+        );
+
+        let output = cmd!(
+            &fake_root,
+            "getent passwd custom && getent group customgrp && getent shadow custom"
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "custom:x:4242:4343:Custom:/home/custom:/bin/sh\n\
+             customgrp:x:4343:custom\n\
+             custom:!:19000:0:99999:7:::\n"
+        );
+    });
+
+    test!(fopen_stream, |dir: &Path| {
+        let bin = compile(
+            dir,
+            "fopen_stream",
+            r#"
+                #include <stdio.h>
+                #include <sys/stat.h>
+
+                int main(int argc, char **argv) {
+                    FILE *fp = fopen(argv[1], "r");
+                    struct stat st;
+                    if (fp == NULL || fstat(fileno(fp), &st) != 0) {
+                        perror(argv[1]);
+                        return 1;
+                    }
+
+                    fclose(fp);
+                    return 0;
+                }
+            "#,
+            &[],
+        );
+
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        fs::write(fake_root.join("etc/hosts"), "").unwrap();
+
+        let output = cmd!(
+            &fake_root,
+            format!("{} /etc/hosts", bin.display()),
+            debug = true
+        );
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("@HOOK@: fstat on fake file /etc/hosts"));
+        assert!(stderr.contains("@HOOK@: fclose on fake stream /etc/hosts"));
+    });
+
+    test!(fclose_many, |dir: &Path| {
+        let bin = compile(
+            dir,
+            "fclose_many",
+            r#"
+                #include <stdio.h>
+
+                int main(int argc, char **argv) {
+                    for (int i = 0; i < 1000; i++) {
+                        FILE *fp = fopen(argv[1], "r");
+                        if (fp == NULL) {
+                            perror(argv[1]);
+                            return 1;
+                        }
+
+                        fclose(fp);
+                    }
+
+                    // not faked, so not tracked either
+                    fclose(fopen("/dev/null", "r"));
+                    return 0;
+                }
+            "#,
+            &[],
+        );
+
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        fs::write(fake_root.join("etc/hosts"), "").unwrap();
+
+        let output = cmd!(
+            &fake_root,
+            format!("{} /etc/hosts", bin.display()),
+            debug = true
+        );
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let mut closes = stderr
+            .lines()
+            .filter(|line| line.contains("fclose on fake stream"));
+        assert_eq!(closes.clone().count(), 1000);
+        assert!(closes.all(|line| line.ends_with("(0 still open)")));
+    });
+
+    test!(all, |fake_dir: &Path| {
+        cmd!(&fake_dir, "echo 1 > /asdf", all = true);
+        assert_eq!(cat!(fake_dir.join("asdf")).trim(), "1");
+    });
+
+    test!(on_error, |dir: &Path| {
+        let cat = |on_error: &str| {
+            Command::new("cat")
+                .arg("/etc/hosts")
+                .env("LD_PRELOAD", get_so().display().to_string())
+                .env(ENV_FAKEROOT, dir.join("missing"))
+                .env(ENV_FAKEROOT_ON_ERROR, on_error)
+                .output()
+                .unwrap()
+        };
+
+        let output = cat("passthrough");
+        assert!(output.status.success());
+        assert_eq!(output.stdout, fs::read("/etc/hosts").unwrap());
+
+        let output = cat("fail");
+        assert!(!output.status.success());
+        assert!(String::from_utf8_lossy(&output.stderr).contains("/etc/hosts: Invalid argument"));
+    });
+
+    test!(faccessat, |dir: &Path| {
+        let bin = compile(
+            dir,
+            "faccessat",
+            r#"
+                #include <fcntl.h>
+                #include <stdio.h>
+                #include <unistd.h>
+
+                int main(int argc, char **argv) {
+                    int modes[] = {R_OK, W_OK, X_OK};
+                    for (int i = 0; i < 3; i++) {
+                        int flags[] = {0, AT_EACCESS};
+                        for (int j = 0; j < 2; j++) {
+                            printf("%d", faccessat(AT_FDCWD, argv[1], modes[i], flags[j]) == 0);
+                        }
+                    }
+                    return 0;
+                }
+            "#,
+            &[],
+        );
+
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        let hosts = fake_root.join("etc/hosts");
+        fs::write(&hosts, "").unwrap();
+
+        // the fake file's permissions are checked, whichever IDs are used (though root can read and
+        // write anything, so only the execute check applies to it)
+        let root = unsafe { libc::geteuid() } == 0;
+        for (mode, expected) in [
+            (0o400, "110000"),
+            (0o600, "111100"),
+            (0o500, "110011"),
+            (0o000, "000000"),
+        ] {
+            fs::set_permissions(&hosts, fs::Permissions::from_mode(mode)).unwrap();
+            let output = cmd!(&fake_root, format!("{} /etc/hosts", bin.display()));
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            if root {
+                assert_eq!(&stdout[4..], &expected[4..]);
+            } else {
+                assert_eq!(stdout, expected);
+            }
+        }
+    });
+
+    test!(readlink, |dir: &Path| {
+        let bin = compile(
+            dir,
+            "readlink",
+            r#"
+                #define _GNU_SOURCE
+                #include <fcntl.h>
+                #include <stdio.h>
+                #include <unistd.h>
+
+                int main(int argc, char **argv) {
+                    char buf[256];
+                    ssize_t n = readlink(argv[1], buf, sizeof(buf));
+                    if (n < 0) {
+                        perror("readlink");
+                        return 1;
+                    }
+                    printf("%.*s\n", (int)n, buf);
+
+                    // the fd refers to the link itself, so the empty path reads it
+                    int fd = open(argv[1], O_PATH | O_NOFOLLOW);
+                    n = readlinkat(fd, "", buf, sizeof(buf));
+                    if (fd < 0 || n < 0) {
+                        perror("readlinkat");
+                        return 1;
+                    }
+                    printf("%.*s\n", (int)n, buf);
+                    return 0;
+                }
+            "#,
+            &[],
+        );
+
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        std::os::unix::fs::symlink("/fake/target", fake_root.join("etc/link")).unwrap();
+
+        let output = cmd!(&fake_root, format!("{} /etc/link", bin.display()));
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "/fake/target\n/fake/target\n"
+        );
+    });
+
+    test!(utimes, |dir: &Path| {
+        use std::time::{Duration, SystemTime};
+
+        let bin = compile(
+            dir,
+            "utimes",
+            r#"
+                #include <stdio.h>
+                #include <sys/time.h>
+
+                int main(int argc, char **argv) {
+                    struct timeval link[2] = {{1000000000, 0}, {1000000000, 0}};
+                    struct timeval target[2] = {{2000000000, 0}, {2000000000, 0}};
+                    if (lutimes(argv[1], link) != 0 || utimes(argv[1], target) != 0) {
+                        perror(argv[1]);
+                        return 1;
+                    }
+
+                    return 0;
+                }
+            "#,
+            &[],
+        );
+
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        fs::write(fake_root.join("etc/target"), "").unwrap();
+        std::os::unix::fs::symlink("target", fake_root.join("etc/link")).unwrap();
+
+        cmd!(&fake_root, format!("{} /etc/link", bin.display()));
+        let link = fs::symlink_metadata(fake_root.join("etc/link")).unwrap();
+        let target = fs::metadata(fake_root.join("etc/link")).unwrap();
+        let secs = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        assert_eq!(link.modified().unwrap(), secs(1_000_000_000));
+        assert_eq!(target.modified().unwrap(), secs(2_000_000_000));
+    });
+
+    test!(access, |dir: &Path| {
+        let bin = compile(
+            dir,
+            "access",
+            r#"
+                #include <stdio.h>
+                #include <unistd.h>
+
+                int main(int argc, char **argv) {
+                    printf("%s\n", access(argv[1], F_OK) == 0 ? "exists" : "missing");
+                    return 0;
+                }
+            "#,
+            &[],
+        );
+
+        let check = format!("{} /etc/fakeroot_access", bin.display());
+        let output = cmd!(
+            &dir,
+            format!("{check}; echo 1 > /etc/fakeroot_access; {check}"),
+            all = true
+        );
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "missing\nexists\n");
+        assert!(dir.join("etc/fakeroot_access").exists());
+    });
+
+    test!(create_parents, |dir: &Path| {
+        cmd!(
+            &dir,
+            "echo 1 > /deep/nested/new",
+            all = true,
+            env = [(ENV_FAKEROOT_UMASK, "077")]
+        );
+        assert_eq!(cat!(dir.join("deep/nested/new")).trim(), "1");
+        for created in ["deep", "deep/nested"] {
+            let metadata = fs::metadata(dir.join(created)).unwrap();
+            assert_eq!(metadata.permissions().mode() & 0o777, 0o700);
+        }
+
+        let output = cmd!(
+            &dir,
+            "echo 1 > /deeper/new || echo failed",
+            all = true,
+            env = [(ENV_FAKEROOT_CREATE_PARENTS, "0")]
+        );
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "failed\n");
+    });
+
+    test!(create_parents_fstat, |dir: &Path| {
+        let bin = compile(
+            dir,
+            "create_parents_fstat",
+            r#"
+                #include <fcntl.h>
+                #include <stdio.h>
+                #include <sys/stat.h>
+
+                int main(int argc, char **argv) {
+                    int fd = argv[2][0] == 'o'
+                        ? open(argv[1], O_WRONLY | O_CREAT, 0644)
+                        : openat(AT_FDCWD, argv[1], O_WRONLY | O_CREAT, 0644);
+                    struct stat st;
+                    if (fd < 0 || fstat(fd, &st) != 0) {
+                        perror(argv[1]);
+                        return 1;
+                    }
+
+                    printf("%d %lld\n", S_ISREG(st.st_mode), (long long)st.st_size);
+                    return 0;
+                }
+            "#,
+            &[],
+        );
+
+        let fake_root = dir.join("root");
+        fs::create_dir_all(&fake_root).unwrap();
+
+        // the fabricated file's missing parents are created first, so its fd is immediately usable
+        for call in ["open", "openat"] {
+            let output = cmd!(
+                &fake_root,
+                format!("{} /missing/{}/tree/new {}", bin.display(), call, call),
+                all = true
+            );
+            assert_eq!(String::from_utf8_lossy(&output.stdout), "1 0\n");
+            assert!(fake_root
+                .join(format!("missing/{}/tree/new", call))
+                .is_file());
+        }
+    });
+
+    test!(create_parents_bad_mode, |dir: &Path| {
+        let bin = compile(
+            dir,
+            "create_parents_bad_mode",
+            r#"
+                #include <stdio.h>
+
+                int main(int argc, char **argv) {
+                    FILE *file = fopen(argv[1], "bogus");
+                    if (file == NULL) {
+                        perror("fopen");
+                        return 1;
+                    }
+
+                    fclose(file);
+                    return 0;
+                }
+            "#,
+            &[],
+        );
+
+        // an invalid mode fails without creating anything
+        let output = cmd!(
+            &dir,
+            format!("{} /missing/tree/new; true", bin.display()),
+            all = true
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&output.stderr),
+            "fopen: Invalid argument\n"
+        );
+        assert!(!dir.join("missing").exists());
+    });
+
+    test!(touch_log, |dir: &Path| {
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        fs::write(fake_root.join("etc/hosts"), "").unwrap();
+        let real_file = dir.join("real");
+        fs::write(&real_file, "real").unwrap();
+
+        let touch_log = dir.join("touched");
+        cmd!(
+            &fake_root,
+            "cat /etc/hosts; echo 1 > /new/file; echo 2 > /new/file",
+            all = true,
+            env = [(ENV_FAKEROOT_TOUCH_LOG, &touch_log)]
+        );
+        cmd!(
+            &fake_root,
+            format!("echo 3 >> {}", real_file.display()),
+            env = [
+                (ENV_FAKEROOT_TOUCH_LOG, &touch_log),
+                (ENV_FAKEROOT_COW, Path::new("1"))
+            ]
+        );
+
+        // files read or written again (by the same process) aren't listed
+        assert_eq!(
+            cat!(&touch_log),
+            format!("/new\n/new/file\n{}\n", real_file.display())
+        );
+    });
+
+    test!(touch_log_seed, |dir: &Path| {
+        let fake_root = dir.join("root");
+        fs::create_dir_all(&fake_root).unwrap();
+
+        // seeding happens while the options are first read, so it must log them without reading
+        // them again
+        let touch_log = dir.join("touched");
+        let output = cmd!(
+            &fake_root,
+            "cat /etc/hostname",
+            env = [
+                (ENV_FAKEROOT_SEED, Path::new("/etc/hostname")),
+                (ENV_FAKEROOT_TOUCH_LOG, &touch_log)
+            ]
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            cat!("/etc/hostname")
+        );
+        assert_eq!(cat!(&touch_log), "/etc/hostname\n");
+    });
+
+    test!(already_fake, |dir: &Path| {
+        let fake_path = dir.join("etc/already");
+        fs::create_dir_all(dir.join("etc")).unwrap();
+
+        cmd!(
+            &dir,
+            format!("echo 1 > {}", fake_path.display()),
+            all = true
+        );
+        assert_eq!(cat!(&fake_path).trim(), "1");
+        assert!(!dir.join(dir.strip_prefix("/").unwrap()).exists());
+    });
+
+    test!(
+        #[should_panic(expected = "/asdf: Permission denied")]
+        all_unset,
+        |fake_dir: &Path| {
+            cmd!(&fake_dir, "echo 1 > /asdf");
+        }
+    );
+
+    test!(
+        #[should_panic(expected = "/asdf: Read-only file system")]
+        all_unset_quiet,
+        |fake_dir: &Path| {
+            cmd!(
+                &fake_dir,
+                "echo 1 > /asdf",
+                env = [(ENV_FAKEROOT_QUIET, "1")]
+            );
+        }
+    );
+
+    #[cfg(target_os = "linux")]
+    test!(confine, |dir: &Path| {
+        // the kernel has to support `openat2` for the fake root to be confined
+        let how = [0u64; 3];
+        let probe =
+            unsafe { libc::syscall(libc::SYS_openat2, libc::AT_FDCWD, c"/".as_ptr(), &how, 24) };
+        if probe < 0 {
+            return;
+        }
+        unsafe { libc::close(probe as c_int) };
+
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        fs::write(fake_root.join("etc/only-fake"), "fake\n").unwrap();
+        std::os::unix::fs::symlink("/etc/only-fake", fake_root.join("etc/absolute")).unwrap();
+        std::os::unix::fs::symlink("../../../../../../etc/only-fake", fake_root.join("etc/up"))
+            .unwrap();
+
+        // otherwise the links lead out of the fake root, to where the file doesn't exist
+        let output = cmd!(&fake_root, "cat /etc/absolute /etc/up; true");
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "");
+
+        let output = cmd!(
+            &fake_root,
+            "cat /etc/absolute /etc/up /etc/hostname",
+            env = [(ENV_FAKEROOT_CONFINE, "1")]
+        );
+        let expected = format!(
+            "fake\nfake\n{}",
+            fs::read_to_string("/etc/hostname").unwrap()
+        );
+        assert_eq!(String::from_utf8_lossy(&output.stdout), expected);
+
+        // the fake root's descriptor is opened again once the program replaces it
+        let bin = compile(
+            dir,
+            "confine_closed",
+            r#"
+                #include <fcntl.h>
+                #include <stdio.h>
+                #include <unistd.h>
+
+                int print(const char *path) {
+                    char buf[64];
+                    int fd = open(path, O_RDONLY);
+                    ssize_t n = fd < 0 ? -1 : read(fd, buf, sizeof(buf));
+                    if (n < 0) {
+                        perror(path);
+                        return 1;
+                    }
+
+                    fwrite(buf, 1, n, stdout);
+                    fflush(stdout);
+                    return 0;
+                }
+
+                int main(int argc, char **argv) {
+                    if (print(argv[1]) != 0) {
+                        return 1;
+                    }
+
+                    // like a daemon, closing every descriptor and opening something else
+                    for (int fd = 3; fd < 1024; fd++) {
+                        close(fd);
+                    }
+                    if (open("/proc", O_RDONLY | O_DIRECTORY) < 0) {
+                        perror("/proc");
+                        return 1;
+                    }
+
+                    return print(argv[1]);
+                }
+            "#,
+            &[],
+        );
+        let output = cmd!(
+            &fake_root,
+            format!("{} /etc/absolute", bin.display()),
+            env = [(ENV_FAKEROOT_CONFINE, "1")]
+        );
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "fake\nfake\n");
+    });
+
+    test!(root_per_uid, |dir: &Path| {
+        let uid = unsafe { libc::getuid() };
+        let name = user_name(uid).unwrap();
+        fs::create_dir_all(dir.join(uid.to_string()).join("etc")).unwrap();
+        fs::write(dir.join(uid.to_string()).join("etc/hostname"), "by uid\n").unwrap();
+        fs::create_dir_all(dir.join(format!("%{}", name)).join("etc")).unwrap();
+        fs::write(
+            dir.join(format!("%{}", name)).join("etc/hostname"),
+            "by name\n",
+        )
+        .unwrap();
+
+        // the template is used in place of `FAKEROOT`
+        let output = cmd!(
+            dir.join("unused"),
+            "cat /etc/hostname",
+            env = [(ENV_FAKEROOT_ROOT_PER_UID, dir.join("%u"))]
+        );
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "by uid\n");
+
+        let output = cmd!(
+            dir.join("unused"),
+            "cat /etc/hostname",
+            env = [(ENV_FAKEROOT_ROOT_PER_UID, dir.join("%%%U"))]
+        );
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "by name\n");
+    });
+
+    test!(strict_sys, |fake_dir: &Path| {
+        let output = cmd!(
+            &fake_dir,
+            "echo 1 > /dev/null || echo denied",
+            env = [(ENV_FAKEROOT_STRICT_SYS, "1")]
+        );
+        assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "denied");
+
+        let output = cmd!(
+            &fake_dir,
+            "cd /sys/kernel && echo 1 > ./profiling; cat /sys/kernel/profiling",
+            env = [(ENV_FAKEROOT_STRICT_SYS, "1")]
+        );
+        assert!(String::from_utf8_lossy(&output.stderr).contains("Read-only file system"));
+        assert!(!output.stdout.is_empty());
+    });
+
+    test!(deny_read, |fake_dir: &Path| {
+        let output = cmd!(
+            &fake_dir,
+            "cat /etc/shadow; true",
+            env = [
+                (ENV_FAKEROOT_DENY_READ, "/etc/shadow"),
+                (ENV_FAKEROOT_DEBUG, "2")
+            ]
+        );
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("read denied (FAKEROOT_DENY_READ): /etc/shadow"));
+        assert!(stderr.contains("Permission denied"));
+
+        // everything in a directory is denied, and symlinks to it are followed
+        std::os::unix::fs::symlink("/etc/passwd", fake_dir.join("link")).unwrap();
+        let output = cmd!(
+            &fake_dir,
+            format!(
+                "cat /etc/passwd; cat {}; true",
+                fake_dir.join("link").display()
+            ),
+            env = [(ENV_FAKEROOT_DENY_READ, "/etc"), (ENV_FAKEROOT_DEBUG, "2")]
+        );
+        assert!(output.stdout.is_empty());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(
+            stderr.matches("read denied (FAKEROOT_DENY_READ)").count(),
+            2
+        );
+
+        // faked files can still be read, and the real ones can still be checked
+        fs::create_dir_all(fake_dir.join("etc")).unwrap();
+        fs::write(fake_dir.join("etc/shadow"), "fake\n").unwrap();
+        let output = cmd!(
+            &fake_dir,
+            "cat /etc/shadow; test -e /etc/passwd && echo exists",
+            env = [(ENV_FAKEROOT_DENY_READ, "/etc")]
+        );
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "fake\nexists\n");
+    });
+
+    const REALPATH_C: &str = r#"
+        #include <limits.h>
+        #include <stdio.h>
+        #include <stdlib.h>
+        #include <string.h>
+
+        int main(int argc, char **argv) {
+            struct { char buf[PATH_MAX]; char canary[16]; } out;
+            memset(out.canary, 'X', sizeof(out.canary));
+
+            char *resolved = realpath(argv[1], out.buf);
+            for (size_t i = 0; i < sizeof(out.canary); i++) {
+                if (out.canary[i] != 'X') {
+                    fprintf(stderr, "buffer overflow\n");
+                    return 2;
+                }
+            }
+
+            if (resolved == NULL) {
+                perror("realpath");
+                return 1;
+            }
+
+            printf("%s", resolved);
+            return 0;
+        }
+    "#;
+
+    test!(open_directory, |dir: &Path| {
+        let bin = compile(
+            dir,
+            "open_directory",
+            r#"
+                #define _GNU_SOURCE
+                #include <errno.h>
+                #include <fcntl.h>
+                #include <stdio.h>
+                #include <string.h>
+
+                int main(int argc, char **argv) {
+                    for (int i = 1; i < argc; i++) {
+                        int flags = O_DIRECTORY | (strcmp(argv[i], "-p") == 0 ? O_PATH : O_RDONLY);
+                        if (flags & O_PATH) {
+                            i++;
+                        }
+
+                        int fd = open(argv[i], flags);
+                        printf("%s: %s\n", argv[i], fd < 0 ? strerror(errno) : "ok");
+                    }
+
+                    return 0;
+                }
+            "#,
+            &[],
+        );
+
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        fs::write(fake_root.join("etc/hosts"), "").unwrap();
+        fs::write(fake_root.join("usr"), "").unwrap();
+
+        // regular files in the fake root aren't directories, even where the real path is one
+        let output = cmd!(
+            &fake_root,
+            format!(
+                "{} /etc/hosts -p /etc/hosts /usr -p /usr /etc",
+                bin.display()
+            )
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "/etc/hosts: Not a directory\n\
+             /etc/hosts: Not a directory\n\
+             /usr: Not a directory\n\
+             /usr: Not a directory\n\
+             /etc: ok\n"
+        );
+    });
+
+    test!(rename, |dir: &Path| {
+        let bin = compile(
+            dir,
+            "rename",
+            r#"
+                #include <fcntl.h>
+                #include <stdio.h>
+                #include <sys/stat.h>
+
+                int main(void) {
+                    struct stat before, after, fd_after;
+                    if (stat("/etc/before", &before) != 0) {
+                        perror("stat");
+                        return 1;
+                    }
+
+                    int fd = open("/etc/before", O_RDONLY);
+                    FILE *f = fopen("/etc/before", "r");
+                    if (rename("/etc/before", "/etc/after") != 0) {
+                        perror("rename");
+                        return 1;
+                    }
+
+                    stat("/etc/after", &after);
+                    fstat(fd, &fd_after);
+                    printf("%d %d\n", before.st_ino == after.st_ino, after.st_ino == fd_after.st_ino);
+                    fclose(f);
+                    return 0;
+                }
+            "#,
+            &[],
+        );
+
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        fs::write(fake_root.join("etc/before"), "").unwrap();
+
+        let output = cmd!(
+            &fake_root,
+            bin.display().to_string(),
+            all = true,
+            env = [(ENV_FAKEROOT_SPOOF_INODE, "1")]
+        );
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "1 1\n");
+        assert!(fake_root.join("etc/after").exists());
+        assert!(!fake_root.join("etc/before").exists());
+        assert!(!Path::new("/etc/after").exists());
+    });
+
+    test!(open_flags, |dir: &Path| {
+        let bin = compile(
+            dir,
+            "open_flags",
+            r#"
+                #define _GNU_SOURCE
+                #include <fcntl.h>
+                #include <stdio.h>
+
+                void check(const char *how, int fd) {
+                    int fd_flags = fcntl(fd, F_GETFD), fl_flags = fcntl(fd, F_GETFL);
+                    printf("%s: %d %d %d\n", how, fd_flags >= 0 && (fd_flags & FD_CLOEXEC) != 0,
+                        (fl_flags & O_NONBLOCK) != 0, (fl_flags & O_APPEND) != 0);
+                }
+
+                int main(int argc, char **argv) {
+                    int flags = O_RDONLY | O_CLOEXEC | O_NONBLOCK | O_APPEND;
+                    check("open", open(argv[1], flags));
+                    check("openat", openat(AT_FDCWD, argv[1], flags));
+                    check("open64", open64(argv[1], flags));
+                    return 0;
+                }
+            "#,
+            &[],
+        );
+
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        fs::write(fake_root.join("etc/only-fake"), "").unwrap();
+
+        // flags are passed on as they were given, whether the fake file is on disk or in memory
+        let expected = "open: 1 1 1\nopenat: 1 1 1\nopen64: 1 1 1\n";
+        let output = cmd!(&fake_root, format!("{} /etc/only-fake", bin.display()));
+        assert_eq!(String::from_utf8_lossy(&output.stdout), expected);
+        let output = cmd!(
+            &fake_root,
+            format!("{} /etc/hostname", bin.display()),
+            env = [(ENV_FAKEROOT_INLINE, "/etc/hostname=myhost")]
+        );
+        assert_eq!(String::from_utf8_lossy(&output.stdout), expected);
+    });
+
+    test!(force_mode, |dir: &Path| {
+        let bin = compile(
+            dir,
+            "create",
+            r#"
+                #include <fcntl.h>
+                #include <stdio.h>
+                #include <unistd.h>
+
+                int main(int argc, char **argv) {
+                    close(open("/etc/open", O_WRONLY | O_CREAT, 0600));
+                    close(openat(AT_FDCWD, "/etc/openat", O_WRONLY | O_CREAT, 0777));
+                    close(creat("/etc/creat", 0400));
+                    fclose(fopen("/etc/fopen", "w"));
+                    return 0;
+                }
+            "#,
+            &[],
+        );
+
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        fs::write(fake_root.join("etc/open"), "").unwrap();
+        fs::set_permissions(
+            fake_root.join("etc/open"),
+            fs::Permissions::from_mode(0o600),
+        )
+        .unwrap();
+
+        cmd!(
+            &fake_root,
+            bin.display().to_string(),
+            all = true,
+            env = [(ENV_FAKEROOT_FORCE_MODE, "0644")]
+        );
+
+        // files which already existed keep their mode, since they weren't created
+        let mode = |name: &str| {
+            fake_root
+                .join(name)
+                .metadata()
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o7777
+        };
+        assert_eq!(mode("etc/open"), 0o600);
+        assert_eq!(mode("etc/openat"), 0o644);
+        assert_eq!(mode("etc/creat"), 0o644);
+        assert_eq!(mode("etc/fopen"), 0o644);
+    });
+
+    test!(strip_suid, |dir: &Path| {
+        let bin = compile(
+            dir,
+            "create",
+            r#"
+                #include <fcntl.h>
+                #include <unistd.h>
+
+                int main(int argc, char **argv) {
+                    close(open(argv[1], O_WRONLY | O_CREAT, 04755));
+                    return 0;
+                }
+            "#,
+            &[],
+        );
+
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        let mode = |name: &str| {
+            fake_root
+                .join(name)
+                .metadata()
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o7777
+        };
+
+        cmd!(
+            &fake_root,
+            format!("{} /etc/kept", bin.display()),
+            all = true
+        );
+        assert_eq!(mode("etc/kept") & 0o4000, 0o4000);
+
+        cmd!(
+            &fake_root,
+            format!("{} /etc/stripped", bin.display()),
+            all = true,
+            env = [(ENV_FAKEROOT_STRIP_SUID, "1")]
+        );
+        assert_eq!(mode("etc/stripped"), 0o755);
+
+        // a forced mode is stripped too
+        cmd!(
+            &fake_root,
+            format!("{} /etc/forced", bin.display()),
+            all = true,
+            env = [
+                (ENV_FAKEROOT_STRIP_SUID, "1"),
+                (ENV_FAKEROOT_FORCE_MODE, "06644")
+            ]
+        );
+        assert_eq!(mode("etc/forced"), 0o644);
+    });
+
+    test!(deterministic_dir_order, |dir: &Path| {
+        let bin = compile(
+            dir,
+            "readdir",
+            r#"
+                #include <dirent.h>
+                #include <stdio.h>
+
+                int main(int argc, char **argv) {
+                    DIR *dir = opendir(argv[1]);
+                    if (dir == NULL) {
+                        perror("opendir");
+                        return 1;
+                    }
+
+                    struct dirent *entry;
+                    while ((entry = readdir(dir)) != NULL) {
+                        printf("%s\n", entry->d_name);
+                    }
+
+                    closedir(dir);
+                    return 0;
+                }
+            "#,
+            &[],
+        );
+
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        for name in ["c", "a", "d", "b", "f", "e"] {
+            fs::write(fake_root.join("etc").join(name), "").unwrap();
+        }
+
+        for _ in 0..3 {
+            let output = cmd!(
+                &fake_root,
+                format!("{} /etc", bin.display()),
+                dirs = true,
+                env = [(ENV_FAKEROOT_DETERMINISTIC_DIR_ORDER, "1")]
+            );
+            assert_eq!(
+                String::from_utf8_lossy(&output.stdout),
+                ".\n..\na\nb\nc\nd\ne\nf\n"
+            );
+        }
+    });
+
+    test!(exact, |dir: &Path| {
+        let bin = compile(
+            dir,
+            "exact",
+            r#"
+                #include <dirent.h>
+                #include <fcntl.h>
+                #include <stdio.h>
+                #include <sys/stat.h>
+
+                int main(int argc, char **argv) {
+                    struct stat buf;
+                    printf("open: %d\n", open("/etc/only-fake-dir", O_RDONLY | O_DIRECTORY) >= 0);
+                    printf("opendir: %d\n", opendir("/etc/only-fake-dir") != NULL);
+                    printf("stat: %d\n", stat("/etc/only-fake-dir", &buf) == 0);
+                    printf("file: %d\n", open("/etc/only-fake-dir/file", O_RDONLY) >= 0);
+                    return 0;
+                }
+            "#,
+            &[],
+        );
+
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc/only-fake-dir")).unwrap();
+        fs::write(fake_root.join("etc/only-fake-dir/file"), "").unwrap();
+
+        let output = cmd!(&fake_root, bin.display().to_string(), dirs = true);
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "open: 1\nopendir: 1\nstat: 1\nfile: 1\n"
+        );
+
+        // only the file inside the directory is still faked
+        let output = cmd!(
+            &fake_root,
+            bin.display().to_string(),
+            dirs = true,
+            env = [
+                (ENV_FAKEROOT_EXACT, "1"),
+                (ENV_FAKEROOT_DIRS_OPS, "opendir")
+            ]
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "open: 0\nopendir: 0\nstat: 0\nfile: 1\n"
+        );
+    });
+
+    test!(dirs_ops, |dir: &Path| {
+        let bin = compile(
+            dir,
+            "dirs_ops",
+            r#"
+                #include <dirent.h>
+                #include <glob.h>
+                #include <stdio.h>
+                #include <string.h>
+
+                int main(int argc, char **argv) {
+                    DIR *dir = opendir("/etc");
+                    struct dirent *entry;
+                    while (dir != NULL && (entry = readdir(dir)) != NULL) {
+                        if (strncmp(entry->d_name, "only-", 5) == 0) {
+                            printf("opendir: %s\n", entry->d_name);
+                        }
+                    }
+
+                    glob_t found;
+                    if (glob("/etc/only-*", 0, NULL, &found) == 0) {
+                        for (size_t i = 0; i < found.gl_pathc; i++) {
+                            printf("glob: %s\n", found.gl_pathv[i]);
+                        }
+                    }
+
+                    return 0;
+                }
+            "#,
+            &[],
+        );
+
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        fs::write(fake_root.join("etc/only-fake"), "").unwrap();
+
+        let output = cmd!(&fake_root, bin.display().to_string());
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "");
+
+        // only the listed hooks look at the fake directory
+        let output = cmd!(
+            &fake_root,
+            bin.display().to_string(),
+            env = [
+                (ENV_FAKEROOT_DIRS_OPS, "opendir,nope"),
+                (ENV_FAKEROOT_DEBUG, "2")
+            ]
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "opendir: only-fake\n"
+        );
+        assert!(String::from_utf8_lossy(&output.stderr)
+            .contains("@HOOK@: unknown hook in FAKEROOT_DIRS_OPS: nope"));
+    });
+
+    test!(on_missing_dir, |dir: &Path| {
+        let bin = compile(
+            dir,
+            "opendir",
+            r#"
+                #include <dirent.h>
+                #include <stdio.h>
+                #include <string.h>
+
+                int main(int argc, char **argv) {
+                    DIR *dir = opendir(argv[1]);
+                    if (dir == NULL) {
+                        perror("opendir");
+                        return 0;
+                    }
+
+                    struct dirent *entry;
+                    while ((entry = readdir(dir)) != NULL) {
+                        if (strcmp(entry->d_name, ".") != 0 && strcmp(entry->d_name, "..") != 0) {
+                            printf("%s\n", entry->d_name);
+                        }
+                    }
+
+                    printf("listed\n");
+                    return closedir(dir);
+                }
+            "#,
+            &[],
+        );
+
+        let fake_root = dir.join("root");
+        fs::create_dir_all(&fake_root).unwrap();
+        let cmd = format!("{} /new/dir", bin.display());
+
+        let output = cmd!(&fake_root, &cmd, all = true, dirs = true);
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "");
+        assert!(!fake_root.join("new").exists());
+
+        let output = cmd!(
+            &fake_root,
+            &cmd,
+            all = true,
+            dirs = true,
+            env = [(ENV_FAKEROOT_ON_MISSING_DIR, "create")]
+        );
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "listed\n");
+        assert!(fake_root.join("new/dir").is_dir());
+    });
+
+    test!(getdents64, |dir: &Path| {
+        let bin = compile(
+            dir,
+            "getdents64",
+            r#"
+                #define _GNU_SOURCE
+                #include <dirent.h>
+                #include <fcntl.h>
+                #include <stdio.h>
+                #include <unistd.h>
+
+                int main(int argc, char **argv) {
+                    int fd = open(argv[1], O_RDONLY | O_DIRECTORY);
+                    if (fd < 0) {
+                        perror("open");
+                        return 1;
+                    }
+
+                    char buf[4096];
+                    ssize_t n;
+                    while ((n = getdents64(fd, buf, sizeof(buf))) > 0) {
+                        for (ssize_t off = 0; off < n;) {
+                            struct dirent64 *entry = (struct dirent64 *)(buf + off);
+                            if (entry->d_name[0] != '.') {
+                                printf("%s\n", entry->d_name);
+                            }
+                            off += entry->d_reclen;
+                        }
+                    }
+
+                    if (n < 0) {
+                        perror("getdents64");
+                        return 1;
+                    }
+
+                    close(fd);
+                    return 0;
+                }
+            "#,
+            &[],
+        );
+
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        fs::write(fake_root.join("etc/FAKED"), "").unwrap();
+
+        let output = cmd!(&fake_root, format!("{} /etc", bin.display()), debug = true);
+        assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "FAKED");
+        assert!(String::from_utf8_lossy(&output.stderr)
+            .contains("@HOOK@: getdents64 on fake directory /etc"));
+    });
+
+    test!(fstat, |dir: &Path| {
+        let bin = compile(
+            dir,
+            "fstat",
+            r#"
+                #include <fcntl.h>
+                #include <stdio.h>
+                #include <sys/stat.h>
+                #include <unistd.h>
+
+                int main(int argc, char **argv) {
+                    int fd = open(argv[1], O_RDONLY);
+                    struct stat st;
+                    if (fd < 0 || fstat(fd, &st) != 0) {
+                        perror(argv[1]);
+                        return 1;
+                    }
+
+                    printf("%lld", (long long)st.st_size);
+                    close(fd);
+                    return 0;
+                }
+            "#,
+            &[],
+        );
+
+        let real_path = dir.join("size");
+        fs::write(&real_path, "real").unwrap();
+        let fake_root = dir.join("root");
+        let fake_path = fake_root.join(real_path.strip_prefix("/").unwrap());
+        fs::create_dir_all(fake_path.parent().unwrap()).unwrap();
+        fs::write(&fake_path, "much bigger fake").unwrap();
+
+        let output = cmd!(
+            &fake_root,
+            format!("{} {}", bin.display(), real_path.display()),
+            debug = true
+        );
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "16");
+        assert!(String::from_utf8_lossy(&output.stderr).contains("@HOOK@: fstat on fake file"));
+    });
+
+    test!(owner, |dir: &Path| {
+        let bin = compile(
+            dir,
+            "owner",
+            r#"
+                #include <stdio.h>
+                #include <sys/stat.h>
+                #include <unistd.h>
+
+                void owner(const char *path) {
+                    struct stat buf;
+                    if (stat(path, &buf) == 0) {
+                        printf("%u:%u\n", buf.st_uid, buf.st_gid);
+                    }
+                }
+
+                int main(int argc, char **argv) {
+                    owner(argv[1]);
+                    if (chown(argv[1], 0, 0) != 0) {
+                        perror("chown");
+                    }
+                    owner(argv[1]);
+                    lchown(argv[1], -1, 42);
+                    owner(argv[1]);
+                    return 0;
+                }
+            "#,
+            &[],
+        );
+
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        fs::write(fake_root.join("etc/owned"), "").unwrap();
+        let metadata = fake_root.join("etc/owned").metadata().unwrap();
+
+        let output = cmd!(
+            &fake_root,
+            format!("{} /etc/owned", bin.display()),
+            env = [(ENV_FAKEROOT_UID, "1234"), (ENV_FAKEROOT_GID, "5678")]
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "1234:5678\n0:0\n0:42\n"
+        );
+
+        // the fake file itself still belongs to whoever created it
+        let after = fake_root.join("etc/owned").metadata().unwrap();
+        assert_eq!((after.uid(), after.gid()), (metadata.uid(), metadata.gid()));
+    });
+
+    test!(state, |dir: &Path| {
+        let bin = compile(
+            dir,
+            "state",
+            r#"
+                #include <stdio.h>
+                #include <string.h>
+                #include <sys/stat.h>
+                #include <unistd.h>
+
+                int main(int argc, char **argv) {
+                    struct stat buf;
+                    if (argc > 2 && strcmp(argv[2], "chown") == 0 && chown(argv[1], 0, 0) != 0) {
+                        perror("chown");
+                    }
+                    if (stat(argv[1], &buf) == 0) {
+                        printf("%u:%u %lu\n", buf.st_uid, buf.st_gid, (unsigned long)buf.st_ino);
+                    }
+                    return 0;
+                }
+            "#,
+            &[],
+        );
+
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        fs::write(fake_root.join("etc/a"), "").unwrap();
+        fs::write(fake_root.join("etc/b"), "").unwrap();
+
+        let state = dir.join("fakeroot.state");
+        let run = |args: &str| {
+            let output = cmd!(
+                &fake_root,
+                format!("{} {}", bin.display(), args),
+                env = [
+                    (ENV_FAKEROOT_UID, Path::new("1000")),
+                    (ENV_FAKEROOT_GID, Path::new("1000")),
+                    (ENV_FAKEROOT_SPOOF_INODE, Path::new("1")),
+                    (ENV_FAKEROOT_STATE, &state)
+                ]
+            );
+            String::from_utf8_lossy(&output.stdout).into_owned()
+        };
+
+        // the owner and inode number given in one process are seen by the next
+        assert_eq!(run("/etc/a chown"), "0:0 1\n");
+        assert_eq!(run("/etc/b"), "1000:1000 2\n");
+        assert_eq!(run("/etc/a"), "0:0 1\n");
+        assert_eq!(
+            fs::read_to_string(&state).unwrap(),
+            "1\t0\t0\t/etc/a\n2\t-\t-\t/etc/b\n"
+        );
+    });
+
+    test!(spoof_inode, |dir: &Path| {
+        let bin = compile(
+            dir,
+            "spoof_inode",
+            r#"
+                #include <fcntl.h>
+                #include <stdio.h>
+                #include <sys/stat.h>
+                #include <unistd.h>
+
+                int main(int argc, char **argv) {
+                    struct stat a, b, c;
+                    int fd = open(argv[1], O_RDONLY);
+                    if (stat(argv[1], &a) != 0 || fd < 0 || fstat(fd, &b) != 0 || lstat(argv[1], &c) != 0) {
+                        perror(argv[1]);
+                        return 1;
+                    }
+
+                    printf("%llx:%llu\n", (unsigned long long)a.st_dev, (unsigned long long)a.st_ino);
+                    printf("%llx:%llu\n", (unsigned long long)b.st_dev, (unsigned long long)b.st_ino);
+                    printf("%llx:%llu\n", (unsigned long long)c.st_dev, (unsigned long long)c.st_ino);
+                    close(fd);
+                    return 0;
+                }
+            "#,
+            &[],
+        );
+
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        fs::write(fake_root.join("etc/hosts"), "").unwrap();
+
+        let output = cmd!(
+            &fake_root,
+            format!("{} /etc/hosts", bin.display()),
+            env = [(ENV_FAKEROOT_SPOOF_INODE, "1")]
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "fa4e:1\nfa4e:1\nfa4e:1\n"
+        );
+    });
+
+    test!(
+        #[cfg(any(target_os = "freebsd", target_os = "macos"))]
+        chflags,
+        |dir: &Path| {
+            #[cfg(target_os = "freebsd")]
+            use std::os::freebsd::fs::MetadataExt;
+            #[cfg(target_os = "macos")]
+            use std::os::macos::fs::MetadataExt;
+
+            let bin = compile(
+                dir,
+                "chflags",
+                r#"
+                    #include <stdio.h>
+                    #include <sys/stat.h>
+                    #include <unistd.h>
+
+                    int main(int argc, char **argv) {
+                        if (chflags(argv[1], UF_NODUMP) != 0) {
+                            perror(argv[1]);
+                            return 1;
+                        }
+
+                        return 0;
+                    }
+                "#,
+                &[],
+            );
+
+            let real_path = dir.join("flags");
+            fs::write(&real_path, "").unwrap();
+            let fake_root = dir.join("root");
+            let fake_path = fake_root.join(real_path.strip_prefix("/").unwrap());
+            fs::create_dir_all(fake_path.parent().unwrap()).unwrap();
+            fs::write(&fake_path, "").unwrap();
+
+            // macOS drops `DYLD_INSERT_LIBRARIES` when running system programs like `sh`, so this
+            // runs the program directly
+            let preload = if cfg!(target_os = "macos") {
+                "DYLD_INSERT_LIBRARIES"
+            } else {
+                "LD_PRELOAD"
+            };
+            let output = Command::new(&bin)
+                .arg(&real_path)
+                .env(preload, get_so())
+                .env(ENV_FAKEROOT, &fake_root)
+                .output()
+                .unwrap();
+            assert!(
+                output.status.success(),
+                "{}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+            let nodump = |path: &Path| {
+                fs::metadata(path).unwrap().st_flags() as FileFlags & libc::UF_NODUMP != 0
+            };
+            assert!(nodump(&fake_path));
+            assert!(!nodump(&real_path));
+        }
+    );
+
+    test!(find, |dir: &Path| {
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc/sub/deeper")).unwrap();
+        fs::write(fake_root.join("etc/FAKED"), "").unwrap();
+        fs::write(fake_root.join("etc/sub/deeper/FAKED"), "").unwrap();
+
+        let output = cmd!(&fake_root, "find /etc", dirs = true);
+        let mut found = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(String::from)
+            .collect::<Vec<_>>();
+        found.sort();
+        assert_eq!(
+            found,
+            [
+                "/etc",
+                "/etc/FAKED",
+                "/etc/sub",
+                "/etc/sub/deeper",
+                "/etc/sub/deeper/FAKED"
+            ]
+        );
+    });
+
+    test!(realpath, |dir: &Path| {
+        let bin = compile(dir, "realpath", REALPATH_C, &[]);
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        fs::write(fake_root.join("etc/hosts"), "").unwrap();
+
+        let output = cmd!(&fake_root, format!("{} /etc/hosts", bin.display()));
+        assert_eq!(
+            PathBuf::from(String::from_utf8_lossy(&output.stdout).as_ref()),
+            fake_root.join("etc/hosts").canonicalize().unwrap()
+        );
+    });
+
+    test!(realpath_null, |dir: &Path| {
+        let bin = compile(
+            dir,
+            "realpath_null",
+            r#"
+                #include <stdio.h>
+                #include <stdlib.h>
+
+                int main(int argc, char **argv) {
+                    for (int i = 0; i < 100; i++) {
+                        char *resolved = realpath(argv[1], NULL);
+                        if (resolved == NULL) {
+                            perror("realpath");
+                            return 1;
+                        }
+
+                        if (i == 0) {
+                            printf("%s", resolved);
+                        }
+                        free(resolved);
+                    }
+
+                    return 0;
+                }
+            "#,
+            &[],
+        );
+
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        fs::write(fake_root.join("etc/hosts"), "").unwrap();
+
+        let output = cmd!(&fake_root, format!("{} /etc/hosts", bin.display()));
+        assert_eq!(
+            PathBuf::from(String::from_utf8_lossy(&output.stdout).as_ref()),
+            fake_root.join("etc/hosts").canonicalize().unwrap()
+        );
+    });
+
+    test!(realpath_too_long, |dir: &Path| {
+        let bin = compile(dir, "realpath", REALPATH_C, &[]);
+
+        // libc won't resolve a path that doesn't fit in `PATH_MAX` itself, so stand in for it with
+        // a `realpath` which does for the fake path
+        let long_realpath = compile(
+            dir,
+            "long_realpath.so",
+            r#"
+                #define _GNU_SOURCE
+                #include <dlfcn.h>
+                #include <limits.h>
+                #include <stdlib.h>
+                #include <string.h>
+
+                char *realpath(const char *path, char *resolved) {
+                    if (strstr(path, "/root/toolong") == NULL) {
+                        char *(*real)(const char *, char *) = dlsym(RTLD_NEXT, "realpath");
+                        return real(path, resolved);
+                    }
+
+                    char *long_path = malloc(PATH_MAX + 1);
+                    memset(long_path, 'y', PATH_MAX);
+                    long_path[0] = '/';
+                    long_path[PATH_MAX] = '\0';
+                    return long_path;
+                }
+            "#,
+            &["-shared", "-fPIC", "-ldl"],
+        );
+
+        let fake_root = dir.join("root");
+        fs::create_dir_all(&fake_root).unwrap();
+        fs::write(fake_root.join("toolong"), "").unwrap();
+
+        let output = cmd!(
+            &fake_root,
+            format!("{} /toolong; true", bin.display()),
+            debug = true,
+            env = [(
+                "LD_PRELOAD",
+                format!("{} {}", get_so().display(), long_realpath.display())
+            )]
+        );
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains(&format!("fake path too long: {}", PATH_MAX)));
+        assert!(stderr.ends_with("realpath: File name too long\n"));
+    });
+
+    const RELATIVE_C: &str = r#"
+        #include <fcntl.h>
+        #include <limits.h>
+        #include <stdio.h>
+        #include <unistd.h>
+
+        int main(int argc, char **argv) {
+            if (chdir(argv[1]) != 0) {
+                perror("chdir");
+                return 1;
+            }
+
+            char cwd[PATH_MAX];
+            if (getcwd(cwd, sizeof(cwd)) == NULL) {
+                perror("getcwd");
+                return 1;
+            }
+            printf("%s\n", cwd);
+
+            int fd = open(argv[2], O_RDONLY);
+            if (fd < 0) {
+                perror("open");
+                return 1;
+            }
+
+            char buf[64];
+            ssize_t n = read(fd, buf, sizeof(buf));
+            fwrite(buf, 1, n > 0 ? n : 0, stdout);
+            close(fd);
+            return 0;
+        }
+    "#;
+
+    test!(relative, |dir: &Path| {
+        let bin = compile(dir, "relative", RELATIVE_C, &[]);
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        fs::write(fake_root.join("etc/hosts"), "🎉").unwrap();
+
+        // relative paths aren't faked by default
+        let real_hosts = fs::read("/etc/hosts").unwrap();
+        let output = cmd!(&fake_root, format!("{} /etc hosts", bin.display()));
+        assert!(real_hosts.starts_with(&output.stdout["/etc\n".len()..]));
+
+        let output = cmd!(
+            &fake_root,
+            format!("{} /etc hosts", bin.display()),
+            env = [(ENV_FAKEROOT_RELATIVE, "1")]
+        );
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "/etc\n🎉");
+    });
+
+    test!(inherit_cwd, |dir: &Path| {
+        let bin = compile(dir, "relative", RELATIVE_C, &[]);
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        fs::write(fake_root.join("etc/hosts"), "🎉").unwrap();
+
+        // the program starts in `/etc`, so `hosts` is still found there after it moves to `/usr`
+        let output = cmd!(
+            &fake_root,
+            format!("cd /etc && exec {} /usr hosts", bin.display()),
+            env = [(ENV_FAKEROOT_INHERIT_CWD, "1")]
+        );
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "/usr\n🎉");
+    });
+
+    test!(strict_absolute, |dir: &Path| {
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        fs::write(fake_root.join("etc/hosts"), "fake").unwrap();
+
+        // the relative path is reported even when only logging errors, and still opened for real
+        let output = cmd!(
+            &fake_root,
+            "cd /etc && exec cat hosts",
+            env = [
+                (ENV_FAKEROOT_DEBUG, "2"),
+                (ENV_FAKEROOT_STRICT_ABSOLUTE, "1")
+            ]
+        );
+        assert_eq!(output.stdout, fs::read("/etc/hosts").unwrap());
+        assert!(String::from_utf8_lossy(&output.stderr).contains(
+            "@HOOK@: relative path not faked (enable FAKEROOT_RELATIVE to fake it): hosts"
+        ));
+    });
+
+    test!(relative_chdir_redirected, |dir: &Path| {
+        let bin = compile(dir, "relative", RELATIVE_C, &[]);
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        fs::write(fake_root.join("etc/hosts"), "🎉").unwrap();
+
+        // the program still thinks it's in `/etc`
+        let output = cmd!(
+            &fake_root,
+            format!("{} /etc hosts", bin.display()),
+            dirs = true,
+            env = [(ENV_FAKEROOT_RELATIVE, "1")]
+        );
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "/etc\n🎉");
+
+        // and files which aren't faked are still found
+        let real_passwd = fs::read("/etc/passwd").unwrap();
+        let output = cmd!(
+            &fake_root,
+            format!("{} /etc passwd", bin.display()),
+            dirs = true,
+            env = [(ENV_FAKEROOT_RELATIVE, "1")]
+        );
+        assert!(real_passwd.starts_with(&output.stdout["/etc\n".len()..]));
+    });
+
+    test!(relative_escape, |dir: &Path| {
+        let bin = compile(dir, "relative", RELATIVE_C, &[]);
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        fs::write(fake_root.join("secret"), "clamped").unwrap();
+        fs::write(dir.join("secret"), "escaped").unwrap();
+
+        // `..` can't climb out of the fake root from a faked directory
+        let output = cmd!(
+            &fake_root,
+            format!("{} /etc ../../secret", bin.display()),
+            dirs = true,
+            env = [(ENV_FAKEROOT_RELATIVE, "1")]
+        );
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "/etc\nclamped");
+
+        // nor from a real directory inside the fake root, such as one inherited from a parent
+        let output = cmd!(
+            &fake_root,
+            format!(
+                "cd {} && {} . ../../secret",
+                fake_root.join("etc").display(),
+                bin.display()
+            ),
+            dirs = true,
+            env = [(ENV_FAKEROOT_RELATIVE, "1")]
+        );
+        assert!(String::from_utf8_lossy(&output.stdout).ends_with("\nclamped"));
+    });
+
+    #[cfg(target_os = "linux")]
+    test!(execveat, |dir: &Path| {
+        let bin = compile(
+            dir,
+            "execveat",
+            r#"
+                #define _GNU_SOURCE
+                #include <fcntl.h>
+                #include <stdio.h>
+                #include <string.h>
+                #include <unistd.h>
+
+                int main(int argc, char **argv) {
+                    char *args[] = {"cat", argv[2], NULL};
+                    char *env[] = {NULL};
+                    if (strcmp(argv[1], "fd") == 0) {
+                        int fd = open("/bin/cat", O_PATH);
+                        execveat(fd, "", args, env, AT_EMPTY_PATH);
+                    } else {
+                        execveat(AT_FDCWD, "/bin/cat", args, env, 0);
+                    }
+
+                    perror("execveat");
+                    return 1;
+                }
+            "#,
+            &[],
+        );
+
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        fs::write(fake_root.join("etc/hosts"), "fake").unwrap();
+
+        // the child is launched with an empty environment, but the fake root is passed on
+        for how in ["path", "fd"] {
+            let output = cmd!(&fake_root, format!("{} {} /etc/hosts", bin.display(), how));
+            assert_eq!(String::from_utf8_lossy(&output.stdout), "fake");
+        }
+    });
+
+    test!(c_api, |dir: &Path| {
+        let so_dir = get_so().parent().unwrap().display().to_string();
+        let bin = compile(
+            dir,
+            "c_api",
+            r#"
+                #include <errno.h>
+                #include <fcntl.h>
+                #include <limits.h>
+                #include <stdio.h>
+                #include <unistd.h>
+
+                int fakeroot_init(const char *root, unsigned int flags);
+                int fakeroot_resolve(const char *path, char *resolved, size_t size);
+
+                int main(int argc, char **argv) {
+                    if (fakeroot_init(argv[1], 0) != 0) {
+                        perror("fakeroot_init");
+                        return 1;
+                    }
+
+                    // options can only be set once
+                    if (fakeroot_init(argv[1], 0) != -1 || errno != EBUSY) {
+                        fprintf(stderr, "fakeroot_init succeeded twice\n");
+                        return 1;
+                    }
+
+                    char resolved[PATH_MAX];
+                    printf("%d %s\n", fakeroot_resolve("/etc/hosts", resolved, sizeof(resolved)), resolved);
+                    printf("%d %s\n", fakeroot_resolve("/etc/passwd", resolved, sizeof(resolved)), resolved);
+
+                    // hooks use the same options
+                    char buf[64];
+                    int fd = open("/etc/hosts", O_RDONLY);
+                    ssize_t n = read(fd, buf, sizeof(buf));
+                    fwrite(buf, 1, n > 0 ? n : 0, stdout);
+                    return 0;
+                }
+            "#,
+            &[
+                "-L",
+                &so_dir,
+                "-lfakeroot",
+                &format!("-Wl,-rpath,{}", so_dir),
+            ],
+        );
+
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        fs::write(fake_root.join("etc/hosts"), "🎉").unwrap();
+
+        let output = Command::new(bin)
+            .arg(&fake_root)
+            .env_remove(ENV_FAKEROOT)
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            format!(
+                "1 {}\n0 /etc/passwd\n🎉",
+                fake_root.join("etc/hosts").display()
+            )
+        );
+    });
+
+    test!(version, |dir: &Path| {
+        let so_dir = get_so().parent().unwrap().display().to_string();
+        let bin = compile(
+            dir,
+            "version",
+            r#"
+                #include <stdio.h>
+
+                const char *fakeroot_version(void);
+
+                int main(void) {
+                    printf("%s", fakeroot_version());
+                    return 0;
+                }
+            "#,
+            &[
+                "-L",
+                &so_dir,
+                "-lfakeroot",
+                &format!("-Wl,-rpath,{}", so_dir),
+            ],
+        );
+
+        let output = Command::new(bin).output().unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            env!("CARGO_PKG_VERSION")
+        );
+
+        // the version is logged when the hooks are first used
+        let output = cmd!(dir, "cat /dev/null", env = [(ENV_FAKEROOT_DEBUG, "3")]);
+        assert!(String::from_utf8_lossy(&output.stderr).contains(&format!(
+            "fakeroot {} loaded, root: {}",
+            env!("CARGO_PKG_VERSION"),
+            dir.display()
+        )));
+    });
+
+    test!(hook_names, |dir: &Path| {
+        assert!(available_hooks().contains(&"open"));
+        assert!(available_hooks().contains(&"opendir"));
+
+        let so_dir = get_so().parent().unwrap().display().to_string();
+        let bin = compile(
+            dir,
+            "hook_names",
+            r#"
+                #include <stddef.h>
+                #include <stdio.h>
+
+                const char *fakeroot_hook_name(size_t index);
+
+                int main(void) {
+                    const char *name;
+                    for (size_t i = 0; (name = fakeroot_hook_name(i)) != NULL; i++) {
+                        printf("%s\n", name);
+                    }
+
+                    return 0;
+                }
+            "#,
+            &[
+                "-L",
+                &so_dir,
+                "-lfakeroot",
+                &format!("-Wl,-rpath,{}", so_dir),
+            ],
+        );
+
+        let output = Command::new(bin).output().unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            format!("{}\n", available_hooks().join("\n"))
+        );
+
+        // unknown hooks are warned about
+        let output = cmd!(
+            dir,
+            "cat /etc/hosts",
+            debug = true,
+            env = [(ENV_FAKEROOT_DEBUG_HOOKS, "open,nope")]
+        );
+        assert!(String::from_utf8_lossy(&output.stderr)
+            .contains("@HOOK@: unknown hook in FAKEROOT_DEBUG_HOOKS: nope"));
+    });
+
+    const FOPEN_EXCLUSIVE_C: &str = r#"
+        #include <stdio.h>
+
+        int main(int argc, char **argv) {
+            FILE *file = fopen(argv[1], "wx");
+            if (file == NULL) {
+                perror("fopen");
+                return 1;
+            }
+
+            fputs("created", file);
+            fclose(file);
+            return 0;
+        }
+    "#;
+
+    test!(fopen_exclusive, |dir: &Path| {
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("opt")).unwrap();
+
+        // check both `fopen` and `fopen64` (which large file support uses)
+        for (name, args) in [
+            ("fopen", &[][..]),
+            ("fopen64", &["-D_FILE_OFFSET_BITS=64"][..]),
+        ] {
+            let bin = compile(dir, name, FOPEN_EXCLUSIVE_C, args);
+            let fake_file = fake_root.join("opt").join(name);
+
+            cmd!(
+                &fake_root,
+                format!("{} /opt/{}", bin.display(), name),
+                all = true
+            );
+            assert_eq!(cat!(&fake_file), "created");
+            assert!(!Path::new("/opt").join(name).exists());
+        }
+    });
+
+    test!(
+        #[should_panic(expected = "fopen: File exists")]
+        fopen_exclusive_exists,
+        |dir: &Path| {
+            let bin = compile(dir, "fopen", FOPEN_EXCLUSIVE_C, &["-D_FILE_OFFSET_BITS=64"]);
+            let fake_root = dir.join("root");
+            fs::create_dir_all(fake_root.join("opt")).unwrap();
+            fs::write(fake_root.join("opt/exists"), "").unwrap();
+
+            cmd!(
+                &fake_root,
+                format!("{} /opt/exists", bin.display()),
+                all = true
+            );
+        }
+    );
+
+    const OPEN_EXCLUSIVE_C: &str = r#"
+        #include <fcntl.h>
+        #include <stdio.h>
+        #include <unistd.h>
+
+        int main(int argc, char **argv) {
+            int fd = open(argv[1], O_WRONLY | O_CREAT | O_EXCL, 0644);
+            if (fd < 0) {
+                perror("open");
+                return 1;
+            }
+
+            write(fd, "created", 7);
+            close(fd);
+            return 0;
+        }
+    "#;
+
+    test!(open_exclusive, |dir: &Path| {
+        let bin = compile(dir, "open", OPEN_EXCLUSIVE_C, &[]);
+        let fake_root = dir.join("root");
+        fs::create_dir_all(&fake_root).unwrap();
+
+        // the fake parents don't exist yet, but the file is still created exclusively
+        cmd!(
+            &fake_root,
+            format!("{} /opt/fakeroot/exclusive", bin.display()),
+            all = true
+        );
+        assert_eq!(cat!(fake_root.join("opt/fakeroot/exclusive")), "created");
+    });
+
+    test!(
+        #[should_panic(expected = "open: File exists")]
+        open_exclusive_exists,
+        |dir: &Path| {
+            let bin = compile(dir, "open", OPEN_EXCLUSIVE_C, &[]);
+            let fake_root = dir.join("root");
+            fs::create_dir_all(fake_root.join("opt")).unwrap();
+            fs::write(fake_root.join("opt/exists"), "").unwrap();
+
+            cmd!(
+                &fake_root,
+                format!("{} /opt/exists", bin.display()),
+                all = true
+            );
+        }
+    );
+
+    test!(follow, |dir: &Path| {
+        let fake_etc = dir.join("etc");
+        fs::create_dir_all(&fake_etc).unwrap();
+        fs::write(fake_etc.join("hosts"), "🎉").unwrap();
+        std::os::unix::fs::symlink("/etc/passwd", fake_etc.join("absolute")).unwrap();
+        std::os::unix::fs::symlink("../../../../etc/passwd", fake_etc.join("relative")).unwrap();
+        std::os::unix::fs::symlink("hosts", fake_etc.join("inside")).unwrap();
+        std::os::unix::fs::symlink("/", dir.join("up")).unwrap();
+
+        // symlinks are followed by default
+        let real_passwd = fs::read("/etc/passwd").unwrap();
+        let output = cmd!(&dir, "cat /etc/absolute");
+        assert_eq!(output.stdout, real_passwd);
+
+        // links which stay inside the fake root still work
+        let output = cmd!(&dir, "cat /etc/inside", env = [(ENV_FAKEROOT_FOLLOW, "0")]);
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "🎉");
+
+        for path in ["/etc/absolute", "/etc/relative", "/up/etc/passwd"] {
+            let result = std::panic::catch_unwind(|| {
+                cmd!(
+                    &dir,
+                    format!("cat {}", path),
+                    env = [(ENV_FAKEROOT_FOLLOW, "0")]
+                );
+            });
+            assert!(result.is_err(), "{} was followed", path);
+        }
+    });
+
+    test!(
+        #[should_panic(expected = "/etc/escape: Permission denied")]
+        follow_disabled,
+        |dir: &Path| {
+            let fake_etc = dir.join("etc");
+            fs::create_dir_all(&fake_etc).unwrap();
+            std::os::unix::fs::symlink("/etc/passwd", fake_etc.join("escape")).unwrap();
+
+            cmd!(&dir, "cat /etc/escape", env = [(ENV_FAKEROOT_FOLLOW, "0")]);
+        }
+    );
+
+    test!(cow, |dir: &Path| {
+        use std::time::{Duration, SystemTime};
+
+        let fake_root = dir.join("root");
+        let real_file = dir.join("real/file");
+        fs::create_dir_all(&fake_root).unwrap();
+        fs::create_dir_all(real_file.parent().unwrap()).unwrap();
+        fs::write(&real_file, "real").unwrap();
+        fs::set_permissions(&real_file, fs::Permissions::from_mode(0o640)).unwrap();
+        let then = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        File::open(&real_file)
+            .unwrap()
+            .set_times(FileTimes::new().set_accessed(then).set_modified(then))
+            .unwrap();
+
+        // reading doesn't copy
+        let output = cmd!(
+            &fake_root,
+            format!("cat {}", real_file.display()),
+            env = [(ENV_FAKEROOT_COW, "1")]
+        );
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "real");
+        let fake_file = fake_root.join(real_file.strip_prefix("/").unwrap());
+        assert!(!fake_file.exists());
+
+        // opening for writing does
+        cmd!(
+            &fake_root,
+            format!(": >> {}", real_file.display()),
+            env = [(ENV_FAKEROOT_COW, "1")]
+        );
+        assert_eq!(cat!(&fake_file), "real");
+
+        let real_metadata = fs::metadata(&real_file).unwrap();
+        let fake_metadata = fs::metadata(&fake_file).unwrap();
+        assert_eq!(fake_metadata.modified().unwrap(), then);
+        assert_eq!(
+            fake_metadata.modified().unwrap(),
+            real_metadata.modified().unwrap()
+        );
+        assert_eq!(fake_metadata.permissions().mode() & 0o7777, 0o640);
+
+        // and writes only land in the fake root
+        cmd!(
+            &fake_root,
+            format!("echo fake > {}", real_file.display()),
+            env = [(ENV_FAKEROOT_COW, "1")]
+        );
+        assert_eq!(cat!(&fake_file), "fake\n");
+        assert_eq!(cat!(&real_file), "real");
+    });
+
+    test!(match_regex, |dir: &Path| {
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        fs::write(fake_root.join("etc/hosts"), "fake hosts").unwrap();
+        fs::write(fake_root.join("etc/fake.conf"), "fake conf").unwrap();
+
+        let output = cmd!(
+            &fake_root,
+            "cat /etc/fake.conf; cat /etc/hosts",
+            env = [(ENV_FAKEROOT_MATCH_REGEX, r"^/etc/.*\.conf$")]
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            format!("fake conf{}", cat!("/etc/hosts"))
+        );
+    });
+
+    test!(bind, |dir: &Path| {
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        fs::write(fake_root.join("etc/hostname"), "fake root").unwrap();
+        fs::write(dir.join("resolv"), "bound").unwrap();
+
+        let output = cmd!(
+            &fake_root,
+            "cat /etc/resolv.conf; cat /etc/hostname; cat /etc/hosts",
+            env = [(
+                ENV_FAKEROOT_BIND,
+                format!("/etc/resolv.conf={}", dir.join("resolv").display())
+            )]
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            format!("boundfake root{}", cat!("/etc/hosts"))
+        );
+    });
+
+    test!(alias, |dir: &Path| {
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        fs::write(fake_root.join("etc/hostname"), "fake\n").unwrap();
+
+        let output = cmd!(
+            &fake_root,
+            "cat /etc/hostname /etc/HOSTNAME /etc/host-name",
+            env = [(
+                ENV_FAKEROOT_ALIAS,
+                "/etc/hostname<-/etc/HOSTNAME,/etc/host-name"
+            )]
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "fake\nfake\nfake\n"
+        );
+
+        // writing to an alias writes the one fake file
+        cmd!(
+            &fake_root,
+            "echo written > /etc/HOSTNAME",
+            env = [(ENV_FAKEROOT_ALIAS, "/etc/hostname<-/etc/HOSTNAME")]
+        );
+        assert_eq!(cat!(fake_root.join("etc/hostname")), "written\n");
+        assert!(!fake_root.join("etc/HOSTNAME").exists());
+    });
+
+    test!(match_device, |dir: &Path| {
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        fs::write(fake_root.join("etc/hostname"), "fake\n").unwrap();
+        let real = fs::read_to_string("/etc/hostname").unwrap();
+
+        // paths on the device are faked, whether it's given by ID or by a path on it
+        let etc = fs::metadata("/etc").unwrap().dev().to_string();
+        for device in [etc.as_str(), "/etc"] {
+            let output = cmd!(
+                &fake_root,
+                "cat /etc/hostname",
+                env = [(ENV_FAKEROOT_MATCH_DEVICE, device)]
+            );
+            assert_eq!(String::from_utf8_lossy(&output.stdout), "fake\n");
+        }
+
+        // paths on any other device aren't
+        let other = (fs::metadata("/etc").unwrap().dev() + 1).to_string();
+        let output = cmd!(
+            &fake_root,
+            "cat /etc/hostname",
+            env = [(ENV_FAKEROOT_MATCH_DEVICE, other.as_str())]
+        );
+        assert_eq!(String::from_utf8_lossy(&output.stdout), real);
+    });
+
+    #[cfg(target_os = "linux")]
+    test!(inline, |dir: &Path| {
+        let fake_root = dir.join("root");
+        fs::create_dir_all(&fake_root).unwrap();
+
+        let output = cmd!(
+            &fake_root,
+            "cat /etc/hostname; echo; cat < /etc/hostname; echo; cat /etc/hosts",
+            env = [(ENV_FAKEROOT_INLINE, "/etc/hostname=myhost")]
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            format!("myhost\nmyhost\n{}", cat!("/etc/hosts"))
+        );
+        assert!(!fake_root.join("etc").exists());
+
+        // paths longer than a memfd's name are fine too
+        let long_path = format!("/etc/{}/hostname", ["nested"; 40].join("/"));
+        let output = cmd!(
+            &fake_root,
+            format!("cat {}", long_path),
+            env = [(ENV_FAKEROOT_INLINE, format!("{}=long", long_path))]
+        );
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "long");
+    });
+
+    #[cfg(target_os = "linux")]
+    test!(gen, |dir: &Path| {
+        let fake_root = dir.join("root");
+        fs::create_dir_all(&fake_root).unwrap();
+
+        let output = cmd!(
+            &fake_root,
+            "cat /etc/hostname; cat < /etc/hostname",
+            env = [(ENV_FAKEROOT_GEN, "/etc/hostname=echo generated")]
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "generated\ngenerated\n"
+        );
+        assert!(!fake_root.join("etc").exists());
+
+        // the command isn't faked, so it can read the real file it's generating
+        let output = cmd!(
+            &fake_root,
+            "cat /etc/hostname",
+            env = [(
+                ENV_FAKEROOT_GEN,
+                "/etc/hostname=cat /etc/hostname; echo more"
+            )]
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            format!("{}more\n", cat!("/etc/hostname"))
+        );
+
+        // a failed command falls through to the file itself
+        let output = cmd!(
+            &fake_root,
+            "cat /etc/hostname",
+            env = [(ENV_FAKEROOT_GEN, "/etc/hostname=echo partial; exit 1")]
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            cat!("/etc/hostname")
+        );
+    });
+
+    test!(nulldev, |dir: &Path| {
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        fs::write(fake_root.join("etc/passwd"), "fake").unwrap();
+
+        // writes are discarded and reads are empty, whether or not there's a file on disk
+        let output = cmd!(
+            &fake_root,
+            "echo secret > /etc/noisy; cat /etc/noisy /etc/passwd; echo done",
+            env = [(ENV_FAKEROOT_NULLDEV, "/etc/noisy:/etc/passwd")]
+        );
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "done\n");
+        assert_eq!(String::from_utf8_lossy(&output.stderr), "");
+        assert!(!fake_root.join("etc/noisy").exists());
+        assert_eq!(
+            fs::read_to_string(fake_root.join("etc/passwd")).unwrap(),
+            "fake"
+        );
+    });
+
+    test!(synth_stat, |dir: &Path| {
+        let bin = compile(
+            dir,
+            "synth_stat",
+            r#"
+                #define _GNU_SOURCE
+                #include <fcntl.h>
+                #include <stdio.h>
+                #include <sys/stat.h>
+
+                int main(int argc, char **argv) {
+                    struct stat buf;
+                    struct statx bufx;
+                    if (stat(argv[1], &buf) == 0) {
+                        printf("stat: %ld %o\n", (long)buf.st_size, buf.st_mode);
+                    }
+                    if (lstat(argv[1], &buf) == 0) {
+                        printf("lstat: %ld %o\n", (long)buf.st_size, buf.st_mode);
+                    }
+                    if (statx(AT_FDCWD, argv[1], 0, STATX_BASIC_STATS, &bufx) == 0) {
+                        printf("statx: %llu %o\n", (unsigned long long)bufx.stx_size, bufx.stx_mode);
+                    }
+                    return 0;
+                }
+            "#,
+            &[],
+        );
 
-    // tests fopen by using `tee`
-    // https://github.com/coreutils/coreutils/blob/master/src/tee.c#L263
-    test!(fopen, |dir: &Path| {
-        let fake_opt = dir.join("opt");
-        fs::create_dir_all(&fake_opt).unwrap();
-        fs::write(fake_opt.join("foo"), "not 1").unwrap();
-        fs::write(fake_opt.join("bar"), "not 1").unwrap();
+        let fake_root = dir.join("root");
+        fs::create_dir_all(&fake_root).unwrap();
+        let cmd = format!("{} /never/created", bin.display());
+
+        let output = cmd!(&fake_root, &cmd, all = true);
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "");
+
+        let output = cmd!(
+            &fake_root,
+            &cmd,
+            all = true,
+            env = [(ENV_FAKEROOT_SYNTH_STAT, "1")]
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "stat: 0 100644\nlstat: 0 100644\nstatx: 0 100644\n"
+        );
+        assert!(!fake_root.join("never").exists());
+
+        // the size matches what the file would be read as
+        let output = cmd!(
+            &fake_root,
+            &cmd,
+            all = true,
+            env = [
+                (ENV_FAKEROOT_SYNTH_STAT, "1"),
+                (ENV_FAKEROOT_DEFAULT_CONTENT, "hello")
+            ]
+        );
+        assert!(String::from_utf8_lossy(&output.stdout).starts_with("stat: 5 100644\n"));
+    });
+
+    test!(default_content, |dir: &Path| {
+        let output = cmd!(
+            dir,
+            // programs can't be found with an empty fake root, so this only uses the shell
+            "read -r line < /etc/never-created; echo \"$line\"",
+            all = true,
+            env = [(ENV_FAKEROOT_DEFAULT_CONTENT, "default")]
+        );
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "default\n");
+        assert!(!dir.join("etc").exists());
+
+        // as are paths longer than a memfd's name
+        let long_path = format!("/etc/{}/never-created", ["nested"; 40].join("/"));
+        let output = cmd!(
+            dir,
+            format!("read -r line < {}; echo \"$line\"", long_path),
+            all = true,
+            env = [(ENV_FAKEROOT_DEFAULT_CONTENT, "default")]
+        );
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "default\n");
+        assert!(!dir.join("etc").exists());
+
+        // files which have been created are read as normal
+        let output = cmd!(
+            dir,
+            "echo created > /etc/created && read -r line < /etc/created && echo \"$line\"",
+            all = true,
+            env = [(ENV_FAKEROOT_DEFAULT_CONTENT, "default")]
+        );
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "created\n");
+    });
+
+    test!(apply_diff, |dir: &Path| {
+        fs::create_dir_all(dir.join("etc")).unwrap();
+        fs::write(dir.join("etc/app.conf"), "name=app\nlevel=info\n").unwrap();
+        let diff = dir.join("app.diff");
+        fs::write(
+            &diff,
+            "--- a/etc/app.conf\n+++ b/etc/app.conf\n@@ -1,2 +1,2 @@\n name=app\n-level=info\n+level=debug\n",
+        )
+        .unwrap();
+
+        let output = cmd!(
+            dir,
+            "cat /etc/app.conf",
+            env = [(ENV_FAKEROOT_APPLY_DIFF, &diff)]
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "name=app\nlevel=debug\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("etc/app.conf")).unwrap(),
+            "name=app\nlevel=info\n"
+        );
+
+        // files which the diff doesn't apply to are read as normal
+        fs::write(dir.join("etc/app.conf"), "name=other\n").unwrap();
+        let output = cmd!(
+            dir,
+            "cat /etc/app.conf",
+            env = [(ENV_FAKEROOT_APPLY_DIFF, &diff)]
+        );
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "name=other\n");
+    });
+
+    test!(gzip, |dir: &Path| {
+        fs::create_dir_all(dir.join("etc")).unwrap();
+        let mut encoder = GzEncoder::new(
+            File::create(dir.join("etc/big.conf.gz")).unwrap(),
+            Compression::default(),
+        );
+        encoder.write_all(b"decompressed\n").unwrap();
+        encoder.finish().unwrap();
+
+        let output = cmd!(
+            dir,
+            "read -r line < /etc/big.conf; echo \"$line\"",
+            env = [(ENV_FAKEROOT_GZIP, "1")]
+        );
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "decompressed\n");
+        assert!(!dir.join("etc/big.conf").exists());
+
+        // an uncompressed file is read as normal
+        fs::write(dir.join("etc/big.conf"), "uncompressed\n").unwrap();
+        let output = cmd!(
+            dir,
+            "read -r line < /etc/big.conf; echo \"$line\"",
+            env = [(ENV_FAKEROOT_GZIP, "1")]
+        );
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "uncompressed\n");
+    });
+
+    test!(vanished, |dir: &Path| {
+        // preloaded after the hooks, so the fake file is removed between finding it and opening it
+        let shim = compile(
+            dir,
+            "libvanish.so",
+            r#"
+                #define _GNU_SOURCE
+                #include <dlfcn.h>
+                #include <stdarg.h>
+                #include <string.h>
+                #include <unistd.h>
+
+                static void vanish(const char *path) {
+                    if (strstr(path, "/root/etc/hostname") != NULL) {
+                        unlink(path);
+                    }
+                }
+
+                int open(const char *path, int flags, ...) {
+                    va_list args;
+                    va_start(args, flags);
+                    int mode = va_arg(args, int);
+                    va_end(args);
+                    vanish(path);
+                    return ((int (*)(const char *, int, ...))dlsym(RTLD_NEXT, "open"))(path, flags, mode);
+                }
+
+                int openat(int dirfd, const char *path, int flags, ...) {
+                    va_list args;
+                    va_start(args, flags);
+                    int mode = va_arg(args, int);
+                    va_end(args);
+                    vanish(path);
+                    return ((int (*)(int, const char *, int, ...))dlsym(RTLD_NEXT, "openat"))(dirfd, path, flags, mode);
+                }
+            "#,
+            &["-shared", "-fPIC", "-ldl"],
+        );
+
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        fs::write(fake_root.join("etc/hostname"), "fake").unwrap();
+
+        let output = Command::new("cat")
+            .arg("/etc/hostname")
+            .env(
+                "LD_PRELOAD",
+                format!("{} {}", get_so().display(), shim.display()),
+            )
+            .env(ENV_FAKEROOT, &fake_root)
+            .output()
+            .unwrap();
+        assert!(!fake_root.join("etc/hostname").exists());
+        assert_eq!(String::from_utf8_lossy(&output.stderr), "");
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            fs::read_to_string("/etc/hostname").unwrap()
+        );
+    });
+
+    test!(threads, |dir: &Path| {
+        let bin = compile(
+            dir,
+            "threads",
+            r#"
+                #include <fcntl.h>
+                #include <pthread.h>
+                #include <string.h>
+                #include <unistd.h>
+
+                #define THREADS 64
+
+                static pthread_barrier_t barrier;
+
+                void *run(void *arg) {
+                    char buf[16] = {0};
+                    pthread_barrier_wait(&barrier);
+                    int fd = open("/etc/hosts", O_RDONLY);
+                    if (fd < 0 || read(fd, buf, sizeof(buf) - 1) < 0) {
+                        return (void *)1;
+                    }
+
+                    close(fd);
+                    return (void *)(long)(strcmp(buf, "fake") != 0);
+                }
+
+                int main() {
+                    pthread_t threads[THREADS];
+                    pthread_barrier_init(&barrier, NULL, THREADS);
+                    for (int i = 0; i < THREADS; i++) {
+                        pthread_create(&threads[i], NULL, run, NULL);
+                    }
+
+                    long failed = 0;
+                    for (int i = 0; i < THREADS; i++) {
+                        void *ret;
+                        pthread_join(threads[i], &ret);
+                        failed += (long)ret;
+                    }
+
+                    return failed != 0;
+                }
+            "#,
+            &["-pthread"],
+        );
+
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        fs::write(fake_root.join("etc/hosts"), "fake").unwrap();
+
+        // every thread touches the hooks for the first time at once, racing to read the options
+        for _ in 0..10 {
+            cmd!(&fake_root, bin.display().to_string());
+        }
+    });
+
+    test!(threads_check_root_once, |dir: &Path| {
+        let bin = compile(
+            dir,
+            "threads",
+            r#"
+                #include <pthread.h>
+                #include <sys/stat.h>
+
+                #define THREADS 256
+
+                static pthread_barrier_t barrier;
+
+                void *run(void *arg) {
+                    struct stat buf;
+                    pthread_barrier_wait(&barrier);
+                    for (int i = 0; i < 16; i++) {
+                        stat("/etc/hosts", &buf);
+                    }
+                    return NULL;
+                }
+
+                int main() {
+                    pthread_t threads[THREADS];
+                    pthread_barrier_init(&barrier, NULL, THREADS);
+                    for (int i = 0; i < THREADS; i++) {
+                        pthread_create(&threads[i], NULL, run, NULL);
+                    }
+                    for (int i = 0; i < THREADS; i++) {
+                        pthread_join(threads[i], NULL);
+                    }
+                    return 0;
+                }
+            "#,
+            &["-pthread"],
+        );
+
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        fs::write(fake_root.join("etc/hosts"), "fake").unwrap();
+
+        // however many threads race to resolve the first path, the root is only checked on disk once
+        let log = dir.join("fakeroot.log");
+        let status = Command::new(&bin)
+            .env("LD_PRELOAD", get_so().display().to_string())
+            .env(ENV_FAKEROOT, &fake_root)
+            .env(ENV_FAKEROOT_DEBUG, "3")
+            .env(ENV_FAKEROOT_LOG, &log)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let log = fs::read_to_string(log).unwrap();
+        let checking = format!("@HOOK@: checking {}\n", fake_root.display());
+        assert_eq!(log.matches(&checking).count(), 1, "{}", log);
+        assert!(log.contains("@HOOK@: /etc/hosts => "));
+    });
+
+    test!(quota, |dir: &Path| {
+        let bin = compile(
+            dir,
+            "quota",
+            r#"
+                #include <fcntl.h>
+                #include <stdio.h>
+                #include <string.h>
+                #include <errno.h>
+                #include <unistd.h>
+
+                int main(int argc, char **argv) {
+                    for (int i = 1; i < argc; i++) {
+                        int fd = open(argv[i], O_WRONLY | O_TRUNC);
+                        if (fd < 0) {
+                            printf("%s: %s\n", argv[i], strerror(errno));
+                            continue;
+                        }
+                        write(fd, "0123456789", 10);
+                        close(fd);
+                        printf("%s: ok\n", argv[i]);
+                    }
+                    return 0;
+                }
+            "#,
+            &[],
+        );
+
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        for name in ["a", "b", "c"] {
+            fs::write(fake_root.join("etc").join(name), "").unwrap();
+        }
+
+        // writes are counted as files are closed, and denied once the quota is exceeded
+        let output = cmd!(
+            &fake_root,
+            format!("{} /etc/a /etc/b /etc/c", bin.display()),
+            env = [(ENV_FAKEROOT_QUOTA, "16")]
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "/etc/a: ok\n/etc/b: ok\n/etc/c: No space left on device\n"
+        );
+
+        // later processes find the fake root already full, though files which aren't faked are
+        // written as normal
+        let real_file = dir.join("real");
+        fs::write(&real_file, "").unwrap();
+        let output = cmd!(
+            &fake_root,
+            format!("{} /etc/c {}", bin.display(), real_file.display()),
+            env = [(ENV_FAKEROOT_QUOTA, "16")]
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            format!(
+                "/etc/c: No space left on device\n{}: ok\n",
+                real_file.display()
+            )
+        );
+    });
+
+    test!(threads_copy_once, |dir: &Path| {
+        let bin = compile(
+            dir,
+            "threads",
+            r#"
+                #include <fcntl.h>
+                #include <pthread.h>
+                #include <stdio.h>
+                #include <sys/stat.h>
+                #include <unistd.h>
+
+                #define THREADS 64
+
+                static pthread_barrier_t barrier;
+                static const char *path;
+
+                void *run(void *arg) {
+                    struct stat buf;
+                    pthread_barrier_wait(&barrier);
+                    for (int i = 0; i < 4; i++) {
+                        int fd = open(path, O_WRONLY | O_APPEND);
+                        if (fstat(fd, &buf) == 0) {
+                            printf("%ld\n", (long)buf.st_size);
+                        }
+                        close(fd);
+                    }
+                    return NULL;
+                }
+
+                int main(int argc, char **argv) {
+                    pthread_t threads[THREADS];
+                    path = argv[1];
+                    pthread_barrier_init(&barrier, NULL, THREADS);
+                    for (int i = 0; i < THREADS; i++) {
+                        pthread_create(&threads[i], NULL, run, NULL);
+                    }
+                    for (int i = 0; i < THREADS; i++) {
+                        pthread_join(threads[i], NULL);
+                    }
+                    return 0;
+                }
+            "#,
+            &["-pthread"],
+        );
+
+        let fake_root = dir.join("root");
+        fs::create_dir_all(&fake_root).unwrap();
+        let src = dir.join("source");
+        fs::write(&src, "x".repeat(1 << 24)).unwrap();
+
+        // however many threads race to write the file, it's only copied from its source once, and
+        // none of them see it before it has been
+        let log = dir.join("fakeroot.log");
+        let output = Command::new(&bin)
+            .arg(&src)
+            .env("LD_PRELOAD", get_so().display().to_string())
+            .env(ENV_FAKEROOT, &fake_root)
+            .env(ENV_FAKEROOT_COW, "1")
+            .env(ENV_FAKEROOT_DEBUG, "1")
+            .env(ENV_FAKEROOT_LOG, &log)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let sizes = String::from_utf8_lossy(&output.stdout);
+        assert_eq!(sizes.lines().count(), 256);
+        assert!(sizes.lines().all(|size| size == "16777216"), "{}", sizes);
+        let log = fs::read_to_string(log).unwrap();
+        assert_eq!(log.matches("@HOOK@: copied ").count(), 1, "{}", log);
+        assert_eq!(
+            fs::metadata(fake_root.join(src.strip_prefix("/").unwrap()))
+                .unwrap()
+                .len(),
+            1 << 24
+        );
+    });
+
+    test!(threads_atomic_save, |dir: &Path| {
+        let bin = compile(
+            dir,
+            "atomic_save",
+            r#"
+                #include <fcntl.h>
+                #include <pthread.h>
+                #include <stdio.h>
+                #include <string.h>
+                #include <unistd.h>
+
+                #define SIZE 65536
+                #define SAVES 200
+                #define READERS 4
+
+                static volatile int done = 0;
+
+                void *save(void *arg) {
+                    static char buf[SIZE];
+                    for (int i = 0; i < SAVES; i++) {
+                        memset(buf, 'a' + i % 26, SIZE);
+                        int fd = open("/etc/hosts.tmp", O_WRONLY | O_CREAT | O_TRUNC, 0644);
+                        for (int written = 0; written < SIZE; written += 4096) {
+                            write(fd, buf + written, 4096);
+                        }
+                        close(fd);
+                        if (rename("/etc/hosts.tmp", "/etc/hosts") != 0) {
+                            perror("rename");
+                        }
+                    }
+                    done = 1;
+                    return NULL;
+                }
+
+                void *load(void *arg) {
+                    static __thread char buf[SIZE + 1];
+                    long *torn = arg;
+                    while (!done) {
+                        int fd = open("/etc/hosts", O_RDONLY);
+                        if (fd < 0) {
+                            (*torn)++;
+                            continue;
+                        }
+                        ssize_t len = 0, n;
+                        while ((n = read(fd, buf + len, SIZE + 1 - len)) > 0) {
+                            len += n;
+                        }
+                        close(fd);
+                        for (ssize_t i = 1; i < len; i++) {
+                            if (buf[i] != buf[0]) {
+                                len = -1;
+                                break;
+                            }
+                        }
+                        if (len != SIZE) {
+                            (*torn)++;
+                        }
+                    }
+                    return NULL;
+                }
+
+                int main() {
+                    pthread_t saver, loaders[READERS];
+                    long torn[READERS] = {0}, total = 0;
+                    for (int i = 0; i < READERS; i++) {
+                        pthread_create(&loaders[i], NULL, load, &torn[i]);
+                    }
+                    pthread_create(&saver, NULL, save, NULL);
+                    pthread_join(saver, NULL);
+                    for (int i = 0; i < READERS; i++) {
+                        pthread_join(loaders[i], NULL);
+                        total += torn[i];
+                    }
+                    printf("%ld\n", total);
+                    return 0;
+                }
+            "#,
+            &["-pthread"],
+        );
+
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        fs::write(fake_root.join("etc/hosts"), "z".repeat(65536)).unwrap();
+
+        // saving by renaming a temporary file over the fake file replaces it in one step within the
+        // fake root, so readers only ever see a whole version of it
+        let output = cmd!(&fake_root, bin.display().to_string(), all = true);
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "0\n");
+        assert_eq!(String::from_utf8_lossy(&output.stderr), "");
+        assert_eq!(
+            cat!(fake_root.join("etc/hosts")),
+            ((b'a' + 199 % 26) as char).to_string().repeat(65536)
+        );
+        assert!(!fake_root.join("etc/hosts.tmp").exists());
+    });
+
+    test!(fake_time, |dir: &Path| {
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        fs::write(fake_root.join("etc/hosts"), "").unwrap();
+
+        // `stat` uses `statx` and `date -r` uses `stat`, and real files keep their times
+        let output = cmd!(
+            &fake_root,
+            "stat -c '%X %Y %Z' /etc/hosts; date -r /etc/hosts +%s; stat -c %Y /etc/passwd",
+            env = [(ENV_FAKEROOT_FAKE_TIME, "1234567890")]
+        );
+        let real_mtime = fs::metadata("/etc/passwd").unwrap().mtime();
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            format!(
+                "1234567890 1234567890 1234567890\n1234567890\n{}\n",
+                real_mtime
+            )
+        );
+    });
+
+    test!(separator, |dir: &Path| {
+        let real_files = [dir.join("real/with:colon"), dir.join("real/plain")];
+        fs::create_dir_all(dir.join("real")).unwrap();
+        for real_file in &real_files {
+            fs::write(real_file, "real").unwrap();
+        }
+
+        let fake_root = dir.join("fake:root");
+        fs::create_dir_all(&fake_root).unwrap();
+
+        let seed = format!("{};{}", real_files[0].display(), real_files[1].display());
+        cmd!(
+            &fake_root,
+            "true",
+            env = [(ENV_FAKEROOT_SEED, &seed), (ENV_FAKEROOT_SEP, ";")]
+        );
+        for real_file in &real_files {
+            assert_eq!(
+                cat!(fake_root.join(real_file.strip_prefix("/").unwrap())),
+                "real"
+            );
+        }
+    });
+
+    test!(copy_on_open, |dir: &Path| {
+        let fake_root = dir.join("root");
+        let real_file = dir.join("real/file");
+        fs::create_dir_all(&fake_root).unwrap();
+        fs::create_dir_all(real_file.parent().unwrap()).unwrap();
+        fs::write(&real_file, "real\n").unwrap();
+
+        // reading copies the file
+        let output = cmd!(
+            &fake_root,
+            format!("cat {}", real_file.display()),
+            env = [(ENV_FAKEROOT_COPY_ON_OPEN, "1")]
+        );
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "real\n");
+        let fake_file = fake_root.join(real_file.strip_prefix("/").unwrap());
+        assert_eq!(cat!(&fake_file), "real\n");
+
+        // so reads and writes after it all use the copy, whatever happens to the real file
+        fs::write(&real_file, "changed\n").unwrap();
+        let output = cmd!(
+            &fake_root,
+            format!("cat {0}; echo written >> {0}; cat {0}", real_file.display()),
+            env = [(ENV_FAKEROOT_COPY_ON_OPEN, "1")]
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "real\nreal\nwritten\n"
+        );
+        assert_eq!(cat!(&real_file), "changed\n");
+    });
+
+    test!(cow_trunc, |dir: &Path| {
+        let bin = compile(
+            dir,
+            "cow_trunc",
+            r#"
+                #include <fcntl.h>
+                #include <string.h>
+                #include <unistd.h>
+
+                int main(int argc, char **argv) {
+                    int flags = O_WRONLY | (strcmp(argv[2], "trunc") == 0 ? O_TRUNC : 0);
+                    int fd = strcmp(argv[3], "openat") == 0
+                        ? openat(AT_FDCWD, argv[1], flags)
+                        : open(argv[1], flags);
+                    if (fd < 0 || write(fd, "new", 3) != 3) {
+                        return 1;
+                    }
+
+                    close(fd);
+                    return 0;
+                }
+            "#,
+            &[],
+        );
+
+        let fake_root = dir.join("root");
+        fs::create_dir_all(&fake_root).unwrap();
+
+        // without `O_TRUNC` the real contents are partly overwritten, so they're copied first
+        for call in ["open", "openat"] {
+            for (trunc, expected) in [("keep", "newl,"), ("trunc", "new")] {
+                let real_file = dir.join(format!("real/{}-{}", call, trunc));
+                fs::create_dir_all(real_file.parent().unwrap()).unwrap();
+                fs::write(&real_file, "real,").unwrap();
+
+                cmd!(
+                    &fake_root,
+                    format!(
+                        "{} {} {} {}",
+                        bin.display(),
+                        real_file.display(),
+                        trunc,
+                        call
+                    ),
+                    env = [(ENV_FAKEROOT_COW, "1")]
+                );
+                let fake_file = fake_root.join(real_file.strip_prefix("/").unwrap());
+                assert_eq!(cat!(fake_file), expected);
+                assert_eq!(cat!(&real_file), "real,");
+            }
+        }
+    });
+
+    test!(cow_dir, |dir: &Path| {
+        let template = dir.join("template");
+        let cow_dir = dir.join("cow");
+        fs::create_dir_all(template.join("etc")).unwrap();
+        fs::create_dir_all(&cow_dir).unwrap();
+        fs::write(template.join("etc/hosts"), "template\n").unwrap();
+        let real_file = dir.join("real/file");
+        fs::create_dir_all(real_file.parent().unwrap()).unwrap();
+        fs::write(&real_file, "real\n").unwrap();
+
+        let run = |cmd: String| {
+            let output = cmd!(&template, &cmd, env = [(ENV_FAKEROOT_COW_DIR, &cow_dir)]);
+            String::from_utf8_lossy(&output.stdout).into_owned()
+        };
+
+        // reads fall through to the template
+        assert_eq!(run("cat /etc/hosts".into()), "template\n");
+        assert!(!cow_dir.join("etc/hosts").exists());
+
+        // writes land in the writable layer, shadowing the template which is left alone
+        run("echo cow >> /etc/hosts".into());
+        assert_eq!(cat!(cow_dir.join("etc/hosts")), "template\ncow\n");
+        assert_eq!(cat!(template.join("etc/hosts")), "template\n");
+        assert_eq!(run("cat /etc/hosts".into()), "template\ncow\n");
+
+        // as do writes to real files
+        run(format!("echo cow >> {}", real_file.display()));
+        let cow_file = cow_dir.join(real_file.strip_prefix("/").unwrap());
+        assert_eq!(cat!(&cow_file), "real\ncow\n");
+        assert_eq!(cat!(&real_file), "real\n");
+        assert_eq!(run(format!("cat {}", real_file.display())), "real\ncow\n");
+    });
+
+    test!(cow_fopen, |dir: &Path| {
+        let bin = compile(
+            dir,
+            "cow_fopen",
+            r#"
+                #include <stdio.h>
+
+                int main(int argc, char **argv) {
+                    FILE *file = fopen(argv[1], argv[2]);
+                    if (file == NULL) {
+                        perror("fopen");
+                        return 1;
+                    }
+
+                    fputs("new", file);
+                    fclose(file);
+                    return 0;
+                }
+            "#,
+            &[],
+        );
+
+        let fake_root = dir.join("root");
+        fs::create_dir_all(&fake_root).unwrap();
+        let fake_path = |real: &Path| fake_root.join(real.strip_prefix("/").unwrap());
+
+        // appending (or updating) keeps the real contents, truncating doesn't need them
+        for (mode, expected) in [("a", "real,new"), ("r+", "newl,"), ("w", "new")] {
+            let real_file = dir.join(format!("real/{}", mode));
+            fs::create_dir_all(real_file.parent().unwrap()).unwrap();
+            fs::write(&real_file, "real,").unwrap();
+
+            cmd!(
+                &fake_root,
+                format!("{} {} {}", bin.display(), real_file.display(), mode),
+                env = [(ENV_FAKEROOT_COW, "1")]
+            );
+            assert_eq!(cat!(fake_path(&real_file)), expected);
+            assert_eq!(cat!(&real_file), "real,");
+        }
+    });
+
+    #[cfg(target_env = "gnu")]
+    test!(seed, |dir: &Path| {
+        let real_hosts = fs::read("/etc/hosts").unwrap();
 
         cmd!(
             &dir,
-            "echo 1 | tee /opt/{foo,bar}",
-            dirs = true,
-            debug = true
+            "echo seeded >> /etc/hosts",
+            env = [(ENV_FAKEROOT_SEED, "/etc/hosts:/does/not/exist")]
+        );
+        assert_eq!(fs::read("/etc/hosts").unwrap(), real_hosts);
+        assert_eq!(
+            fs::read(dir.join("etc/hosts")).unwrap(),
+            [real_hosts.as_slice(), b"seeded\n"].concat()
         );
-        assert_eq!(cat!(fake_opt.join("foo")).trim(), "1");
-        assert_eq!(cat!(fake_opt.join("bar")).trim(), "1");
     });
 
-    test!(all, |fake_dir: &Path| {
-        cmd!(&fake_dir, "echo 1 > /asdf", all = true);
-        assert_eq!(cat!(fake_dir.join("asdf")).trim(), "1");
+    test!(stat, |dir: &Path| {
+        let bin = compile(
+            dir,
+            "stat",
+            r#"
+                #define _LARGEFILE64_SOURCE
+                #include <stdio.h>
+                #include <sys/stat.h>
+
+                int main(int argc, char **argv) {
+                    struct stat st;
+                    struct stat64 st64;
+
+                    if (stat(argv[1], &st) != 0) {
+                        perror("stat");
+                        return 1;
+                    }
+                    printf("stat %lld\n", (long long)st.st_size);
+
+                    if (lstat(argv[1], &st) != 0) {
+                        perror("lstat");
+                        return 1;
+                    }
+                    printf("lstat %lld\n", (long long)st.st_size);
+
+                    if (stat64(argv[1], &st64) != 0) {
+                        perror("stat64");
+                        return 1;
+                    }
+                    printf("stat64 %lld\n", (long long)st64.st_size);
+
+                    if (lstat64(argv[1], &st64) != 0) {
+                        perror("lstat64");
+                        return 1;
+                    }
+                    printf("lstat64 %lld\n", (long long)st64.st_size);
+
+                    return 0;
+                }
+            "#,
+            &[],
+        );
+
+        let fake_etc = dir.join("root/etc");
+        fs::create_dir_all(&fake_etc).unwrap();
+        fs::write(fake_etc.join("hosts"), "🎉").unwrap();
+
+        let output = cmd!(dir.join("root"), format!("{} /etc/hosts", bin.display()));
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "stat 4\nlstat 4\nstat64 4\nlstat64 4\n"
+        );
     });
 
-    test!(
-        #[should_panic(expected = "/asdf: Permission denied")]
-        all_unset,
-        |fake_dir: &Path| {
-            cmd!(&fake_dir, "echo 1 > /asdf");
+    test!(open_nofollow, |dir: &Path| {
+        let bin = compile(
+            dir,
+            "open_nofollow",
+            r#"
+                #include <errno.h>
+                #include <fcntl.h>
+                #include <stdio.h>
+
+                int main(int argc, char **argv) {
+                    for (int i = 1; i < argc; i++) {
+                        int fd = open(argv[i], O_RDONLY | O_NOFOLLOW);
+                        printf("%s\n", fd < 0 && errno == ELOOP ? "ELOOP" : "opened");
+                    }
+                    return 0;
+                }
+            "#,
+            &[],
+        );
+
+        let real_dir = dir.join("real");
+        fs::create_dir_all(&real_dir).unwrap();
+        fs::write(real_dir.join("link"), "").unwrap();
+        fs::write(real_dir.join("dangling"), "").unwrap();
+
+        let fake_root = dir.join("root");
+        let fake_dir = fake_root.join(real_dir.strip_prefix("/").unwrap());
+        fs::create_dir_all(&fake_dir).unwrap();
+        fs::write(fake_dir.join("target"), "").unwrap();
+        std::os::unix::fs::symlink("target", fake_dir.join("link")).unwrap();
+        std::os::unix::fs::symlink("missing", fake_dir.join("dangling")).unwrap();
+
+        // the faked symlinks are opened, rather than followed or skipped for the real files
+        let output = cmd!(
+            &fake_root,
+            format!(
+                "{} {} {}",
+                bin.display(),
+                real_dir.join("link").display(),
+                real_dir.join("dangling").display()
+            )
+        );
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "ELOOP\nELOOP\n");
+    });
+
+    test!(lstat_symlink, |dir: &Path| {
+        let bin = compile(
+            dir,
+            "lstat_symlink",
+            r#"
+                #include <stdio.h>
+                #include <sys/stat.h>
+
+                int main(int argc, char **argv) {
+                    struct stat st;
+                    if (lstat(argv[1], &st) != 0) {
+                        perror("lstat");
+                        return 1;
+                    }
+                    printf("lstat %d %lld\n", S_ISLNK(st.st_mode), (long long)st.st_size);
+
+                    if (stat(argv[1], &st) != 0) {
+                        perror("stat");
+                        return 0;
+                    }
+                    printf("stat %d %lld\n", S_ISLNK(st.st_mode), (long long)st.st_size);
+                    return 0;
+                }
+            "#,
+            &[],
+        );
+
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        fs::write(fake_root.join("etc/target"), "1234").unwrap();
+        std::os::unix::fs::symlink("target", fake_root.join("etc/link")).unwrap();
+        std::os::unix::fs::symlink("/etc/passwd", fake_root.join("etc/escape")).unwrap();
+
+        // `lstat` reports the faked link itself, and `stat` what it points to
+        let output = cmd!(&fake_root, format!("{} /etc/link", bin.display()));
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "lstat 1 6\nstat 0 4\n"
+        );
+
+        // and a link out of the fake root can still be looked at when it mustn't be followed
+        let output = cmd!(
+            &fake_root,
+            format!("{} /etc/escape", bin.display()),
+            env = [(ENV_FAKEROOT_FOLLOW, "0")]
+        );
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "lstat 1 11\n");
+        assert!(String::from_utf8_lossy(&output.stderr).contains("stat: Permission denied"));
+    });
+
+    test!(statx, |dir: &Path| {
+        let bin = compile(
+            dir,
+            "statx",
+            r#"
+                #define _GNU_SOURCE
+                #include <fcntl.h>
+                #include <stdio.h>
+                #include <sys/stat.h>
+
+                int main(int argc, char **argv) {
+                    struct statx stx;
+                    int flags[] = {0, AT_SYMLINK_NOFOLLOW | AT_NO_AUTOMOUNT};
+                    for (int i = 0; i < 2; i++) {
+                        if (statx(AT_FDCWD, argv[1], flags[i], STATX_TYPE | STATX_SIZE, &stx) != 0) {
+                            perror("statx");
+                            return 1;
+                        }
+                        printf("%s\n", S_ISLNK(stx.stx_mode) ? "link" : "file");
+                    }
+
+                    // the empty path refers to the descriptor, and mustn't be resolved
+                    int fd = open(argv[2], O_RDONLY);
+                    if (fd < 0 || statx(fd, "", AT_EMPTY_PATH, STATX_SIZE, &stx) != 0) {
+                        perror("statx");
+                        return 1;
+                    }
+                    printf("%llu\n", (unsigned long long)stx.stx_size);
+                    return 0;
+                }
+            "#,
+            &[],
+        );
+
+        let fake_root = dir.join("root");
+        fs::create_dir_all(fake_root.join("etc")).unwrap();
+        fs::write(fake_root.join("etc/target"), "").unwrap();
+        std::os::unix::fs::symlink("target", fake_root.join("etc/link")).unwrap();
+        fs::write(dir.join("size"), "1234").unwrap();
+
+        let output = cmd!(
+            &fake_root,
+            format!("cd {} && {} /etc/link size", dir.display(), bin.display()),
+            env = [(ENV_FAKEROOT_RELATIVE, "1")]
+        );
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "file\nlink\n4\n");
+    });
+
+    test!(order, |dir: &Path| {
+        let fake_root = dir.join("root");
+        let real_dir = dir.join("real");
+        let fake_dir = fake_root.join(real_dir.strip_prefix("/").unwrap());
+        fs::create_dir_all(&real_dir).unwrap();
+        fs::create_dir_all(&fake_dir).unwrap();
+        fs::write(real_dir.join("both"), "real").unwrap();
+        fs::write(fake_dir.join("both"), "fake").unwrap();
+        fs::write(fake_dir.join("fake"), "fake only").unwrap();
+
+        let cat = |path: &str, order: &str| {
+            let output = cmd!(
+                &fake_root,
+                format!("cat {}", real_dir.join(path).display()),
+                env = [(ENV_FAKEROOT_ORDER, order)]
+            );
+            String::from_utf8_lossy(&output.stdout).to_string()
+        };
+
+        assert_eq!(cat("both", "fake-first"), "fake");
+        assert_eq!(cat("both", "real-first"), "real");
+        assert_eq!(cat("fake", "fake-first"), "fake only");
+        assert_eq!(cat("fake", "real-first"), "fake only");
+    });
+
+    test!(proc, |dir: &Path| {
+        fs::create_dir_all(dir.join("proc")).unwrap();
+        fs::write(dir.join("proc/mounts"), "fake mounts").unwrap();
+        fs::create_dir_all(dir.join("sys")).unwrap();
+        fs::write(dir.join("sys/fakeroot"), "").unwrap();
+
+        // never faked by default
+        let output = cmd!(&dir, "cat /proc/mounts");
+        assert_ne!(String::from_utf8_lossy(&output.stdout), "fake mounts");
+
+        let output = cmd!(&dir, "cat /proc/mounts", env = [(ENV_FAKEROOT_PROC, "1")]);
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "fake mounts");
+
+        // `/sys` is still never faked
+        let output = cmd!(
+            &dir,
+            "[ -e /sys/fakeroot ] || echo missing",
+            env = [(ENV_FAKEROOT_PROC, "1")]
+        );
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "missing\n");
+    });
+
+    test!(whiteout, |dir: &Path| {
+        let real_dir = dir.join("etc");
+        fs::create_dir_all(&real_dir).unwrap();
+        fs::write(real_dir.join("foo"), "real").unwrap();
+        fs::write(real_dir.join("bar"), "real").unwrap();
+
+        let fake_root = dir.join("root");
+        let fake_dir = fake_root.join(real_dir.strip_prefix("/").unwrap());
+        fs::create_dir_all(&fake_dir).unwrap();
+        fs::write(fake_dir.join(".wh.foo"), "").unwrap();
+
+        let cat = format!("cat {}/foo 2>/dev/null || echo missing", real_dir.display());
+        let ls = format!("ls -a {}", real_dir.display());
+
+        // whiteouts are ignored unless enabled
+        let output = cmd!(&fake_root, &cat);
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "real");
+
+        let output = cmd!(&fake_root, &cat, env = [(ENV_FAKEROOT_WHITEOUT, "1")]);
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "missing\n");
+        let output = cmd!(&fake_root, &ls, env = [(ENV_FAKEROOT_WHITEOUT, "1")]);
+        assert_eq!(String::from_utf8_lossy(&output.stdout), ".\n..\nbar\n");
+
+        // the markers are hidden from listings of the fake directory too
+        let output = cmd!(
+            &fake_root,
+            &ls,
+            dirs = true,
+            env = [(ENV_FAKEROOT_WHITEOUT, "1")]
+        );
+        assert_eq!(String::from_utf8_lossy(&output.stdout), ".\n..\n");
+    });
+
+    test!(whiteout_all_hooks, |dir: &Path| {
+        let bin = compile(
+            dir,
+            "whiteout",
+            r#"
+                #define _GNU_SOURCE
+                #include <dirent.h>
+                #include <errno.h>
+                #include <fcntl.h>
+                #include <libgen.h>
+                #include <limits.h>
+                #include <stdio.h>
+                #include <stdlib.h>
+                #include <string.h>
+                #include <sys/stat.h>
+                #include <unistd.h>
+
+                static void report(const char *hook, int ok) {
+                    printf("%s: %s\n", hook, ok ? "found" : strerror(errno));
+                }
+
+                int main(int argc, char **argv) {
+                    struct stat buf;
+                    struct statx xbuf;
+                    char resolved[PATH_MAX];
+                    const char *path = argv[1];
+
+                    int fd = open(path, O_RDONLY);
+                    report("open", fd >= 0);
+                    fd = openat(AT_FDCWD, path, O_RDONLY);
+                    report("openat", fd >= 0);
+                    report("fopen", fopen(path, "r") != NULL);
+                    report("stat", stat(path, &buf) == 0);
+                    report("lstat", lstat(path, &buf) == 0);
+                    report("statx", statx(AT_FDCWD, path, 0, STATX_BASIC_STATS, &xbuf) == 0);
+                    report("access", access(path, F_OK) == 0);
+                    report("faccessat", faccessat(AT_FDCWD, path, F_OK, 0) == 0);
+                    report("realpath", realpath(path, resolved) != NULL);
+
+                    char *copy = strdup(path);
+                    char *name = basename(copy);
+                    DIR *dir = opendir(dirname(strdup(path)));
+                    int listed = 0;
+                    struct dirent *entry;
+                    while (dir != NULL && (entry = readdir(dir)) != NULL) {
+                        listed |= strcmp(entry->d_name, name) == 0;
+                    }
+                    printf("readdir: %s\n", listed ? "found" : "hidden");
+                    return 0;
+                }
+            "#,
+            &[],
+        );
+
+        let real_dir = dir.join("etc");
+        fs::create_dir_all(real_dir.join("sub")).unwrap();
+        fs::write(real_dir.join("foo"), "real").unwrap();
+        fs::write(real_dir.join("sub/bar"), "real").unwrap();
+
+        let fake_root = dir.join("root");
+        let fake_dir = fake_root.join(real_dir.strip_prefix("/").unwrap());
+        fs::create_dir_all(&fake_dir).unwrap();
+        fs::write(fake_dir.join(".wh.foo"), "").unwrap();
+        fs::write(fake_dir.join(".wh.sub"), "").unwrap();
+
+        // a whited out file (or one in a whited out directory) is missing from every hook alike, and
+        // isn't listed (or its directory can't be)
+        let hooks = [
+            "open",
+            "openat",
+            "fopen",
+            "stat",
+            "lstat",
+            "statx",
+            "access",
+            "faccessat",
+            "realpath",
+        ];
+        let missing = hooks
+            .iter()
+            .map(|hook| format!("{}: No such file or directory\n", hook))
+            .collect::<String>();
+        for path in ["foo", "sub", "sub/bar"].map(|path| real_dir.join(path)) {
+            for dirs in [false, true] {
+                let output = cmd!(
+                    &fake_root,
+                    format!("{} {}", bin.display(), path.display()),
+                    env = [
+                        (ENV_FAKEROOT_WHITEOUT, "1"),
+                        (ENV_FAKEROOT_DIRS, if dirs { "1" } else { "0" })
+                    ]
+                );
+                assert_eq!(
+                    String::from_utf8_lossy(&output.stdout),
+                    format!("{}readdir: hidden\n", missing),
+                    "{} (dirs: {})",
+                    path.display(),
+                    dirs
+                );
+            }
         }
-    );
+    });
 }